@@ -0,0 +1,608 @@
+//! Player accounts, so a player is the same player from one visit to the next.
+//! Each account has a name and a password, kept as a salted PBKDF2 hash, and
+//! signing in hands out a session token that stays good for weeks. Everything
+//! is kept in one JSON file, written out again whenever it changes.
+
+use hmac::Hmac;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::chess::{Outcome, Player, VariantKind};
+use crate::clock::TimeControl;
+use crate::matchmaking::COLOR_HISTORY;
+use crate::ratings::{
+    Category, LeaderboardEntry, PlayerRating, Rating, RatingChange, RatingSystem,
+};
+
+// OWASP's recommendation for PBKDF2-HMAC-SHA256, which unoptimized tests
+// would spend most of a minute on
+const HASH_ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+const SALT_LENGTH: usize = 16;
+const TOKEN_LENGTH: usize = 32;
+// what a password given for an account that doesn't exist is checked against
+const UNKNOWN_SALT: &str = "00000000000000000000000000000000";
+const UNKNOWN_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// How long a session lasts after signing in
+const SESSION_LIFETIME_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// How many finished games each account's history keeps
+const GAME_HISTORY: usize = 100;
+
+/// Why an account couldn't be made or signed in to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountError {
+    /// Usernames are 3 to 20 letters, digits, `_` or `-`
+    InvalidUsername,
+    /// Someone already has the name, ignoring case
+    UsernameTaken,
+    PasswordTooShort,
+    /// Either there's no account by that name or the password is wrong, which
+    /// aren't told apart so names can't be fished for
+    WrongPassword,
+    /// The session token is unknown or has expired
+    NotSignedIn,
+    NoSuchAccount,
+    /// Only bot accounts can play through the bot API
+    NotABot,
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            AccountError::InvalidUsername => {
+                "Usernames must be 3 to 20 letters, digits, underscores or hyphens"
+            }
+            AccountError::UsernameTaken => "That username is taken",
+            AccountError::PasswordTooShort => "Passwords must be at least 8 characters",
+            AccountError::WrongPassword => "The username or password is wrong",
+            AccountError::NotSignedIn => "You're not signed in",
+            AccountError::NoSuchAccount => "There's no account by that name",
+            AccountError::NotABot => "That's only for bot accounts",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    // as it was registered, though it's looked up ignoring case
+    username: String,
+    salt: String,
+    password_hash: String,
+    /// When the account was made, in milliseconds since the Unix epoch
+    created: u64,
+    // played by a program through the bot API rather than by a person
+    #[serde(default)]
+    bot: bool,
+    // only for the pools the player has played rated games in
+    #[serde(default)]
+    ratings: HashMap<Category, Rating>,
+    // the colours of the player's last few matched games, oldest first
+    #[serde(default)]
+    colors: Vec<Player>,
+    // the players whose chat the account doesn't see, as they registered
+    #[serde(default)]
+    muted: Vec<String>,
+    // the account's last few finished games, oldest first
+    #[serde(default)]
+    games: Vec<PlayedGame>,
+}
+
+/// A finished game between two accounts, for their histories
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayedGame {
+    pub game: String,
+    pub white: String,
+    pub black: String,
+    pub variant: VariantKind,
+    pub time_control: Option<TimeControl>,
+    pub outcome: Outcome,
+    /// When it ended, in milliseconds since the Unix epoch
+    pub ended: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    username: String,
+    expires: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Store {
+    // by lowercased username
+    accounts: HashMap<String, Account>,
+    // by the hash of the session token, so the file can't be used to sign in
+    sessions: HashMap<String, Session>,
+}
+
+/// Every account on the server, and who's signed in to them. It's shared by
+/// every connection, and only locked while it's being looked at, never while
+/// a password is being hashed.
+#[derive(Debug)]
+pub struct Accounts {
+    // `None` keeps the accounts in memory only
+    path: Option<PathBuf>,
+    rating_system: RatingSystem,
+    store: Mutex<Store>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `length` random bytes, written out in hex
+pub fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0; length];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+fn hash_password(password: &str, salt: &str) -> String {
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt.as_bytes(), HASH_ROUNDS, &mut hash);
+    hex(&hash)
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Compare without stopping at the first difference, so how long it takes
+/// gives nothing away.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn valid_username(username: &str) -> bool {
+    (3..=20).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Accounts {
+    /// Read the accounts kept at `path`, or start with none if there's
+    /// nothing there yet. Changes are written back to the same file, and
+    /// games are rated with `rating_system`.
+    pub fn open(path: &Path, rating_system: RatingSystem) -> io::Result<Self> {
+        let store = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Store::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Accounts {
+            path: Some(path.to_path_buf()),
+            rating_system,
+            store: Mutex::new(store),
+        })
+    }
+
+    /// Start with no accounts, kept in memory only.
+    #[cfg(test)]
+    pub fn in_memory(rating_system: RatingSystem) -> Self {
+        Accounts {
+            path: None,
+            rating_system,
+            store: Mutex::new(Store::default()),
+        }
+    }
+
+    /// Make an account, a bot account if `bot` is set, and sign in to it at
+    /// `now`, returning the session token.
+    pub fn register(
+        &self,
+        username: &str,
+        password: &str,
+        bot: bool,
+        now: u64,
+    ) -> Result<String, AccountError> {
+        if !valid_username(username) {
+            return Err(AccountError::InvalidUsername);
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AccountError::PasswordTooShort);
+        }
+        let key = username.to_lowercase();
+        if self.store.lock().unwrap().accounts.contains_key(&key) {
+            return Err(AccountError::UsernameTaken);
+        }
+        let salt = random_hex(SALT_LENGTH);
+        let password_hash = hash_password(password, &salt);
+
+        let mut store = self.store.lock().unwrap();
+        // someone else could have taken the name while the password was hashed
+        if store.accounts.contains_key(&key) {
+            return Err(AccountError::UsernameTaken);
+        }
+        let account = Account {
+            username: username.to_string(),
+            salt,
+            password_hash,
+            created: now,
+            bot,
+            ratings: HashMap::new(),
+            colors: vec![],
+            muted: vec![],
+            games: vec![],
+        };
+        store.accounts.insert(key, account);
+        let token = start_session(&mut store, username, now);
+        self.save(&store);
+        Ok(token)
+    }
+
+    /// Sign in at `now`, returning the session token and the username as it
+    /// was registered.
+    pub fn login(
+        &self,
+        username: &str,
+        password: &str,
+        now: u64,
+    ) -> Result<(String, String), AccountError> {
+        let account = self
+            .store
+            .lock()
+            .unwrap()
+            .accounts
+            .get(&username.to_lowercase())
+            .cloned();
+        // the password's hashed even when there's no such account, so how
+        // long it takes doesn't give away which names are taken
+        let (salt, password_hash) = match &account {
+            Some(account) => (account.salt.as_str(), account.password_hash.as_str()),
+            None => (UNKNOWN_SALT, UNKNOWN_HASH),
+        };
+        let matches = constant_time_eq(&hash_password(password, salt), password_hash);
+        let account = match account {
+            Some(account) if matches => account,
+            _ => return Err(AccountError::WrongPassword),
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let token = start_session(&mut store, &account.username, now);
+        self.save(&store);
+        Ok((token, account.username))
+    }
+
+    /// End the session with `token`.
+    pub fn logout(&self, token: &str) -> Result<(), AccountError> {
+        let mut store = self.store.lock().unwrap();
+        store
+            .sessions
+            .remove(&hash_token(token))
+            .ok_or(AccountError::NotSignedIn)?;
+        self.save(&store);
+        Ok(())
+    }
+
+    /// Who's signed in with `token`, if the session hasn't expired by `now`.
+    pub fn username(&self, token: &str, now: u64) -> Option<String> {
+        self.store
+            .lock()
+            .unwrap()
+            .sessions
+            .get(&hash_token(token))
+            .filter(|session| session.expires > now)
+            .map(|session| session.username.clone())
+    }
+
+    /// Rate a game in `category` between the accounts `white` and `black`,
+    /// in which White scored `white_score`, returning how each rating moved.
+    pub fn rate_game(
+        &self,
+        white: &str,
+        black: &str,
+        white_score: f64,
+        category: Category,
+    ) -> Option<(RatingChange, RatingChange)> {
+        let mut store = self.store.lock().unwrap();
+        let rating = |store: &Store, username: &str| {
+            let account = store.accounts.get(&username.to_lowercase())?;
+            Some(account.ratings.get(&category).copied().unwrap_or_default())
+        };
+        let white_before = rating(&store, white)?;
+        let black_before = rating(&store, black)?;
+        // both ratings move at once, each from what the other was before
+        let white_after = white_before.after(&black_before, white_score, self.rating_system);
+        let black_after = black_before.after(&white_before, 1.0 - white_score, self.rating_system);
+
+        let mut change = |username: &str, before: Rating, after: Rating| {
+            let account = store.accounts.get_mut(&username.to_lowercase())?;
+            account.ratings.insert(category, after);
+            Some(RatingChange {
+                username: account.username.clone(),
+                before: before.rating.round() as i32,
+                after: after.rating.round() as i32,
+                provisional: after.provisional(self.rating_system),
+            })
+        };
+        let changes = (
+            change(white, white_before, white_after)?,
+            change(black, black_before, black_after)?,
+        );
+        self.save(&store);
+        Some(changes)
+    }
+
+    /// The name an account was registered under, looking it up ignoring
+    /// case.
+    pub fn registered_name(&self, username: &str) -> Option<String> {
+        let store = self.store.lock().unwrap();
+        let account = store.accounts.get(&username.to_lowercase())?;
+        Some(account.username.clone())
+    }
+
+    /// Whether the account is a bot's.
+    pub fn is_bot(&self, username: &str) -> bool {
+        let store = self.store.lock().unwrap();
+        store
+            .accounts
+            .get(&username.to_lowercase())
+            .is_some_and(|account| account.bot)
+    }
+
+    /// The account's rating in `category`, which starts out the same for
+    /// everyone.
+    pub fn rating(&self, username: &str, category: Category) -> Option<Rating> {
+        let store = self.store.lock().unwrap();
+        let account = store.accounts.get(&username.to_lowercase())?;
+        Some(account.ratings.get(&category).copied().unwrap_or_default())
+    }
+
+    /// The colours the account has had in its recent games, oldest first.
+    pub fn recent_colors(&self, username: &str) -> Vec<Player> {
+        let store = self.store.lock().unwrap();
+        store
+            .accounts
+            .get(&username.to_lowercase())
+            .map(|account| account.colors.clone())
+            .unwrap_or_default()
+    }
+
+    /// Remember who got which colour in a game that's just started.
+    pub fn record_colors(&self, white: &str, black: &str) {
+        let mut store = self.store.lock().unwrap();
+        for (username, color) in [(white, Player::White), (black, Player::Black)] {
+            if let Some(account) = store.accounts.get_mut(&username.to_lowercase()) {
+                account.colors.push(color);
+                let excess = account.colors.len().saturating_sub(COLOR_HISTORY);
+                account.colors.drain(..excess);
+            }
+        }
+        self.save(&store);
+    }
+
+    /// Stop `username` seeing `other`'s chat, or let them see it again if
+    /// `mute` is false, returning everyone they have muted.
+    pub fn set_muted(
+        &self,
+        username: &str,
+        other: &str,
+        mute: bool,
+    ) -> Result<Vec<String>, AccountError> {
+        let mut store = self.store.lock().unwrap();
+        let other = store
+            .accounts
+            .get(&other.to_lowercase())
+            .map(|account| account.username.clone())
+            .ok_or(AccountError::NoSuchAccount)?;
+        let account = store
+            .accounts
+            .get_mut(&username.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        account.muted.retain(|muted| *muted != other);
+        if mute {
+            account.muted.push(other);
+        }
+        let muted = account.muted.clone();
+        self.save(&store);
+        Ok(muted)
+    }
+
+    /// Whether `username` has muted `other`.
+    pub fn has_muted(&self, username: &str, other: &str) -> bool {
+        let store = self.store.lock().unwrap();
+        store
+            .accounts
+            .get(&username.to_lowercase())
+            .is_some_and(|account| {
+                account
+                    .muted
+                    .iter()
+                    .any(|muted| muted.eq_ignore_ascii_case(other))
+            })
+    }
+
+    /// Add a finished game to both players' histories.
+    pub fn record_game(&self, game: PlayedGame) {
+        let mut store = self.store.lock().unwrap();
+        for username in [&game.white, &game.black] {
+            if let Some(account) = store.accounts.get_mut(&username.to_lowercase()) {
+                account.games.push(game.clone());
+                let excess = account.games.len().saturating_sub(GAME_HISTORY);
+                account.games.drain(..excess);
+            }
+        }
+        self.save(&store);
+    }
+
+    /// The account's finished games, most recent first, with its name as it
+    /// was registered.
+    pub fn games(&self, username: &str) -> Result<(String, Vec<PlayedGame>), AccountError> {
+        let store = self.store.lock().unwrap();
+        let account = store
+            .accounts
+            .get(&username.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        let games = account.games.iter().rev().cloned().collect();
+        Ok((account.username.clone(), games))
+    }
+
+    /// The account's ratings in every pool it's played in, with its name as
+    /// it was registered.
+    pub fn ratings(&self, username: &str) -> Result<(String, Vec<PlayerRating>), AccountError> {
+        let store = self.store.lock().unwrap();
+        let account = store
+            .accounts
+            .get(&username.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        let mut ratings: Vec<PlayerRating> = account
+            .ratings
+            .iter()
+            .map(|(category, rating)| rating.shown(*category, self.rating_system))
+            .collect();
+        ratings.sort_by_key(|rating| rating.category as u8);
+        Ok((account.username.clone(), ratings))
+    }
+
+    /// The `limit` highest rated players in `category`, leaving out anyone
+    /// whose rating is still provisional.
+    pub fn leaderboard(&self, category: Category, limit: usize) -> Vec<LeaderboardEntry> {
+        let store = self.store.lock().unwrap();
+        let mut rated: Vec<(&str, &Rating)> = store
+            .accounts
+            .values()
+            .filter_map(|account| {
+                Some((account.username.as_str(), account.ratings.get(&category)?))
+            })
+            .filter(|(_, rating)| !rating.provisional(self.rating_system))
+            .collect();
+        rated.sort_by(|(a_name, a), (b_name, b)| {
+            b.rating
+                .total_cmp(&a.rating)
+                .then_with(|| a_name.cmp(b_name))
+        });
+        rated
+            .into_iter()
+            .take(limit)
+            .enumerate()
+            .map(|(idx, (username, rating))| LeaderboardEntry {
+                rank: idx + 1,
+                username: username.to_string(),
+                rating: rating.rating.round() as i32,
+                games: rating.games,
+            })
+            .collect()
+    }
+
+    /// Write the accounts out, replacing the file in one go so a crash can't
+    /// leave it half written.
+    fn save(&self, store: &Store) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let write = || -> io::Result<()> {
+            let partial = path.with_extension("json.partial");
+            fs::write(&partial, serde_json::to_string_pretty(store)?)?;
+            fs::rename(&partial, path)
+        };
+        if let Err(e) = write() {
+            error!("Failed to write accounts to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Sign `username` in until a while after `now`, returning the session token.
+fn start_session(store: &mut Store, username: &str, now: u64) -> String {
+    // expired sessions are otherwise only noticed when they're used, so clear
+    // them out whenever there's a new one
+    store.sessions.retain(|_, session| session.expires > now);
+    let token = random_hex(TOKEN_LENGTH);
+    store.sessions.insert(
+        hash_token(&token),
+        Session {
+            username: username.to_string(),
+            expires: now + SESSION_LIFETIME_MS,
+        },
+    );
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts() -> Accounts {
+        Accounts::in_memory(RatingSystem::Glicko2)
+    }
+
+    #[test]
+    fn registering_signs_in_and_the_password_signs_in_again() {
+        let accounts = accounts();
+        let token = accounts
+            .register("Alice", "correct horse", false, 0)
+            .unwrap();
+        assert_eq!(accounts.username(&token, 1), Some("Alice".to_string()));
+
+        // with the name in any case, coming back as it was registered
+        let (again, username) = accounts.login("alice", "correct horse", 1).unwrap();
+        assert_eq!(username, "Alice");
+        assert_ne!(again, token);
+        assert_eq!(accounts.username(&again, 2), Some("Alice".to_string()));
+    }
+
+    #[test]
+    fn wrong_passwords_and_unknown_names_look_the_same() {
+        let accounts = accounts();
+        accounts
+            .register("alice", "correct horse", false, 0)
+            .unwrap();
+        assert_eq!(
+            accounts.login("alice", "battery staple", 0),
+            Err(AccountError::WrongPassword)
+        );
+        assert_eq!(
+            accounts.login("bob", "correct horse", 0),
+            Err(AccountError::WrongPassword)
+        );
+    }
+
+    #[test]
+    fn names_are_taken_whatever_their_case() {
+        let accounts = accounts();
+        accounts
+            .register("alice", "correct horse", false, 0)
+            .unwrap();
+        assert_eq!(
+            accounts.register("ALICE", "battery staple", false, 0),
+            Err(AccountError::UsernameTaken)
+        );
+        assert_eq!(
+            accounts.register("al", "battery staple", false, 0),
+            Err(AccountError::InvalidUsername)
+        );
+        assert_eq!(
+            accounts.register("bob", "short", false, 0),
+            Err(AccountError::PasswordTooShort)
+        );
+    }
+
+    #[test]
+    fn sessions_end_when_they_expire_or_on_logout() {
+        let accounts = accounts();
+        let token = accounts
+            .register("alice", "correct horse", false, 0)
+            .unwrap();
+        assert!(accounts.username(&token, SESSION_LIFETIME_MS - 1).is_some());
+        assert_eq!(accounts.username(&token, SESSION_LIFETIME_MS), None);
+
+        let (token, _) = accounts.login("alice", "correct horse", 0).unwrap();
+        assert_eq!(accounts.logout(&token), Ok(()));
+        assert_eq!(accounts.username(&token, 1), None);
+        assert_eq!(accounts.logout(&token), Err(AccountError::NotSignedIn));
+    }
+}
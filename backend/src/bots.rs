@@ -0,0 +1,315 @@
+//! Bots: programs that play on the server from a bot account. A bot opens a
+//! stream with `GET /api/bot/stream`, which stays open and sends it one JSON
+//! event a line: challenges made to it, and for each game it plays, the
+//! start, the position and clocks after every move, and how it ended. It
+//! answers challenges and plays its moves, in UCI notation, with the HTTP
+//! API. Bots sit down at a game the same way as anyone else, so they're held
+//! to all the same rules.
+
+use futures_channel::mpsc::unbounded;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::info;
+
+use crate::challenges::ChallengeInfo;
+use crate::chat::ChatLine;
+use crate::chess::{Board, Outcome, Player, UciMove};
+use crate::clock::{Clock, TimeControl};
+use crate::ratings::Category;
+use crate::{
+    move_to, now_ms, ClientMessage, Connection, GameState, Server, ServerMessage, SharedGame,
+    DEFAULT_GAME,
+};
+
+/// How often an empty line is sent down a quiet stream, so the bot can tell
+/// the server is still there, and the server can tell the bot isn't
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Something a bot's stream tells it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum BotEvent {
+    /// A game has started with the bot in it
+    GameStart {
+        game: String,
+        color: Player,
+        opponent: String,
+        opponent_rating: i32,
+        opponent_bot: bool,
+        time_control: Option<TimeControl>,
+    },
+    /// Where the game has got to, at the start and after every move
+    GameState {
+        game: String,
+        /// Shredder-FEN when castling needs the rooks' files, as it can in
+        /// Chess960, and with the pieces in hand in variants with drops
+        fen: String,
+        /// Every move so far, in UCI notation
+        moves: Vec<String>,
+        turn: Player,
+        /// How long each player has left, for games with a clock
+        white_ms: Option<u64>,
+        black_ms: Option<u64>,
+    },
+    GameFinish {
+        game: String,
+        outcome: Outcome,
+    },
+    /// Someone has challenged the bot
+    Challenge(ChallengeInfo),
+    /// A challenge to or from the bot has been accepted elsewhere, declined,
+    /// cancelled or has lapsed
+    ChallengeGone {
+        id: String,
+    },
+    /// A line of chat from the game the bot is playing
+    Chat(ChatLine),
+}
+
+/// Turns what's sent to a bot's connection into events for its stream,
+/// keeping track of which game it's in
+#[derive(Debug, Default)]
+struct Events {
+    game: Option<String>,
+}
+
+impl Events {
+    /// The event for `msg` at `now`, if the bot needs to hear about it.
+    fn event(&mut self, msg: ServerMessage, now: u64) -> Option<BotEvent> {
+        match msg {
+            ServerMessage::Matched {
+                game,
+                color,
+                opponent,
+                opponent_rating,
+                opponent_bot,
+                time_control,
+            } => {
+                self.game = Some(game.clone());
+                Some(BotEvent::GameStart {
+                    game,
+                    color,
+                    opponent,
+                    opponent_rating,
+                    opponent_bot,
+                    time_control,
+                })
+            }
+            // the games the server starts are only ever played on one board
+            ServerMessage::BoardState(board) => self.state(&board, None, now),
+            ServerMessage::BoardsState { boards, clocks } => {
+                self.state(boards.first()?, clocks.first(), now)
+            }
+            ServerMessage::GameOver(outcome) => Some(BotEvent::GameFinish {
+                game: self.game.clone()?,
+                outcome,
+            }),
+            ServerMessage::Challenged(info) => Some(BotEvent::Challenge(info)),
+            ServerMessage::ChallengeDeclined { id }
+            | ServerMessage::ChallengeCancelled { id }
+            | ServerMessage::ChallengeExpired { id } => Some(BotEvent::ChallengeGone { id }),
+            ServerMessage::Chat(line) => Some(BotEvent::Chat(line)),
+            _ => None,
+        }
+    }
+
+    fn state(&self, board: &Board, clock: Option<&Clock>, now: u64) -> Option<BotEvent> {
+        Some(BotEvent::GameState {
+            game: self.game.clone()?,
+            fen: board.to_variant_fen(),
+            moves: board.history().iter().map(|mv| mv.uci()).collect(),
+            turn: board.turn(),
+            white_ms: clock.map(|clock| clock.left(Player::White, now)),
+            black_ms: clock.map(|clock| clock.left(Player::Black, now)),
+        })
+    }
+}
+
+/// Send the bot signed in to `account` its events over `stream`, which the
+/// response head has already gone out on, until it hangs up. It waits in
+/// `default_game` until it has a game of its own, since it's only ever sent
+/// anything from a game once it's moved over to it.
+pub async fn stream(
+    mut stream: TcpStream,
+    server: Arc<Server>,
+    account: String,
+    default_game: SharedGame,
+) {
+    let (tx, mut rx) = unbounded();
+    let connection = Connection {
+        tx,
+        game: Arc::new(Mutex::new(default_game)),
+        account: Arc::new(Mutex::new(Some(account.clone()))),
+    };
+    server.connections.lock().unwrap().push(connection.clone());
+    info!("Bot {} is streaming", account);
+
+    let mut events = Events::default();
+    let mut pending: Vec<BotEvent> = server
+        .challenges
+        .lock()
+        .unwrap()
+        .lobby(Some(&account))
+        .into_iter()
+        .filter(|info| {
+            info.to
+                .as_deref()
+                .is_some_and(|to| to.eq_ignore_ascii_case(&account))
+        })
+        .map(BotEvent::Challenge)
+        .collect();
+    pending.extend(resume(&server, &connection, &mut events));
+
+    let (mut reader, mut writer) = stream.split();
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+    let mut buf = [0; 256];
+    let mut pending = pending.into_iter();
+    loop {
+        let event = match pending.next() {
+            Some(event) => Some(event),
+            None => tokio::select! {
+                msg = rx.next() => match msg {
+                    Some(msg) => match events.event(msg, now_ms()) {
+                        Some(event) => Some(event),
+                        None => continue,
+                    },
+                    None => break,
+                },
+                _ = keep_alive.tick() => None,
+                // a bot has nothing to send on its stream, so it's only ever
+                // read from to notice it's gone
+                read = reader.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                },
+            },
+        };
+        let mut line = match event {
+            Some(event) => serde_json::to_string(&event).expect("Could not serialize event"),
+            None => String::new(),
+        };
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+
+    server.disconnect(&connection);
+    info!("Bot {} stopped streaming", account);
+}
+
+/// Move a bot's new stream over to the game it was playing when it last went
+/// away, if it's still going, returning how it started and where it's got to.
+fn resume(server: &Server, connection: &Connection, events: &mut Events) -> Vec<BotEvent> {
+    let account = match connection.account.lock().unwrap().clone() {
+        Some(account) => account,
+        None => return vec![],
+    };
+    let games: Vec<(String, SharedGame)> = server
+        .games
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| *id != DEFAULT_GAME)
+        .map(|(id, game)| (id.clone(), game.clone()))
+        .collect();
+    for (id, game) in games {
+        let messages = {
+            let game = game.lock().unwrap();
+            let going = game.outcomes().iter().any(Option::is_none);
+            match matched(server, id, &game, &account) {
+                Some(matched) if going => vec![matched, game.state()],
+                _ => continue,
+            }
+        };
+        move_to(connection, &game);
+        let now = now_ms();
+        return messages
+            .into_iter()
+            .filter_map(|msg| events.event(msg, now))
+            .collect();
+    }
+    vec![]
+}
+
+/// The `Matched` that `account` was sent when the game with `id` started, if
+/// they're playing in it.
+fn matched(server: &Server, id: String, game: &GameState, account: &str) -> Option<ServerMessage> {
+    let color = [Player::White, Player::Black]
+        .iter()
+        .copied()
+        .find(|&player| {
+            game.account_at(0, player)
+                .is_some_and(|seated| seated.eq_ignore_ascii_case(account))
+        })?;
+    let opponent = game.account_at(0, !color)?;
+    let rating = server
+        .accounts
+        .rating(&opponent, Category::of(game.time_control))
+        .unwrap_or_default();
+    Some(ServerMessage::Matched {
+        game: id,
+        color,
+        opponent_rating: rating.rating.round() as i32,
+        opponent_bot: server.accounts.is_bot(&opponent),
+        opponent,
+        time_control: game.time_control,
+    })
+}
+
+/// Play `uci` as `account` in `game`, returning why not if it can't be
+/// played.
+pub fn play(server: &Server, game: &SharedGame, account: &str, uci: &str) -> Option<ServerMessage> {
+    let id_token = game
+        .lock()
+        .unwrap()
+        .accounts
+        .iter()
+        .find(|(_, seated)| seated.eq_ignore_ascii_case(account))
+        .map(|(id_token, _)| id_token.clone());
+    let id_token = match id_token {
+        Some(id_token) => id_token,
+        None => {
+            return Some(ServerMessage::Rejected(
+                "You're not playing in that game".to_string(),
+            ))
+        }
+    };
+    let client_msg = match uci.parse() {
+        Ok(mv) => client_message(mv, id_token),
+        Err(e) => return Some(ServerMessage::IllegalMove(e.to_string())),
+    };
+    server.play(game, client_msg).into_iter().find(|msg| {
+        matches!(
+            msg,
+            ServerMessage::MoveRejected { .. }
+                | ServerMessage::IllegalMove(_)
+                | ServerMessage::Rejected(_)
+                | ServerMessage::UnrecognizedPlayer(_)
+        )
+    })
+}
+
+/// The message the player with `id_token` would send to play `mv`.
+pub fn client_message(mv: UciMove, id_token: String) -> ClientMessage {
+    match mv {
+        UciMove::Move {
+            from,
+            to,
+            promotion,
+        } => ClientMessage::MovePiece {
+            id_token,
+            prev_location: from,
+            location: to,
+            promotion,
+        },
+        UciMove::Drop { piece, to } => ClientMessage::DropPiece {
+            id_token,
+            piece,
+            location: to,
+        },
+    }
+}
@@ -0,0 +1,120 @@
+//! Challenges, for setting up exactly the game a player wants rather than
+//! waiting to be paired. A challenge is either open, for anyone in the lobby
+//! to accept, or made to one player. It stands until it's accepted, declined,
+//! cancelled or it runs out of time, or the challenger goes away.
+
+use serde::{Deserialize, Serialize};
+
+use crate::chess::VariantKind;
+use crate::clock::TimeControl;
+use crate::Connection;
+
+/// How long a challenge stands if nobody answers it
+pub const CHALLENGE_LIFETIME_MS: u64 = 5 * 60 * 1000;
+
+/// Which colour the challenger plays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeColor {
+    White,
+    Black,
+    #[default]
+    Random,
+}
+
+/// A challenge as players see it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChallengeInfo {
+    pub id: String,
+    pub challenger: String,
+    /// Whether the challenger is a bot
+    pub challenger_bot: bool,
+    /// Who the challenge is for, or `None` for anyone
+    pub to: Option<String>,
+    pub time_control: Option<TimeControl>,
+    pub color: ChallengeColor,
+    pub variant: VariantKind,
+    pub rated: bool,
+    /// When the challenge lapses, in milliseconds since the Unix epoch
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub info: ChallengeInfo,
+    /// Where the challenger is, to start the game there, or `None` for
+    /// challenges made over HTTP
+    pub connection: Option<Connection>,
+}
+
+impl Challenge {
+    /// Whether `account` is the one the challenge was made to, or anyone can
+    /// take it.
+    pub fn open_to(&self, account: &str) -> bool {
+        self.info
+            .to
+            .as_ref()
+            .is_none_or(|to| to.eq_ignore_ascii_case(account))
+    }
+}
+
+/// Every challenge that's still standing
+#[derive(Debug, Default)]
+pub struct Challenges {
+    challenges: Vec<Challenge>,
+}
+
+impl Challenges {
+    pub fn add(&mut self, challenge: Challenge) {
+        self.challenges.push(challenge);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Challenge> {
+        self.challenges
+            .iter()
+            .find(|challenge| challenge.info.id == id)
+    }
+
+    /// Take the challenge with `id` away, to answer it or call it off.
+    pub fn take(&mut self, id: &str) -> Option<Challenge> {
+        let idx = self
+            .challenges
+            .iter()
+            .position(|challenge| challenge.info.id == id)?;
+        Some(self.challenges.remove(idx))
+    }
+
+    /// Take away every challenge made from `connection`, since it's gone.
+    pub fn take_from(&mut self, connection: &Connection) -> Vec<Challenge> {
+        self.take_where(|challenge| {
+            challenge
+                .connection
+                .as_ref()
+                .is_some_and(|made_from| made_from.tx.same_receiver(&connection.tx))
+        })
+    }
+
+    /// Take away every challenge that's lapsed by `now`.
+    pub fn expire(&mut self, now: u64) -> Vec<Challenge> {
+        self.take_where(|challenge| challenge.info.expires <= now)
+    }
+
+    fn take_where(&mut self, taken: impl Fn(&Challenge) -> bool) -> Vec<Challenge> {
+        let (gone, kept) = self.challenges.drain(..).partition(taken);
+        self.challenges = kept;
+        gone
+    }
+
+    /// The challenges `account` can see in the lobby: every open one, and
+    /// those made to or by them. Players who aren't signed in only see the
+    /// open ones.
+    pub fn lobby(&self, account: Option<&str>) -> Vec<ChallengeInfo> {
+        self.challenges
+            .iter()
+            .filter(|challenge| match account {
+                Some(account) => challenge.open_to(account) || challenge.info.challenger == account,
+                None => challenge.info.to.is_none(),
+            })
+            .map(|challenge| challenge.info.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,101 @@
+//! Chat in games. Each game has two rooms, one for the accounts playing it
+//! and one for everyone watching, and neither hears the other, so nobody
+//! watching can pass a player advice. Lines are kept short, nobody can send
+//! too many at once, and players can mute anyone they'd rather not hear.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// The longest a line can be, in characters
+pub const MAX_CHAT_LENGTH: usize = 500;
+/// How many lines an account can send in `RATE_LIMIT_WINDOW_MS`
+const RATE_LIMIT_LINES: usize = 5;
+const RATE_LIMIT_WINDOW_MS: u64 = 10_000;
+
+/// Why a line of chat wasn't sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatError {
+    NoSuchGame,
+    /// Only those in a game can chat in it
+    NotInGame,
+    Empty,
+    TooLong,
+    /// Too many lines too quickly
+    TooFast,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ChatError::NoSuchGame => "There's no game by that id",
+            ChatError::NotInGame => "You're not in that game",
+            ChatError::Empty => "There's nothing to send",
+            ChatError::TooLong => "Chat messages can be at most 500 characters",
+            ChatError::TooFast => "You're sending messages too quickly",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatRoom {
+    Players,
+    Spectators,
+}
+
+/// A line of chat as it's sent out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatLine {
+    pub game_id: String,
+    pub room: ChatRoom,
+    pub username: String,
+    /// Whether the speaker is a bot
+    pub bot: bool,
+    pub text: String,
+    /// When it was sent, in milliseconds since the Unix epoch
+    pub sent: u64,
+}
+
+/// Tidy up a line before it's sent, turning line breaks and other control
+/// characters into spaces so one line can't pass itself off as several.
+pub fn clean(text: &str) -> Result<String, ChatError> {
+    let text: String = text
+        .trim()
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    if text.is_empty() {
+        Err(ChatError::Empty)
+    } else if text.chars().count() > MAX_CHAT_LENGTH {
+        Err(ChatError::TooLong)
+    } else {
+        Ok(text)
+    }
+}
+
+/// When each account last sent lines, to keep them from flooding a room
+#[derive(Debug, Default)]
+pub struct ChatLimiter {
+    // by lowercased username, oldest first, only those inside the window
+    sent: HashMap<String, VecDeque<u64>>,
+}
+
+impl ChatLimiter {
+    /// Whether `account` can send a line at `now`, counting it if so.
+    pub fn allow(&mut self, account: &str, now: u64) -> bool {
+        let since = now.saturating_sub(RATE_LIMIT_WINDOW_MS);
+        self.sent.retain(|_, sent| {
+            while sent.front().is_some_and(|&time| time <= since) {
+                sent.pop_front();
+            }
+            !sent.is_empty()
+        });
+        let sent = self.sent.entry(account.to_lowercase()).or_default();
+        if sent.len() >= RATE_LIMIT_LINES {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
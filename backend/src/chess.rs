@@ -1,10 +1,34 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
 use std::num::NonZeroU8;
 use std::ops::Not;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+mod annotate;
+mod eval;
+mod fen;
+mod pocket;
+mod polyglot;
+mod search;
+mod syzygy;
+mod uci;
+mod variant;
+
+use pocket::Pockets;
+
+pub use annotate::Annotation;
+pub use eval::Evaluation;
+pub use polyglot::{Book, BookMove};
+pub use search::AnalysisLine;
+pub use syzygy::{Tablebase, TablebaseMove, Wdl};
+pub use uci::UciMove;
+pub use variant::{DrawReason, Outcome, VariantKind, WinReason};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 #[serde(from = "bool", into = "bool")]
 pub enum Player {
+    #[default]
     White,
     Black,
 }
@@ -26,12 +50,6 @@ impl From<Player> for bool {
     }
 }
 
-impl Default for Player {
-    fn default() -> Self {
-        Player::White
-    }
-}
-
 impl Not for Player {
     type Output = Self;
 
@@ -48,10 +66,14 @@ pub struct Piece {
     #[serde(alias = "white", rename(serialize = "white", deserialize = "white"))]
     player: Player,
     piecetype: PieceType,
-    position: Option<BoardLocation>,
+    position: Option<Square>,
     alive: bool,
     // whether the piece has ever been moved before
     moved: bool,
+    // whether the piece started out as a pawn, so it goes back to being one
+    // when it's captured into a pocket
+    #[serde(default)]
+    promoted: bool,
 }
 
 impl Piece {
@@ -68,6 +90,7 @@ impl Default for Piece {
             position: None,
             alive: true,
             moved: false,
+            promoted: false,
         }
     }
 }
@@ -86,6 +109,63 @@ pub enum PieceType {
 pub struct Board {
     pieces: Vec<Piece>,
     map: [[Option<NonZeroU8>; 8]; 8],
+    variant: VariantKind,
+    turn: Player,
+    // the square a pawn skipped over with a double move on the last turn
+    en_passant: Option<Square>,
+    // moves since the last capture or pawn move, for the fifty-move rule
+    halfmove_clock: u32,
+    fullmove_number: u32,
+    outcome: Option<Outcome>,
+    // pieces in hand, for variants with drops
+    pockets: Pockets,
+    history: Vec<Move>,
+    #[serde(skip)]
+    undo: Vec<Undo>,
+}
+
+// The undo stack is just bookkeeping, two boards are the same if everything
+// that gets sent to clients is.
+impl PartialEq for Board {
+    fn eq(&self, other: &Self) -> bool {
+        self.pieces == other.pieces
+            && self.map == other.map
+            && self.variant == other.variant
+            && self.turn == other.turn
+            && self.en_passant == other.en_passant
+            && self.halfmove_clock == other.halfmove_clock
+            && self.fullmove_number == other.fullmove_number
+            && self.outcome == other.outcome
+            && self.pockets == other.pockets
+            && self.history == other.history
+    }
+}
+
+impl Eq for Board {}
+
+/// Everything needed to take a move back exactly, including the state that
+/// the move itself destroyed.
+#[derive(Debug, Clone)]
+struct Undo {
+    piece_idx: usize,
+    from: Square,
+    to: Square,
+    // whether the piece had moved before this move
+    moved: bool,
+    promoted: bool,
+    // the index of the piece that was captured and the square it was on
+    captured: Option<(usize, Square)>,
+    // the pieces blown up by an Atomic capture and where they were, the
+    // capturing piece included
+    exploded: Vec<(usize, Square)>,
+    // what the capture put in the capturer's pocket
+    pocketed: Option<PieceType>,
+    // the side castled on and the square the rook started from
+    castling: Option<(Castling, Square)>,
+    en_passant: Option<Square>,
+    halfmove_clock: u32,
+    // for a drop, the captured piece whose slot the dropped piece took over
+    dropped: Option<Option<Piece>>,
 }
 
 #[derive(Debug, Clone)]
@@ -95,17 +175,138 @@ pub enum BoardSlot<'a> {
     Piece(&'a Piece),
 }
 
-#[derive(Debug, Clone)]
+/// Why a move was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MovePieceError {
-    // TODO: break down why the move is invalid
-    IllegalMove,
-    KingIsInCheck,
+    /// There's nothing on the square being moved from
+    NoPieceAtSource,
+    /// It's the other player's turn to move
     NotYourTurn,
+    /// The piece being moved belongs to the other player
+    NotYourPiece,
+    /// The piece doesn't move that way
+    InvalidMovement,
+    /// Another piece is in the way
+    PathBlocked,
+    /// The target square has one of the player's own pieces on it
+    CapturingOwnPiece,
+    /// Moving the piece would expose the king to check
+    PiecePinned,
+    /// The king is in check and the move doesn't get it out of check
+    KingIsInCheck,
+    /// The king would be moving onto an attacked square
+    MovesIntoCheck,
+    /// The king or rook has already moved, or the rook isn't there
+    CastlingNotAllowed,
+    /// The king would castle out of, through, or into check
+    CastlingThroughCheck,
+    /// A pawn reached the last rank without saying what it becomes
+    MissingPromotion,
+    /// Pawns can't promote to pawns or kings
+    InvalidPromotion,
+    /// The variant makes capturing compulsory and a capture is available
+    MustCapture,
+    /// The game has already been decided
+    GameOver,
+    /// Pieces can only be dropped in variants like Crazyhouse
+    DropsNotAllowed,
+    /// There's no piece of that kind in the player's pocket
+    NotInPocket,
+    /// Pieces can only be dropped onto empty squares
+    SquareOccupied,
+    /// Pawns can't be dropped on the first or last rank
+    PawnOnBackRank,
+    /// Kings can't capture in Atomic, since they'd blow up with the piece
+    KingCannotCapture,
+    /// The capture would blow up the player's own king
+    ExplodesOwnKing,
+}
+
+impl fmt::Display for MovePieceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            MovePieceError::NoPieceAtSource => "There's no piece there to move",
+            MovePieceError::NotYourTurn => "It's not your turn",
+            MovePieceError::NotYourPiece => "That's not your piece to move",
+            MovePieceError::InvalidMovement => "That piece doesn't move that way",
+            MovePieceError::PathBlocked => "Another piece is in the way",
+            MovePieceError::CapturingOwnPiece => "You can't capture your own piece",
+            MovePieceError::PiecePinned => "That piece is pinned to your king",
+            MovePieceError::KingIsInCheck => "Your king is in check",
+            MovePieceError::MovesIntoCheck => "Your king can't move into check",
+            MovePieceError::CastlingNotAllowed => "You can't castle on that side anymore",
+            MovePieceError::CastlingThroughCheck => "You can't castle out of or through check",
+            MovePieceError::MissingPromotion => "Choose a piece to promote to",
+            MovePieceError::InvalidPromotion => "Pawns can't promote to that",
+            MovePieceError::MustCapture => "You have to capture when you can",
+            MovePieceError::GameOver => "The game is over",
+            MovePieceError::DropsNotAllowed => "Pieces can't be dropped in this game",
+            MovePieceError::NotInPocket => "You don't have that piece to drop",
+            MovePieceError::SquareOccupied => "There's already a piece there",
+            MovePieceError::PawnOnBackRank => "Pawns can't be dropped on the first or last rank",
+            MovePieceError::KingCannotCapture => "Kings can't capture in Atomic",
+            MovePieceError::ExplodesOwnKing => "That would blow up your own king",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Castling {
+    KingSide,
+    QueenSide,
+}
+
+impl Castling {
+    /// The files the king and rook end up on after castling on this side.
+    /// These are the same in Chess960 wherever the pieces started.
+    fn destination_files(self) -> (usize, usize) {
+        match self {
+            Castling::KingSide => (6, 5),
+            Castling::QueenSide => (2, 3),
+        }
+    }
+}
+
+/// A move that has been played on the board.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Move {
+    pub player: Player,
+    pub from: Square,
+    pub to: Square,
+    pub piece: PieceType,
+    pub captured: Option<PieceType>,
+    pub promotion: Option<PieceType>,
+    pub castling: Option<Castling>,
+    pub en_passant: bool,
+    pub check: bool,
+    /// The piece came from the player's pocket rather than `from`, which is
+    /// the same as `to`
+    #[serde(default)]
+    pub dropped: bool,
+    /// Standard algebraic notation, e.g. "Nxe5+"
+    pub san: String,
+}
+
+impl Move {
+    /// The move in the long algebraic notation UCI engines use, e.g. "e2e4",
+    /// "e7e8q", or "N@f3" for a drop. Castling is the king's two-square move.
+    pub fn uci(&self) -> String {
+        if self.dropped {
+            return format!("{}@{}", self.piece.letter(), self.to);
+        }
+        let promotion = self
+            .promotion
+            .map(|piece| piece.letter().to_ascii_lowercase().to_string())
+            .unwrap_or_default();
+        format!("{}{}{}", self.from, self.to, promotion)
+    }
 }
 
 pub struct PieceIter<'a> {
     pieces: &'a [Piece],
     idx: usize,
+    player: Player,
 }
 
 impl<'a> Iterator for PieceIter<'a> {
@@ -115,7 +316,7 @@ impl<'a> Iterator for PieceIter<'a> {
             if self.idx < self.pieces.len() {
                 let val = &self.pieces[self.idx];
                 self.idx += 1;
-                if !val.alive {
+                if !val.alive || val.player != self.player {
                     continue;
                 }
                 return Some(val);
@@ -126,122 +327,745 @@ impl<'a> Iterator for PieceIter<'a> {
     }
 }
 
-pub type BoardLocation = (NonZeroU8, NonZeroU8);
+/// A square on the board, with the file and rank both counted from 0, so a1
+/// is (0, 0) and h8 is (7, 7). Clients send either the 1-based `[file, rank]`
+/// pair the board has always been drawn with or an algebraic name like "e4",
+/// and anything off the board is rejected when the message is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "SquareRepr", into = "SquareRepr")]
+pub struct Square {
+    file: u8,
+    rank: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SquareRepr {
+    Coords(u8, u8),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSquare(String);
+
+impl fmt::Display for InvalidSquare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} isn't a square on the board", self.0)
+    }
+}
+
+impl Square {
+    pub fn new(file: usize, rank: usize) -> Option<Self> {
+        if file < 8 && rank < 8 {
+            Some(Self {
+                file: file as u8,
+                rank: rank as u8,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn file(self) -> usize {
+        self.file as usize
+    }
+
+    pub fn rank(self) -> usize {
+        self.rank as usize
+    }
+
+    fn coords(self) -> (usize, usize) {
+        (self.file(), self.rank())
+    }
+}
+
+impl fmt::Display for Square {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", (b'a' + self.file) as char, self.rank + 1)
+    }
+}
+
+impl FromStr for Square {
+    type Err = InvalidSquare;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => {
+                Ok(Square::new((file - b'a') as usize, (rank - b'1') as usize).unwrap())
+            }
+            _ => Err(InvalidSquare(format!("{:?}", s))),
+        }
+    }
+}
+
+impl TryFrom<SquareRepr> for Square {
+    type Error = InvalidSquare;
+
+    fn try_from(repr: SquareRepr) -> Result<Self, Self::Error> {
+        match repr {
+            SquareRepr::Coords(x, y) => {
+                new_loc(x, y).ok_or_else(|| InvalidSquare(format!("({}, {})", x, y)))
+            }
+            SquareRepr::Name(name) => name.parse(),
+        }
+    }
+}
+
+impl From<Square> for SquareRepr {
+    fn from(square: Square) -> Self {
+        SquareRepr::Coords(square.file + 1, square.rank + 1)
+    }
+}
+
+impl PieceType {
+    /// The letter used for this piece in algebraic notation
+    pub fn letter(self) -> char {
+        match self {
+            PieceType::Pawn => 'P',
+            PieceType::Rook => 'R',
+            PieceType::Knight => 'N',
+            PieceType::Bishop => 'B',
+            PieceType::Queen => 'Q',
+            PieceType::King => 'K',
+        }
+    }
+}
 
 impl Board {
+    /// Play a move for `player`, returning a record of it. Pawns reaching the
+    /// last rank become `promotion`.
     pub fn move_piece(
         &mut self,
         player: Player,
-        from: BoardLocation,
-        to: BoardLocation,
-    ) -> Result<(), MovePieceError> {
+        from: Square,
+        to: Square,
+        promotion: Option<PieceType>,
+    ) -> Result<Move, MovePieceError> {
+        let before = self.clone();
+        let mv = self.try_move(player, from, to, promotion)?;
+        Ok(self.finish_move(before, mv))
+    }
+
+    /// Fill in the check, SAN and outcome for a move that's just been made
+    /// and add it to the history. `before` is the board before the move.
+    fn finish_move(&mut self, before: Board, mut mv: Move) -> Move {
+        let rules = self.variant.rules();
+        mv.check = rules.has_check() && self.is_check(!mv.player);
+        self.history.push(mv);
+        self.outcome = rules.outcome(self);
+        let checkmate = matches!(
+            self.outcome,
+            Some(Outcome::Win {
+                reason: WinReason::Checkmate,
+                ..
+            })
+        );
+        let mv = self.history.last_mut().unwrap();
+        mv.san = before.san(mv, checkmate);
+
+        mv.clone()
+    }
+
+    /// Every move played so far, oldest first.
+    pub fn history(&self) -> &[Move] {
+        &self.history
+    }
+
+    /// Take back the last move, putting the board back exactly as it was
+    /// before it, castling rights and en passant included. Returns the move
+    /// that was taken back, if it was in the history.
+    pub fn unmake_move(&mut self) -> Option<Move> {
+        let mut undo = self.undo.pop()?;
+        match undo.dropped.take() {
+            Some(replaced) => self.unmake_drop(&undo, replaced),
+            None => self.unmake_piece_move(&undo),
+        }
+
+        self.en_passant = undo.en_passant;
+        self.halfmove_clock = undo.halfmove_clock;
+        self.outcome = None;
+        self.turn = !self.turn;
+        if self.turn == Player::Black {
+            self.fullmove_number -= 1;
+        }
+        if self.history.len() > self.undo.len() {
+            self.history.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Put a piece that moved on the board back where it came from, along
+    /// with anything it captured.
+    fn unmake_piece_move(&mut self, undo: &Undo) {
+        for &(idx, square) in undo.exploded.iter() {
+            let piece = &mut self.pieces[idx];
+            piece.alive = true;
+            piece.position = Some(square);
+            self.map[square.file()][square.rank()] = NonZeroU8::new(idx as u8 + 1);
+        }
+        let (f_x, f_y) = undo.from.coords();
+        let (t_x, t_y) = undo.to.coords();
+
+        // in Chess960 the king and rook can land on each other's starting
+        // squares, so clear both before putting either back
+        if let Some((side, rook_from)) = undo.castling {
+            let (_, rook_to) = side.destination_files();
+            let rook_idx = self.map[rook_to][t_y].take().unwrap();
+            self.map[t_x][t_y] = None;
+            self.map[rook_from.file()][rook_from.rank()] = Some(rook_idx);
+            let rook = &mut self.pieces[rook_idx.get() as usize - 1];
+            rook.position = Some(rook_from);
+            // castling is only allowed with a rook that has never moved
+            rook.moved = false;
+        } else {
+            self.map[t_x][t_y] = None;
+        }
+        self.map[f_x][f_y] = NonZeroU8::new(undo.piece_idx as u8 + 1);
+        let piece = &mut self.pieces[undo.piece_idx];
+        piece.position = Some(undo.from);
+        piece.moved = undo.moved;
+        if undo.promoted {
+            piece.piecetype = PieceType::Pawn;
+            piece.promoted = false;
+        }
+        let player = piece.player;
+        if let Some(pocketed) = undo.pocketed {
+            self.pockets.get_mut(player).take(pocketed);
+        }
+
+        if let Some((captured_idx, square)) = undo.captured {
+            let captured = &mut self.pieces[captured_idx];
+            captured.alive = true;
+            captured.position = Some(square);
+            self.map[square.file()][square.rank()] = NonZeroU8::new(captured_idx as u8 + 1);
+        }
+    }
+
+    /// Apply a move if it's legal, without recording it in the history. The
+    /// returned move has no SAN or check information filled in.
+    fn try_move(
+        &mut self,
+        player: Player,
+        from: Square,
+        to: Square,
+        promotion: Option<PieceType>,
+    ) -> Result<Move, MovePieceError> {
         // easier than remembering how we mutate the board, just fully reset it
         // at the cost of a copy on every move check. Good enough for now.
         let backup_board = self.clone();
+        let rules = self.variant.rules();
 
-        let f_idx1 = from.0.get() as usize - 1;
-        let f_idx2 = from.1.get() as usize - 1;
-        let t_idx1 = to.0.get() as usize - 1;
-        let t_idx2 = to.1.get() as usize - 1;
+        if self.outcome.is_some() {
+            return Err(MovePieceError::GameOver);
+        }
+        if player != self.turn {
+            return Err(MovePieceError::NotYourTurn);
+        }
+        if from == to {
+            return Err(MovePieceError::InvalidMovement);
+        }
+        let (f_idx1, f_idx2) = from.coords();
+        let (t_idx1, t_idx2) = to.coords();
         let src_idx: NonZeroU8;
         if let Some(s_idx) = self.map[f_idx1][f_idx2] {
             src_idx = s_idx;
         } else {
-            return Err(MovePieceError::IllegalMove);
+            return Err(MovePieceError::NoPieceAtSource);
         }
         let piece_idx = src_idx.get() - 1;
 
         let piece = self.pieces[piece_idx as usize].clone();
         if piece.player != player {
-            return Err(MovePieceError::NotYourTurn);
+            return Err(MovePieceError::NotYourPiece);
         }
         let pos = (t_idx1, t_idx2);
-        let valid_move = match piece.piecetype {
-            PieceType::Pawn => self.valid_pawn_move(&piece, pos),
-            PieceType::Rook => self.valid_rook_move(&piece, pos),
-            PieceType::Knight => self.valid_knight_move(&piece, pos),
-            PieceType::King => self.valid_king_move(&piece, pos),
-            PieceType::Queen => self.valid_queen_move(&piece, pos),
-            PieceType::Bishop => self.valid_bishop_move(&piece, pos),
+        let castling_side = self
+            .castling_side(&piece, pos)
+            .filter(|_| rules.allows_castling());
+        let castling = match castling_side {
+            Some(side) => Some((side, self.castling_move(&piece, side)?)),
+            None => None,
         };
+        let valid_move = castling.is_some()
+            || match piece.piecetype {
+                PieceType::Pawn => self.valid_pawn_move(&piece, pos),
+                PieceType::Rook => self.valid_rook_move(&piece, pos),
+                PieceType::Knight => self.valid_knight_move(&piece, pos),
+                PieceType::King => self.valid_king_move(&piece, pos),
+                PieceType::Queen => self.valid_queen_move(&piece, pos),
+                PieceType::Bishop => self.valid_bishop_move(&piece, pos),
+            };
 
         if !valid_move {
-            return Err(MovePieceError::IllegalMove);
+            return Err(self.invalid_move_reason(&piece, pos));
         }
+        let was_in_check = self.is_check(player);
 
-        // TODO: handle captures
-        if let Some(target_idx) = self.map[t_idx1][t_idx2] {
+        let en_passant = piece.piecetype == PieceType::Pawn
+            && t_idx1 != f_idx1
+            && self.map[t_idx1][t_idx2].is_none();
+        // the pawn taken en passant sits beside the capturing pawn, not on
+        // the square it moves to
+        let captured_square = if en_passant {
+            (t_idx1, f_idx2)
+        } else {
+            (t_idx1, t_idx2)
+        };
+        let mut captured = None;
+        let mut captured_idx = None;
+        let mut pocketed = None;
+        if let Some(target_idx) = self.map[captured_square.0][captured_square.1] {
             let t_piece_idx = target_idx.get() as usize - 1;
             let target_piece = &self.pieces[t_piece_idx];
             if !target_piece.color() == piece.color() {
+                captured = Some(target_piece.piecetype);
+                captured_idx = Some((
+                    t_piece_idx,
+                    Square::new(captured_square.0, captured_square.1).unwrap(),
+                ));
                 self.pieces[t_piece_idx].alive = false;
                 self.pieces[t_piece_idx].position = None;
-                //self.map[t_idx1][t_idx2] = None;
+                self.map[captured_square.0][captured_square.1] = None;
+                if rules.pockets_captures() {
+                    // promoted pieces go back to being pawns in the pocket
+                    let kind = if self.pieces[t_piece_idx].promoted {
+                        PieceType::Pawn
+                    } else {
+                        self.pieces[t_piece_idx].piecetype
+                    };
+                    self.pockets.get_mut(player).add(kind);
+                    pocketed = Some(kind);
+                }
             }
-
-            //return Err(MovePieceError::IllegalMove);
         }
 
-        self.map[t_idx1][t_idx2] = Some(src_idx);
         self.map[f_idx1][f_idx2] = None;
+        // a castling king ends up on the g or c file whichever square was
+        // asked for, the king's own square or its rook's
+        let to = match castling {
+            Some((side, rook_from)) => {
+                let (king_to, rook_to) = side.destination_files();
+                let rook_idx = self.map[rook_from.file()][rook_from.rank()].take().unwrap();
+                self.map[rook_to][f_idx2] = Some(rook_idx);
+                let rook = &mut self.pieces[rook_idx.get() as usize - 1];
+                rook.position = Some(Square::new(rook_to, f_idx2).unwrap());
+                rook.moved = true;
+                Square::new(king_to, f_idx2).unwrap()
+            }
+            None => to,
+        };
+        let (t_idx1, t_idx2) = to.coords();
+        self.map[t_idx1][t_idx2] = Some(src_idx);
         self.pieces[piece_idx as usize].position = Some(to);
         self.pieces[piece_idx as usize].moved = true;
 
-        let board_ended_in_check = self.is_check(player);
+        let last_rank = match player {
+            Player::White => 7,
+            Player::Black => 0,
+        };
+        let promotion = if piece.piecetype == PieceType::Pawn && t_idx2 == last_rank {
+            let promote_to = match promotion {
+                Some(promote_to) if !rules.can_promote_to(promote_to) => {
+                    *self = backup_board;
+                    return Err(MovePieceError::InvalidPromotion);
+                }
+                Some(promote_to) => promote_to,
+                None => {
+                    *self = backup_board;
+                    return Err(MovePieceError::MissingPromotion);
+                }
+            };
+            self.pieces[piece_idx as usize].piecetype = promote_to;
+            self.pieces[piece_idx as usize].promoted = true;
+            Some(promote_to)
+        } else {
+            None
+        };
+
+        // in Atomic a capture blows up the capturing piece along with every
+        // piece around it that isn't a pawn
+        let mut exploded = vec![];
+        if captured.is_some() && rules.explodes() {
+            for x in t_idx1.saturating_sub(1)..=(t_idx1 + 1).min(7) {
+                for y in t_idx2.saturating_sub(1)..=(t_idx2 + 1).min(7) {
+                    let idx = match self.map[x][y] {
+                        Some(idx) => idx.get() as usize - 1,
+                        None => continue,
+                    };
+                    let target = &mut self.pieces[idx];
+                    if target.piecetype == PieceType::Pawn && idx != piece_idx as usize {
+                        continue;
+                    }
+                    exploded.push((idx, target.position.unwrap()));
+                    target.alive = false;
+                    target.position = None;
+                    self.map[x][y] = None;
+                }
+            }
+        }
+
+        let double_step =
+            piece.piecetype == PieceType::Pawn && (t_idx2 as isize - f_idx2 as isize).abs() == 2;
+        let prev_en_passant = self.en_passant;
+        self.en_passant = if double_step {
+            Some(Square::new(f_idx1, (f_idx2 + t_idx2) / 2).unwrap())
+        } else {
+            None
+        };
+
+        let board_ended_in_check = rules.has_check() && self.is_check(player);
         if board_ended_in_check {
             // reset the board to its original position
             *self = backup_board;
-            return Err(MovePieceError::KingIsInCheck);
+            return Err(if piece.piecetype == PieceType::King {
+                MovePieceError::MovesIntoCheck
+            } else if was_in_check {
+                MovePieceError::KingIsInCheck
+            } else {
+                MovePieceError::PiecePinned
+            });
         }
 
-        Ok(())
+        let mv = Move {
+            player,
+            from,
+            to,
+            piece: piece.piecetype,
+            captured,
+            promotion,
+            castling: castling.map(|(side, _)| side),
+            en_passant,
+            check: false,
+            dropped: false,
+            san: String::new(),
+        };
+        if let Err(e) = rules.check_move(&backup_board, &mv) {
+            *self = backup_board;
+            return Err(e);
+        }
+
+        self.undo.push(Undo {
+            piece_idx: piece_idx as usize,
+            from,
+            to,
+            moved: piece.moved,
+            promoted: promotion.is_some(),
+            captured: captured_idx,
+            exploded,
+            pocketed,
+            castling,
+            en_passant: prev_en_passant,
+            halfmove_clock: self.halfmove_clock,
+            dropped: None,
+        });
+        if piece.piecetype == PieceType::Pawn || captured.is_some() {
+            self.halfmove_clock = 0;
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if player == Player::Black {
+            self.fullmove_number += 1;
+        }
+        self.turn = !player;
+
+        Ok(mv)
     }
 
-    fn iter_pieces(&self, player: Player) -> PieceIter {
-        let mid = self.pieces.len() / 2;
-        match player {
-            Player::White => PieceIter {
-                idx: 0,
-                pieces: &self.pieces[..mid],
-            },
-            Player::Black => PieceIter {
-                idx: 0,
-                pieces: &self.pieces[mid..],
-            },
+    /// Can `player` make any legal move at all?
+    fn has_legal_move(&self, player: Player) -> bool {
+        let can_move = self.iter_pieces(player).any(|piece| {
+            let from = piece.position.unwrap();
+            (0..8).any(|x| {
+                (0..8).any(|y| {
+                    let to = Square::new(x, y).unwrap();
+                    to != from
+                        && self
+                            .clone()
+                            .try_move(player, from, to, Some(PieceType::Queen))
+                            .is_ok()
+                })
+            })
+        });
+        can_move || (self.variant.rules().has_drops() && self.has_legal_drop(player))
+    }
+
+    /// Every legal move for the player to move, each with the board it leads
+    /// to. Drops aren't included.
+    fn legal_moves(&self) -> Vec<(Move, Board)> {
+        let player = self.turn;
+        let mut moves = vec![];
+        for piece in self.iter_pieces(player) {
+            let from = piece.position.unwrap();
+            for (x, y) in (0..8).flat_map(|x| (0..8).map(move |y| (x, y))) {
+                let to = Square::new(x, y).unwrap();
+                // only bother trying squares the piece could get to at all
+                let reachable = self.piece_can_attack(piece, (x, y))
+                    || (piece.piecetype == PieceType::Pawn && x == from.file())
+                    || self.castling_side(piece, (x, y)).is_some();
+                if to == from || !reachable {
+                    continue;
+                }
+                let mut board = self.clone();
+                let mv = match board.try_move(player, from, to, Some(PieceType::Queen)) {
+                    Ok(mv) => mv,
+                    Err(_) => continue,
+                };
+                if mv.promotion.is_some() {
+                    for &promotion in &[PieceType::Rook, PieceType::Bishop, PieceType::Knight] {
+                        let mut board = self.clone();
+                        if let Ok(mv) = board.try_move(player, from, to, Some(promotion)) {
+                            moves.push((mv, board));
+                        }
+                    }
+                }
+                moves.push((mv, board));
+            }
         }
+        moves
     }
 
-    fn get_king(&self, player: Player) -> &Piece {
-        // hacky, probably clean this up
-        let king_idx = match player {
-            Player::White => 15,
-            Player::Black => 31,
-        };
-        &self.pieces[king_idx]
+    /// Write a move in standard algebraic notation. `self` is the board as it
+    /// was before the move was played.
+    fn san(&self, mv: &Move, checkmate: bool) -> String {
+        let mut san = String::new();
+        match mv.castling {
+            _ if mv.dropped => {
+                san.push(mv.piece.letter());
+                san.push('@');
+                san.push_str(&mv.to.to_string());
+            }
+            Some(Castling::KingSide) => san.push_str("O-O"),
+            Some(Castling::QueenSide) => san.push_str("O-O-O"),
+            None => {
+                let (f_x, f_y) = mv.from.coords();
+                let from_name = mv.from.to_string();
+                if mv.piece == PieceType::Pawn {
+                    if mv.captured.is_some() {
+                        san.push_str(&from_name[..1]);
+                    }
+                } else {
+                    san.push(mv.piece.letter());
+                    // other pieces of the same kind that could also have
+                    // moved here need the move to say which one it was
+                    let others: Vec<(usize, usize)> = self
+                        .iter_pieces(mv.player)
+                        .filter(|p| p.piecetype == mv.piece && p.position != Some(mv.from))
+                        .filter(|p| {
+                            let other_from = p.position.unwrap();
+                            self.clone()
+                                .try_move(mv.player, other_from, mv.to, None)
+                                .is_ok()
+                        })
+                        .map(|p| p.position.unwrap().coords())
+                        .collect();
+                    if !others.is_empty() {
+                        if others.iter().all(|&(x, _)| x != f_x) {
+                            san.push_str(&from_name[..1]);
+                        } else if others.iter().all(|&(_, y)| y != f_y) {
+                            san.push_str(&from_name[1..]);
+                        } else {
+                            san.push_str(&from_name);
+                        }
+                    }
+                }
+                if mv.captured.is_some() {
+                    san.push('x');
+                }
+                san.push_str(&mv.to.to_string());
+                if let Some(promotion) = mv.promotion {
+                    san.push('=');
+                    san.push(promotion.letter());
+                }
+            }
+        }
+        if checkmate {
+            san.push('#');
+        } else if mv.check {
+            san.push('+');
+        }
+        san
     }
 
-    /// Is player in check?
+    fn iter_pieces(&self, player: Player) -> PieceIter<'_> {
+        PieceIter {
+            idx: 0,
+            pieces: &self.pieces,
+            player,
+        }
+    }
+
+    fn get_king(&self, player: Player) -> Option<&Piece> {
+        self.iter_pieces(player)
+            .find(|piece| piece.piecetype == PieceType::King)
+    }
+
+    /// Is player in check? Some variants have their own idea of what that
+    /// means.
     fn is_check(&self, player: Player) -> bool {
+        self.variant.rules().is_check(self, player)
+    }
+
+    /// Could `player`'s king be captured if it were the other player's turn?
+    fn king_attacked(&self, player: Player) -> bool {
         // Determine if king is about to be captured...
-        let king = self.get_king(player);
-        let (k_x, k_y) = king.position.unwrap();
-        let king_pos = (k_x.get() as usize - 1, k_y.get() as usize - 1);
-        self.iter_pieces(!player)
-            .any(|piece| self.piece_can_attack(piece, king_pos))
+        match self.get_king(player) {
+            Some(king) => self.is_attacked(king.position.unwrap().coords(), !player),
+            None => false,
+        }
+    }
+
+    /// Could any of `player`'s pieces capture on `pos`?
+    fn is_attacked(&self, pos: (usize, usize), player: Player) -> bool {
+        self.iter_pieces(player)
+            .any(|piece| self.piece_can_attack(piece, pos))
     }
 
     fn piece_can_attack(&self, piece: &Piece, pos: (usize, usize)) -> bool {
         match piece.piecetype {
-            PieceType::Pawn => self.valid_pawn_move(&piece, pos),
-            PieceType::Rook => self.valid_rook_move(&piece, pos),
-            PieceType::Knight => self.valid_knight_move(&piece, pos),
-            PieceType::King => self.valid_king_move(&piece, pos),
-            PieceType::Queen => self.valid_queen_move(&piece, pos),
-            PieceType::Bishop => self.valid_bishop_move(&piece, pos),
+            PieceType::Pawn => self.pawn_attacks(piece, pos),
+            PieceType::Rook => self.valid_rook_move(piece, pos),
+            PieceType::Knight => self.valid_knight_move(piece, pos),
+            PieceType::King => self.valid_king_move(piece, pos),
+            PieceType::Queen => self.valid_queen_move(piece, pos),
+            PieceType::Bishop => self.valid_bishop_move(piece, pos),
+        }
+    }
+
+    /// Pawns only attack diagonally, whether or not anything is there yet.
+    fn pawn_attacks(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
+        let (p_x, p_y) = piece.position.unwrap().coords();
+        let forward = match piece.color() {
+            Player::White => 1,
+            Player::Black => -1,
+        };
+        (target_x as isize - p_x as isize).abs() == 1 && target_y as isize - p_y as isize == forward
+    }
+
+    /// Is moving `piece` to `pos` an attempt to castle, and on which side?
+    /// A king castles by moving onto one of its own rooks, which is the only
+    /// way to say it in Chess960, or by moving two squares towards the g or c
+    /// file the way it's done in standard chess.
+    fn castling_side(
+        &self,
+        piece: &Piece,
+        (target_x, target_y): (usize, usize),
+    ) -> Option<Castling> {
+        if piece.piecetype != PieceType::King {
+            return None;
+        }
+        let (p_x, p_y) = piece.position.unwrap().coords();
+        if p_y != target_y {
+            return None;
+        }
+        let onto_own_rook = match self.get_location((target_x, target_y)) {
+            BoardSlot::Piece(target) => {
+                target.piecetype == PieceType::Rook && target.color() == piece.color()
+            }
+            _ => false,
+        };
+        let two_squares =
+            (target_x as isize - p_x as isize).abs() == 2 && (target_x == 6 || target_x == 2);
+        if !onto_own_rook && !two_squares {
+            return None;
+        }
+        if target_x > p_x {
+            Some(Castling::KingSide)
+        } else {
+            Some(Castling::QueenSide)
+        }
+    }
+
+    /// Check that `piece` (a king) can castle on `side`, returning the square
+    /// of the rook it castles with.
+    fn castling_move(&self, piece: &Piece, side: Castling) -> Result<Square, MovePieceError> {
+        let (p_x, p_y) = piece.position.unwrap().coords();
+        let back_rank = match piece.color() {
+            Player::White => 0,
+            Player::Black => 7,
+        };
+        if piece.moved || p_y != back_rank {
+            return Err(MovePieceError::CastlingNotAllowed);
+        }
+        // the rook that hasn't moved yet on that side of the king, the
+        // outermost one if there's somehow more than one
+        let rook = self
+            .iter_pieces(piece.color())
+            .filter(|p| p.piecetype == PieceType::Rook && !p.moved)
+            .map(|p| p.position.unwrap())
+            .filter(|sq| sq.rank() == p_y)
+            .filter(|sq| match side {
+                Castling::KingSide => sq.file() > p_x,
+                Castling::QueenSide => sq.file() < p_x,
+            })
+            .max_by_key(|sq| match side {
+                Castling::KingSide => sq.file(),
+                Castling::QueenSide => 7 - sq.file(),
+            });
+        let rook = match rook {
+            Some(rook) => rook,
+            None => return Err(MovePieceError::CastlingNotAllowed),
+        };
+        let (king_to, rook_to) = side.destination_files();
+        let span = |a: usize, b: usize| if a < b { a..=b } else { b..=a };
+        // everything either piece crosses or lands on has to be empty, apart
+        // from the king and rook themselves
+        let blocked = span(p_x, king_to)
+            .chain(span(rook.file(), rook_to))
+            .any(|x| x != p_x && x != rook.file() && self.map[x][p_y].is_some());
+        if blocked {
+            return Err(MovePieceError::PathBlocked);
+        }
+        // the king can't castle out of, through, or into check
+        if span(p_x, king_to).any(|x| self.is_attacked((x, p_y), !piece.color())) {
+            return Err(MovePieceError::CastlingThroughCheck);
         }
+        Ok(rook)
     }
 
-    fn get_location(&self, (x, y): (usize, usize)) -> BoardSlot {
+    /// Work out why `piece` can't move to `pos`, given that it can't.
+    fn invalid_move_reason(
+        &self,
+        piece: &Piece,
+        (target_x, target_y): (usize, usize),
+    ) -> MovePieceError {
+        let target = self.get_location((target_x, target_y));
+        if let BoardSlot::Piece(target_piece) = target {
+            if target_piece.color() == piece.color() {
+                return MovePieceError::CapturingOwnPiece;
+            }
+        }
+        let (p_x, p_y) = piece.position.unwrap().coords();
+        let x_diff = (target_x as isize - p_x as isize).abs();
+        let y_diff = target_y as isize - p_y as isize;
+        let straight = x_diff == 0 || y_diff == 0;
+        let diagonal = x_diff == y_diff.abs();
+        // the squares in between are the only thing left that can be wrong
+        // with a move of the right shape
+        let right_shape = match piece.piecetype {
+            PieceType::Pawn => {
+                let forward = match piece.color() {
+                    Player::White => 1,
+                    Player::Black => -1,
+                };
+                let pushes_forward = y_diff == forward || (y_diff == 2 * forward && !piece.moved);
+                // a diagonal step with nothing to capture is the wrong shape
+                // for a pawn
+                x_diff == 0 && pushes_forward
+            }
+            PieceType::Rook => straight,
+            PieceType::Bishop => diagonal,
+            PieceType::Queen => straight || diagonal,
+            PieceType::Knight | PieceType::King => false,
+        };
+        if right_shape {
+            MovePieceError::PathBlocked
+        } else {
+            MovePieceError::InvalidMovement
+        }
+    }
+
+    fn get_location(&self, (x, y): (usize, usize)) -> BoardSlot<'_> {
         if x > 7 || y > 7 {
             return BoardSlot::OutOfBounds;
         }
@@ -256,53 +1080,40 @@ impl Board {
         // TODO: write test cases...
         // Forward one space - done
         // Forward two space on first move - done
-        // En Passant - TODO
+        // En Passant - done
         // Diagonal to capture - done
 
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
-        let correct_y_move = match piece.color() {
-            Player::White => {
-                p_y + 1 == target_y
-                    || (if !piece.moved {
-                        p_y + 2 == target_y
-                    } else {
-                        false
-                    })
-            }
-            Player::Black => {
-                (p_y as isize - 1) == target_y as isize
-                    || (if !piece.moved {
-                        (p_y as isize - 2) == target_y as isize
-                    } else {
-                        false
-                    })
-            }
+        let (p_x, p_y) = piece.position.unwrap().coords();
+        let forward: isize = match piece.color() {
+            Player::White => 1,
+            Player::Black => -1,
         };
+        let y_diff = target_y as isize - p_y as isize;
+        let x_diff = (target_x as isize - p_x as isize).abs();
+        let one_step = y_diff == forward;
+        // two steps only from the starting square, and only over an empty one
+        let two_step = y_diff == 2 * forward
+            && !piece.moved
+            && self.map[p_x][(p_y as isize + forward) as usize].is_none();
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
+            BoardSlot::OutOfBounds => false,
             BoardSlot::Empty => {
-                return p_x == target_x && correct_y_move;
+                let en_passant = self.en_passant.map(Square::coords) == Some((target_x, target_y));
+                (x_diff == 0 && (one_step || two_step)) || (x_diff == 1 && one_step && en_passant)
             }
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
-                let x_diff = ((target_x as isize) - (p_x as isize)).abs();
 
-                return x_diff == 1 && correct_y_move;
+                x_diff == 1 && one_step
             }
         }
     }
     fn valid_rook_move(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
         // Forward, backward, sideways any number space - DONE
-        // Castle (when valid) - TODO
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
+        // Castle - handled as a king move, see `castling_move`
+        let (p_x, p_y) = piece.position.unwrap().coords();
         let only_moved_in_one_axis =
             (p_x == target_x && p_y != target_y) || (p_x != target_x && p_y == target_y);
         if !only_moved_in_one_axis {
@@ -334,27 +1145,21 @@ impl Board {
         }
 
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
-            BoardSlot::Empty => {
-                return true;
-            }
+            BoardSlot::OutOfBounds => false,
+            BoardSlot::Empty => true,
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
 
-                return true;
+                true
             }
         }
     }
     fn valid_knight_move(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
         // Forward 2, side 1
         // (8 possible moves)
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
+        let (p_x, p_y) = piece.position.unwrap().coords();
         let x_diff = (p_x as isize - target_x as isize).abs();
         let y_diff = (p_y as isize - target_y as isize).abs();
         let valid_knight_move = (x_diff == 2 && y_diff == 1) || (x_diff == 1 && y_diff == 2);
@@ -363,27 +1168,21 @@ impl Board {
         }
 
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
-            BoardSlot::Empty => {
-                return true;
-            }
+            BoardSlot::OutOfBounds => false,
+            BoardSlot::Empty => true,
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
 
-                return true;
+                true
             }
         }
     }
     fn valid_king_move(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
         // One space any direction
-        // Castle (when valid)
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
+        // Castle (when valid) - see `castling_move`
+        let (p_x, p_y) = piece.position.unwrap().coords();
         let x_diff = (p_x as isize - target_x as isize).abs();
         let y_diff = (p_y as isize - target_y as isize).abs();
         let only_moved_1_square = x_diff <= 1 && y_diff <= 1;
@@ -392,26 +1191,20 @@ impl Board {
         }
 
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
-            BoardSlot::Empty => {
-                return true;
-            }
+            BoardSlot::OutOfBounds => false,
+            BoardSlot::Empty => true,
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
 
-                return true;
+                true
             }
         }
     }
     fn valid_queen_move(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
         // Forward, backward, sideways, and diagonally any number space
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
+        let (p_x, p_y) = piece.position.unwrap().coords();
         let x_diff = (p_x as isize - target_x as isize).abs();
         let y_diff = (p_y as isize - target_y as isize).abs();
         let moved_diagonally = x_diff == y_diff;
@@ -448,7 +1241,7 @@ impl Board {
         let x_op = if p_x > target_x { std::ops::Sub::sub } else { std::ops::Add::add };
             #[rustfmt::skip]
         let y_op = if p_y > target_y { std::ops::Sub::sub } else { std::ops::Add::add };
-            let num_squares = (p_x as isize - target_x as isize).abs() as usize;
+            let num_squares = (p_x as isize - target_x as isize).unsigned_abs();
             let range = (1..num_squares).map(|i| (x_op(p_x, i), y_op(p_y, i)));
             for (x, y) in range {
                 if self.map[x][y].is_some() {
@@ -458,26 +1251,20 @@ impl Board {
         }
 
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
-            BoardSlot::Empty => {
-                return true;
-            }
+            BoardSlot::OutOfBounds => false,
+            BoardSlot::Empty => true,
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
 
-                return true;
+                true
             }
         }
     }
     fn valid_bishop_move(&self, piece: &Piece, (target_x, target_y): (usize, usize)) -> bool {
         // Diagonally any number space
-        let pos = piece.position.unwrap();
-        let p_x = pos.0.get() as usize - 1;
-        let p_y = pos.1.get() as usize - 1;
+        let (p_x, p_y) = piece.position.unwrap().coords();
         let x_diff = (p_x as isize - target_x as isize).abs();
         let y_diff = (p_y as isize - target_y as isize).abs();
         let moved_diagonally = x_diff == y_diff;
@@ -489,7 +1276,7 @@ impl Board {
         let x_op = if p_x > target_x { std::ops::Sub::sub } else { std::ops::Add::add };
         #[rustfmt::skip]
         let y_op = if p_y > target_y { std::ops::Sub::sub } else { std::ops::Add::add };
-        let num_squares = (p_x as isize - target_x as isize).abs() as usize;
+        let num_squares = (p_x as isize - target_x as isize).unsigned_abs();
         let range = (1..num_squares).map(|i| (x_op(p_x, i), y_op(p_y, i)));
         for (x, y) in range {
             if self.map[x][y].is_some() {
@@ -498,26 +1285,119 @@ impl Board {
         }
 
         match self.get_location((target_x, target_y)) {
-            BoardSlot::OutOfBounds => {
-                return false;
-            }
-            BoardSlot::Empty => {
-                return true;
-            }
+            BoardSlot::OutOfBounds => false,
+            BoardSlot::Empty => true,
             BoardSlot::Piece(target_piece) => {
                 if target_piece.color() == piece.color() {
                     return false;
                 }
 
-                return true;
+                true
+            }
+        }
+    }
+}
+
+impl Board {
+    /// A board with White to move and nothing played yet, holding `pieces`
+    /// wherever they say they are.
+    fn with_pieces(pieces: Vec<Piece>) -> Self {
+        let mut map = [[None; 8]; 8];
+        for (i, piece) in pieces.iter().enumerate() {
+            let pos = piece.position.unwrap();
+            map[pos.file()][pos.rank()] = NonZeroU8::new(i as u8 + 1);
+        }
+
+        Self {
+            pieces,
+            map,
+            variant: VariantKind::Standard,
+            turn: Player::White,
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+            outcome: None,
+            pockets: Pockets::default(),
+            history: vec![],
+            undo: vec![],
+        }
+    }
+
+    /// The Chess960 start position numbered `position` (0 to 959) in the
+    /// usual Scharnagl numbering, where 518 is the standard setup.
+    pub fn chess960(position: u16) -> Option<Self> {
+        if position >= 960 {
+            return None;
+        }
+        let mut n = position as usize;
+        let mut back_rank = [None; 8];
+        back_rank[n % 4 * 2 + 1] = Some(PieceType::Bishop);
+        n /= 4;
+        back_rank[n % 4 * 2] = Some(PieceType::Bishop);
+        n /= 4;
+        // the rest go on whichever squares are still empty, counting from the
+        // a file
+        let mut place = |nth: usize, piecetype| {
+            let file = (0..8).filter(|&f| back_rank[f].is_none()).nth(nth).unwrap();
+            back_rank[file] = Some(piecetype);
+        };
+        place(n % 6, PieceType::Queen);
+        n /= 6;
+        #[rustfmt::skip]
+        const KNIGHTS: [(usize, usize); 10] = [
+            (0, 1), (0, 2), (0, 3), (0, 4), (1, 2),
+            (1, 3), (1, 4), (2, 3), (2, 4), (3, 4),
+        ];
+        let (first, second) = KNIGHTS[n];
+        // the second knight's square is counted before the first is placed
+        place(second, PieceType::Knight);
+        place(first, PieceType::Knight);
+        place(0, PieceType::Rook);
+        place(0, PieceType::King);
+        place(0, PieceType::Rook);
+
+        let mut pieces = vec![];
+        for &(player, pawn_rank, back) in &[(Player::White, 1, 0), (Player::Black, 6, 7)] {
+            for file in 0..8 {
+                pieces.push(Piece {
+                    player,
+                    piecetype: PieceType::Pawn,
+                    position: Square::new(file, pawn_rank),
+                    ..Default::default()
+                });
+            }
+            for (file, piecetype) in back_rank.iter().enumerate() {
+                pieces.push(Piece {
+                    player,
+                    piecetype: piecetype.unwrap(),
+                    position: Square::new(file, back),
+                    ..Default::default()
+                });
             }
         }
+        Some(Self::with_pieces(pieces))
+    }
+
+    /// Whose move it is.
+    pub fn turn(&self) -> Player {
+        self.turn
+    }
+
+    /// Which rules the game is played by.
+    pub fn variant(&self) -> VariantKind {
+        self.variant
+    }
+
+    /// Moves since the last capture or pawn move, counted in plies.
+    pub fn halfmove_clock(&self) -> u32 {
+        self.halfmove_clock
     }
 }
 
 // TODO: make this a `const fn` when `?` in `const fn` becomes stable
-fn new_loc(x: u8, y: u8) -> Option<(NonZeroU8, NonZeroU8)> {
-    Some((NonZeroU8::new(x)?, NonZeroU8::new(y)?))
+/// The square at a 1-based `x`, `y`, the way clients count them
+fn new_loc(x: u8, y: u8) -> Option<Square> {
+    Square::new(x.checked_sub(1)? as usize, y.checked_sub(1)? as usize)
 }
 
 impl Default for Board {
@@ -558,12 +1438,98 @@ impl Default for Board {
             Piece { player: Player::Black,  piecetype: PieceType::King,   position: new_loc(5, 8), ..Default::default()},
         ];
 
-        let mut map = [[None; 8]; 8];
-        for (i, piece) in pieces.iter().enumerate() {
-            let pos = piece.position.unwrap();
-            map[pos.0.get() as usize - 1][pos.1.get() as usize - 1] = NonZeroU8::new(i as u8 + 1);
+        Self::with_pieces(pieces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(board: &mut Board, moves: &[&str]) {
+        for mv in moves {
+            let player = board.turn();
+            board.play_uci(player, mv.parse().unwrap()).unwrap();
         }
+    }
+
+    /// Play `mv` and take it back, checking the board is just as it was.
+    fn round_trip(board: &mut Board, mv: &str) -> Move {
+        let before = board.clone();
+        play(board, &[mv]);
+        let played = board.history().last().cloned().unwrap();
+        assert_eq!(board.unmake_move(), Some(played.clone()));
+        assert_eq!(board.to_variant_fen(), before.to_variant_fen());
+        assert_eq!(*board, before);
+        // and the same move can be played again
+        play(board, &[mv]);
+        assert_eq!(board.history().last(), Some(&played));
+        played
+    }
+
+    fn variant_start(variant: VariantKind) -> Board {
+        let mut board = variant.rules().start_position();
+        board.set_variant(variant);
+        board
+    }
+
+    #[test]
+    fn unmake_castling_gives_back_the_rights() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        for mv in ["e1g1", "e1c1"] {
+            let mut board = Board::from_fen(fen).unwrap();
+            assert!(round_trip(&mut board, mv).castling.is_some());
+        }
+        // having castled, white has no rights left to lose
+        let mut board = Board::from_fen(fen).unwrap();
+        play(&mut board, &["e1g1"]);
+        round_trip(&mut board, "e8c8");
+    }
+
+    #[test]
+    fn unmake_chess960_castling_onto_the_rook() {
+        // the king and rook swap squares
+        let mut board = Board::from_fen("4k3/8/8/8/8/8/8/5KR1 w G - 0 1").unwrap();
+        let mv = round_trip(&mut board, "f1g1");
+        assert_eq!(mv.castling, Some(Castling::KingSide));
+        // and on the queen side
+        let mut board = Board::from_fen("4k3/8/8/8/8/8/8/2RK4 w C - 0 1").unwrap();
+        let mv = round_trip(&mut board, "d1c1");
+        assert_eq!(mv.castling, Some(Castling::QueenSide));
+    }
+
+    #[test]
+    fn unmake_en_passant() {
+        let mut board = Board::default();
+        play(&mut board, &["e2e4", "a7a6", "e4e5", "d7d5"]);
+        assert!(round_trip(&mut board, "e5d6").en_passant);
+    }
+
+    #[test]
+    fn unmake_promotion() {
+        let fen = "1r2k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        for mv in ["a7a8q", "a7b8n"] {
+            let mut board = Board::from_fen(fen).unwrap();
+            assert!(round_trip(&mut board, mv).promotion.is_some());
+        }
+    }
+
+    #[test]
+    fn unmake_atomic_explosion() {
+        let mut board = variant_start(VariantKind::Atomic);
+        play(&mut board, &["g1f3", "e7e6", "f3e5", "a7a6"]);
+        // blows up the king along with everything else around d7
+        let mv = round_trip(&mut board, "e5d7");
+        assert_eq!(mv.captured, Some(PieceType::Pawn));
+        assert!(board.outcome().is_some());
+    }
 
-        Self { pieces, map }
+    #[test]
+    fn unmake_crazyhouse_captures_and_drops() {
+        let mut board = variant_start(VariantKind::Crazyhouse);
+        play(&mut board, &["e2e4", "d7d5"]);
+        round_trip(&mut board, "e4d5");
+        play(&mut board, &["d8d5"]);
+        assert_eq!(round_trip(&mut board, "P@e4").piece, PieceType::Pawn);
     }
 }
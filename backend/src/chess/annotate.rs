@@ -0,0 +1,291 @@
+//! Going back over a finished game: searching every position it passed
+//! through to see how much each move gave away compared to the best one, and
+//! writing the game out as PGN with the poor moves marked and the better
+//! moves given alongside them.
+
+use serde::{Deserialize, Serialize};
+
+use super::search::Score;
+use super::{Board, Move, Outcome, Player, VariantKind};
+
+/// How bad a move was, by how many centipawns it lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Judgement {
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl Judgement {
+    fn from_loss(loss: u32) -> Option<Judgement> {
+        match loss {
+            0..=49 => None,
+            50..=99 => Some(Judgement::Inaccuracy),
+            100..=299 => Some(Judgement::Mistake),
+            _ => Some(Judgement::Blunder),
+        }
+    }
+
+    /// The Numeric Annotation Glyph PGN marks the move with, the same as
+    /// `?!`, `?` and `??`.
+    fn nag(self) -> &'static str {
+        match self {
+            Judgement::Inaccuracy => "$6",
+            Judgement::Mistake => "$2",
+            Judgement::Blunder => "$4",
+        }
+    }
+}
+
+/// One move of a game, looked back on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnotatedMove {
+    pub player: Player,
+    pub san: String,
+    /// What the position was worth after the move
+    pub score: Score,
+    /// How much worse the move was than the best one
+    pub loss: u32,
+    pub judgement: Option<Judgement>,
+    /// The line the engine would have played instead, in SAN
+    pub best: Vec<String>,
+}
+
+/// A game gone back over move by move
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub moves: Vec<AnnotatedMove>,
+    /// The average centipawn loss of each player's moves
+    pub white_acpl: u32,
+    pub black_acpl: u32,
+    /// The game as PGN, with the inaccuracies, mistakes and blunders marked
+    /// and the engine's line given as a variation for each
+    pub pgn: String,
+}
+
+// a mate is as bad to miss as giving up this much, any more and one missed
+// mate would outweigh the whole rest of the game
+const MAX_LOSS: i32 = 1000;
+// PGN lines are kept under 80 characters
+const LINE_LENGTH: usize = 79;
+
+fn variant_name(variant: VariantKind) -> Option<&'static str> {
+    match variant {
+        VariantKind::Standard => None,
+        VariantKind::KingOfTheHill => Some("King of the Hill"),
+        VariantKind::ThreeCheck => Some("Three-check"),
+        VariantKind::Antichess => Some("Antichess"),
+        VariantKind::Horde => Some("Horde"),
+        VariantKind::Crazyhouse => Some("Crazyhouse"),
+        VariantKind::Atomic => Some("Atomic"),
+        VariantKind::Bughouse => Some("Bughouse"),
+    }
+}
+
+fn result(outcome: Option<Outcome>) -> &'static str {
+    match outcome {
+        Some(Outcome::Win {
+            winner: Player::White,
+            ..
+        }) => "1-0",
+        Some(Outcome::Win {
+            winner: Player::Black,
+            ..
+        }) => "0-1",
+        Some(Outcome::Draw { .. }) => "1/2-1/2",
+        None => "*",
+    }
+}
+
+fn same_move(a: &Move, b: &Move) -> bool {
+    a.from == b.from && a.to == b.to && a.promotion == b.promotion && a.dropped == b.dropped
+}
+
+/// Add the tokens for a line of moves in SAN, played from a position with
+/// `player` to move on move `number`. `numbered` says the first move needs its
+/// number even if it's Black's, like after a variation.
+fn push_line(
+    tokens: &mut Vec<String>,
+    line: &[String],
+    mut number: u32,
+    mut player: Player,
+    mut numbered: bool,
+) {
+    for san in line {
+        match player {
+            Player::White => tokens.push(format!("{}.", number)),
+            Player::Black if numbered => tokens.push(format!("{}...", number)),
+            Player::Black => {}
+        }
+        tokens.push(san.clone());
+        numbered = false;
+        if player == Player::Black {
+            number += 1;
+        }
+        player = !player;
+    }
+}
+
+/// Join tokens into lines no longer than `LINE_LENGTH`.
+fn wrap(tokens: &[String]) -> String {
+    let mut text = String::new();
+    let mut line_length = 0;
+    for token in tokens {
+        if line_length > 0 && line_length + 1 + token.len() > LINE_LENGTH {
+            text.push('\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            text.push(' ');
+            line_length += 1;
+        }
+        text.push_str(token);
+        line_length += token.len();
+    }
+    text
+}
+
+impl Board {
+    /// Every position the game that led to this one passed through, as far
+    /// back as the moves can be taken back, with the moves played from them.
+    fn positions(&self) -> (Vec<Board>, &[Move]) {
+        let mut positions = vec![self.clone()];
+        loop {
+            let mut before = positions.last().unwrap().clone();
+            if before.unmake_move().is_none() {
+                break;
+            }
+            positions.push(before);
+        }
+        positions.reverse();
+        let played = &self.history[self.history.len() - (positions.len() - 1)..];
+        (positions, played)
+    }
+
+    /// Go back over the game that led to this position, searching every
+    /// position `depth` plies deep.
+    pub fn annotate(&self, depth: u32) -> Annotation {
+        let (positions, played) = self.positions();
+
+        let mut moves = vec![];
+        for (idx, mv) in played.iter().enumerate() {
+            let (best_score, best_line) = positions[idx].best_line(depth);
+            // the move played is searched as deep as the best one was, so the
+            // two scores compare fairly; the score after it is for the other
+            // player
+            let (next_score, _) = positions[idx + 1].best_line(depth.max(1) - 1);
+            let played_score = -next_score;
+            let loss = if best_line.first().is_some_and(|best| same_move(best, mv)) {
+                0
+            } else {
+                let capped = |score: i32| score.clamp(-MAX_LOSS, MAX_LOSS);
+                (capped(best_score) - capped(played_score)).max(0) as u32
+            };
+            moves.push(AnnotatedMove {
+                player: mv.player,
+                san: mv.san.clone(),
+                score: positions[idx + 1].score(next_score),
+                loss,
+                judgement: Judgement::from_loss(loss),
+                best: positions[idx].line_san(&best_line),
+            });
+        }
+
+        let acpl = |player| {
+            let losses: Vec<u32> = moves
+                .iter()
+                .filter(|mv| mv.player == player)
+                .map(|mv| mv.loss)
+                .collect();
+            if losses.is_empty() {
+                0
+            } else {
+                losses.iter().sum::<u32>() / losses.len() as u32
+            }
+        };
+        Annotation {
+            white_acpl: acpl(Player::White),
+            black_acpl: acpl(Player::Black),
+            pgn: self.pgn(&positions[0], ["?", "?"], &moves),
+            moves,
+        }
+    }
+
+    /// Write out the game that led to this position as PGN, as it was
+    /// played, between the players named `white` and `black`.
+    pub fn to_pgn(&self, white: &str, black: &str) -> String {
+        let (positions, played) = self.positions();
+        let moves: Vec<AnnotatedMove> = played
+            .iter()
+            .map(|mv| AnnotatedMove {
+                player: mv.player,
+                san: mv.san.clone(),
+                score: Score::Centipawns(0),
+                loss: 0,
+                judgement: None,
+                best: vec![],
+            })
+            .collect();
+        self.pgn(&positions[0], [white, black], &moves)
+    }
+
+    /// Write out the game from `start` between `players`, White first, as
+    /// PGN.
+    fn pgn(&self, start: &Board, players: [&str; 2], moves: &[AnnotatedMove]) -> String {
+        let result = result(self.outcome);
+        let mut tags = vec![
+            ("Event", "?".to_string()),
+            ("Site", "?".to_string()),
+            ("Date", "????.??.??".to_string()),
+            ("Round", "?".to_string()),
+            ("White", players[0].to_string()),
+            ("Black", players[1].to_string()),
+            ("Result", result.to_string()),
+        ];
+        if let Some(name) = variant_name(self.variant) {
+            tags.push(("Variant", name.to_string()));
+        }
+        let start_fen = start.to_fen();
+        if start_fen != start.variant.rules().start_position().to_fen() {
+            tags.push(("SetUp", "1".to_string()));
+            tags.push(("FEN", start_fen));
+        }
+
+        let mut tokens = vec![];
+        let mut number = start.fullmove_number;
+        let mut numbered = true;
+        for mv in moves {
+            push_line(
+                &mut tokens,
+                std::slice::from_ref(&mv.san),
+                number,
+                mv.player,
+                numbered,
+            );
+            numbered = false;
+            if let Some(judgement) = mv.judgement {
+                tokens.push(judgement.nag().to_string());
+                if !mv.best.is_empty() {
+                    let mut variation = vec![];
+                    push_line(&mut variation, &mv.best, number, mv.player, true);
+                    variation[0].insert(0, '(');
+                    variation.last_mut().unwrap().push(')');
+                    tokens.extend(variation);
+                    numbered = true;
+                }
+            }
+            if mv.player == Player::Black {
+                number += 1;
+            }
+        }
+        tokens.push(result.to_string());
+
+        let mut pgn = String::new();
+        for (name, value) in tags {
+            pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('"', "\\\"")));
+        }
+        pgn.push('\n');
+        pgn.push_str(&wrap(&tokens));
+        pgn.push('\n');
+        pgn
+    }
+}
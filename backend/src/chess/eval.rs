@@ -0,0 +1,334 @@
+//! A static evaluation of a position: what each side has, where it stands and
+//! how well it's placed, without looking at any moves ahead. The values and
+//! piece-square tables are Tomasz Michniewski's "Simplified Evaluation
+//! Function", which is simple enough to explain one term at a time.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Board, BoardSlot, Piece, PieceType, Player};
+
+/// What a position is worth in centipawns, positive when White is better, and
+/// the terms that add up to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Evaluation {
+    pub score: i32,
+    /// Pieces on the board and in pockets
+    pub material: i32,
+    /// Squares the pieces can move to
+    pub mobility: i32,
+    /// Pawns sheltering the king and enemy pieces bearing down on it, which
+    /// matter less as pieces come off
+    pub king_safety: i32,
+    pub pawn_structure: PawnStructure,
+    /// Bonuses for pieces on good squares, like knights in the centre
+    pub piece_square: i32,
+}
+
+/// The pawn structure terms of an [`Evaluation`], each in centipawns with
+/// White's pawns counting for and Black's against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PawnStructure {
+    /// Pawns stacked on a file behind another of their own
+    pub doubled: i32,
+    /// Pawns with none of their own on the files either side
+    pub isolated: i32,
+    /// Pawns with no enemy pawns in front of them or on the files either side
+    pub passed: i32,
+}
+
+impl PawnStructure {
+    fn total(&self) -> i32 {
+        self.doubled + self.isolated + self.passed
+    }
+}
+
+fn piece_value(piecetype: PieceType) -> i32 {
+    match piecetype {
+        PieceType::Pawn => 100,
+        PieceType::Knight => 320,
+        PieceType::Bishop => 330,
+        PieceType::Rook => 500,
+        PieceType::Queen => 900,
+        // kings can't be traded, so they don't count towards material
+        PieceType::King => 0,
+    }
+}
+
+/// Centipawns for each square a piece can move to
+fn mobility_value(piecetype: PieceType) -> i32 {
+    match piecetype {
+        PieceType::Knight | PieceType::Bishop => 4,
+        PieceType::Rook => 2,
+        PieceType::Queen => 1,
+        PieceType::Pawn | PieceType::King => 0,
+    }
+}
+
+/// How much each piece counts towards the game still being in the middlegame
+fn phase_value(piecetype: PieceType) -> i32 {
+    match piecetype {
+        PieceType::Knight | PieceType::Bishop => 1,
+        PieceType::Rook => 2,
+        PieceType::Queen => 4,
+        PieceType::Pawn | PieceType::King => 0,
+    }
+}
+
+/// The phase with every piece on the board
+const MIDDLEGAME: i32 = 24;
+
+const DOUBLED_PAWN: i32 = -20;
+const ISOLATED_PAWN: i32 = -15;
+// by how far the pawn has come, from its own back rank
+const PASSED_PAWN: [i32; 8] = [0, 10, 15, 25, 40, 60, 90, 0];
+
+// for each friendly pawn one and two squares in front of the king
+const PAWN_SHIELD: [i32; 2] = [10, 5];
+// for each square next to the king the other side attacks
+const KING_ZONE_ATTACKED: i32 = -8;
+
+// Piece-square tables from White's side of the board, so the first row is the
+// eighth rank
+#[rustfmt::skip]
+const PAWN_TABLE: [[i32; 8]; 8] = [
+    [  0,  0,  0,  0,  0,  0,  0,  0],
+    [ 50, 50, 50, 50, 50, 50, 50, 50],
+    [ 10, 10, 20, 30, 30, 20, 10, 10],
+    [  5,  5, 10, 25, 25, 10,  5,  5],
+    [  0,  0,  0, 20, 20,  0,  0,  0],
+    [  5, -5,-10,  0,  0,-10, -5,  5],
+    [  5, 10, 10,-20,-20, 10, 10,  5],
+    [  0,  0,  0,  0,  0,  0,  0,  0],
+];
+
+#[rustfmt::skip]
+const KNIGHT_TABLE: [[i32; 8]; 8] = [
+    [-50,-40,-30,-30,-30,-30,-40,-50],
+    [-40,-20,  0,  0,  0,  0,-20,-40],
+    [-30,  0, 10, 15, 15, 10,  0,-30],
+    [-30,  5, 15, 20, 20, 15,  5,-30],
+    [-30,  0, 15, 20, 20, 15,  0,-30],
+    [-30,  5, 10, 15, 15, 10,  5,-30],
+    [-40,-20,  0,  5,  5,  0,-20,-40],
+    [-50,-40,-30,-30,-30,-30,-40,-50],
+];
+
+#[rustfmt::skip]
+const BISHOP_TABLE: [[i32; 8]; 8] = [
+    [-20,-10,-10,-10,-10,-10,-10,-20],
+    [-10,  0,  0,  0,  0,  0,  0,-10],
+    [-10,  0,  5, 10, 10,  5,  0,-10],
+    [-10,  5,  5, 10, 10,  5,  5,-10],
+    [-10,  0, 10, 10, 10, 10,  0,-10],
+    [-10, 10, 10, 10, 10, 10, 10,-10],
+    [-10,  5,  0,  0,  0,  0,  5,-10],
+    [-20,-10,-10,-10,-10,-10,-10,-20],
+];
+
+#[rustfmt::skip]
+const ROOK_TABLE: [[i32; 8]; 8] = [
+    [  0,  0,  0,  0,  0,  0,  0,  0],
+    [  5, 10, 10, 10, 10, 10, 10,  5],
+    [ -5,  0,  0,  0,  0,  0,  0, -5],
+    [ -5,  0,  0,  0,  0,  0,  0, -5],
+    [ -5,  0,  0,  0,  0,  0,  0, -5],
+    [ -5,  0,  0,  0,  0,  0,  0, -5],
+    [ -5,  0,  0,  0,  0,  0,  0, -5],
+    [  0,  0,  0,  5,  5,  0,  0,  0],
+];
+
+#[rustfmt::skip]
+const QUEEN_TABLE: [[i32; 8]; 8] = [
+    [-20,-10,-10, -5, -5,-10,-10,-20],
+    [-10,  0,  0,  0,  0,  0,  0,-10],
+    [-10,  0,  5,  5,  5,  5,  0,-10],
+    [ -5,  0,  5,  5,  5,  5,  0, -5],
+    [  0,  0,  5,  5,  5,  5,  0, -5],
+    [-10,  5,  5,  5,  5,  5,  0,-10],
+    [-10,  0,  5,  0,  0,  0,  0,-10],
+    [-20,-10,-10, -5, -5,-10,-10,-20],
+];
+
+// the king hides in the corner while there are pieces about...
+#[rustfmt::skip]
+const KING_MIDDLEGAME_TABLE: [[i32; 8]; 8] = [
+    [-30,-40,-40,-50,-50,-40,-40,-30],
+    [-30,-40,-40,-50,-50,-40,-40,-30],
+    [-30,-40,-40,-50,-50,-40,-40,-30],
+    [-30,-40,-40,-50,-50,-40,-40,-30],
+    [-20,-30,-30,-40,-40,-30,-30,-20],
+    [-10,-20,-20,-20,-20,-20,-20,-10],
+    [ 20, 20,  0,  0,  0,  0, 20, 20],
+    [ 20, 30, 10,  0,  0, 10, 30, 20],
+];
+
+// ...and heads for the centre once they're gone
+#[rustfmt::skip]
+const KING_ENDGAME_TABLE: [[i32; 8]; 8] = [
+    [-50,-40,-30,-20,-20,-30,-40,-50],
+    [-30,-20,-10,  0,  0,-10,-20,-30],
+    [-30,-10, 20, 30, 30, 20,-10,-30],
+    [-30,-10, 30, 40, 40, 30,-10,-30],
+    [-30,-10, 30, 40, 40, 30,-10,-30],
+    [-30,-10, 20, 30, 30, 20,-10,-30],
+    [-30,-30,  0,  0,  0,  0,-30,-30],
+    [-50,-30,-30,-30,-30,-30,-30,-50],
+];
+
+/// Look up a square in a table written from White's side of the board.
+fn table_value(table: &[[i32; 8]; 8], player: Player, (x, y): (usize, usize)) -> i32 {
+    match player {
+        Player::White => table[7 - y][x],
+        Player::Black => table[y][x],
+    }
+}
+
+/// How many ranks `player`'s pawn on rank `y` has come from its own back rank
+fn relative_rank(player: Player, y: usize) -> usize {
+    match player {
+        Player::White => y,
+        Player::Black => 7 - y,
+    }
+}
+
+impl Board {
+    /// Evaluate the position as it stands.
+    pub fn evaluate(&self) -> Evaluation {
+        let phase = self
+            .pieces
+            .iter()
+            .filter(|p| p.alive)
+            .map(|p| phase_value(p.piecetype))
+            .sum::<i32>()
+            .min(MIDDLEGAME);
+
+        let mut eval = Evaluation::default();
+        for &player in [Player::White, Player::Black].iter() {
+            let sign = match player {
+                Player::White => 1,
+                Player::Black => -1,
+            };
+            let pockets = self.pockets.get(player);
+            for piece in self.iter_pieces(player) {
+                eval.material += sign * piece_value(piece.piecetype);
+                eval.mobility += sign * self.mobility(piece);
+                eval.piece_square += sign * self.piece_square(piece, phase);
+            }
+            for &piecetype in pockets.kinds().iter() {
+                eval.material += sign * piece_value(piecetype) * pockets.count(piecetype) as i32;
+            }
+            eval.king_safety += sign * self.king_safety(player) * phase / MIDDLEGAME;
+            let pawns = self.pawn_structure(player);
+            eval.pawn_structure.doubled += sign * pawns.doubled;
+            eval.pawn_structure.isolated += sign * pawns.isolated;
+            eval.pawn_structure.passed += sign * pawns.passed;
+        }
+        eval.score = eval.material
+            + eval.mobility
+            + eval.king_safety
+            + eval.pawn_structure.total()
+            + eval.piece_square;
+        eval
+    }
+
+    fn mobility(&self, piece: &Piece) -> i32 {
+        let value = mobility_value(piece.piecetype);
+        if value == 0 {
+            return 0;
+        }
+        let squares = (0..8)
+            .flat_map(|x| (0..8).map(move |y| (x, y)))
+            .filter(|&pos| match self.get_location(pos) {
+                BoardSlot::Piece(target) => target.color() != piece.color(),
+                _ => true,
+            })
+            .filter(|&pos| self.piece_can_attack(piece, pos))
+            .count();
+        value * squares as i32
+    }
+
+    fn piece_square(&self, piece: &Piece, phase: i32) -> i32 {
+        let pos = piece.position.unwrap().coords();
+        let player = piece.color();
+        match piece.piecetype {
+            PieceType::Pawn => table_value(&PAWN_TABLE, player, pos),
+            PieceType::Knight => table_value(&KNIGHT_TABLE, player, pos),
+            PieceType::Bishop => table_value(&BISHOP_TABLE, player, pos),
+            PieceType::Rook => table_value(&ROOK_TABLE, player, pos),
+            PieceType::Queen => table_value(&QUEEN_TABLE, player, pos),
+            // somewhere between the two, depending on what's left
+            PieceType::King => {
+                let middlegame = table_value(&KING_MIDDLEGAME_TABLE, player, pos);
+                let endgame = table_value(&KING_ENDGAME_TABLE, player, pos);
+                (middlegame * phase + endgame * (MIDDLEGAME - phase)) / MIDDLEGAME
+            }
+        }
+    }
+
+    /// `player`'s king safety before it's scaled down for the phase of the game
+    fn king_safety(&self, player: Player) -> i32 {
+        let king = match self.get_king(player) {
+            Some(king) => king,
+            None => return 0,
+        };
+        let (k_x, k_y) = king.position.unwrap().coords();
+        let forward = match player {
+            Player::White => 1,
+            Player::Black => -1,
+        };
+        let mut safety = 0;
+        for x in k_x.saturating_sub(1)..=(k_x + 1).min(7) {
+            for (distance, bonus) in PAWN_SHIELD.iter().enumerate() {
+                let y = k_y as isize + forward * (distance as isize + 1);
+                if !(0..8).contains(&y) {
+                    continue;
+                }
+                if let BoardSlot::Piece(p) = self.get_location((x, y as usize)) {
+                    if p.piecetype == PieceType::Pawn && p.color() == player {
+                        safety += bonus;
+                    }
+                }
+            }
+            for y in k_y.saturating_sub(1)..=(k_y + 1).min(7) {
+                if (x, y) != (k_x, k_y) && self.is_attacked((x, y), !player) {
+                    safety += KING_ZONE_ATTACKED;
+                }
+            }
+        }
+        safety
+    }
+
+    /// `player`'s pawn structure, counted for them
+    fn pawn_structure(&self, player: Player) -> PawnStructure {
+        let pawns = |player| -> Vec<(usize, usize)> {
+            self.iter_pieces(player)
+                .filter(|p| p.piecetype == PieceType::Pawn)
+                .map(|p| p.position.unwrap().coords())
+                .collect()
+        };
+        let ours = pawns(player);
+        let theirs = pawns(!player);
+        let near = |a: usize, b: usize| (a as isize - b as isize).abs() <= 1;
+
+        let mut structure = PawnStructure::default();
+        for &(x, y) in ours.iter() {
+            // the one furthest back on a file is the doubled one
+            let behind = ours.iter().any(|&(o_x, o_y)| {
+                o_x == x && relative_rank(player, o_y) > relative_rank(player, y)
+            });
+            if behind {
+                structure.doubled += DOUBLED_PAWN;
+            }
+            if !ours.iter().any(|&(o_x, _)| o_x != x && near(o_x, x)) {
+                structure.isolated += ISOLATED_PAWN;
+            }
+            let blocked = theirs.iter().any(|&(t_x, t_y)| {
+                near(t_x, x) && relative_rank(player, t_y) > relative_rank(player, y)
+            });
+            if !blocked {
+                structure.passed += PASSED_PAWN[relative_rank(player, y)];
+            }
+        }
+        structure
+    }
+}
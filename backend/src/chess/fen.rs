@@ -0,0 +1,466 @@
+//! Reading and writing positions as FEN. Castling rights are read in any of
+//! the usual spellings, `KQkq`, X-FEN's file letters for a rook that isn't the
+//! outermost one, or Shredder-FEN's file letters for every rook, so Chess960
+//! positions round-trip. Pieces in hand go after the placement in brackets, for
+//! programs that play variants with drops, and are read back the same way.
+
+use std::fmt;
+
+use super::{Board, Piece, PieceType, Player, Square, VariantKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FenError(String);
+
+impl fmt::Display for FenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid FEN: {}", self.0)
+    }
+}
+
+fn err<T>(msg: impl Into<String>) -> Result<T, FenError> {
+    Err(FenError(msg.into()))
+}
+
+fn back_rank(player: Player) -> usize {
+    match player {
+        Player::White => 0,
+        Player::Black => 7,
+    }
+}
+
+impl PieceType {
+    /// The piece for a letter in FEN or algebraic notation, in either case
+    pub fn from_letter(letter: char) -> Option<Self> {
+        match letter.to_ascii_uppercase() {
+            'P' => Some(PieceType::Pawn),
+            'R' => Some(PieceType::Rook),
+            'N' => Some(PieceType::Knight),
+            'B' => Some(PieceType::Bishop),
+            'Q' => Some(PieceType::Queen),
+            'K' => Some(PieceType::King),
+            _ => None,
+        }
+    }
+}
+
+impl Board {
+    /// Set up a position from FEN, X-FEN, or Shredder-FEN. The move counters
+    /// can be left off, in which case the game starts from move 1. Pieces in
+    /// hand can follow the placement in brackets, as `to_variant_fen` writes
+    /// them, which makes it a Crazyhouse position.
+    pub fn from_fen(fen: &str) -> Result<Self, FenError> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() < 4 || fields.len() > 6 {
+            return err(format!("expected 4 to 6 fields but found {}", fields.len()));
+        }
+
+        let (placement, pockets) = match fields[0].split_once('[') {
+            Some((placement, pockets)) => match pockets.strip_suffix(']') {
+                Some(pockets) => (placement, Some(pockets)),
+                None => return err("the pieces in hand aren't closed with ']'"),
+            },
+            None => (fields[0], None),
+        };
+        let ranks: Vec<&str> = placement.split('/').collect();
+        if ranks.len() != 8 {
+            return err(format!("expected 8 ranks but found {}", ranks.len()));
+        }
+        let mut pieces = vec![];
+        for (i, row) in ranks.iter().enumerate() {
+            let rank = 7 - i;
+            let mut file = 0;
+            for c in row.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    file += empty as usize;
+                    continue;
+                }
+                let piecetype = match PieceType::from_letter(c) {
+                    Some(piecetype) => piecetype,
+                    None => return err(format!("{:?} isn't a piece", c)),
+                };
+                let position = match Square::new(file, rank) {
+                    Some(position) => position,
+                    None => return err(format!("rank {} is too long", rank + 1)),
+                };
+                let player = if c.is_ascii_uppercase() {
+                    Player::White
+                } else {
+                    Player::Black
+                };
+                let (pawn_rank, last_rank) = match player {
+                    Player::White => (1, 7),
+                    Player::Black => (6, 0),
+                };
+                if piecetype == PieceType::Pawn && rank == last_rank {
+                    return err(format!("there's a pawn on {}", position));
+                }
+                // pawns that have left their starting rank can't double step,
+                // though Horde has pawns behind it that still can; everything
+                // else is sorted out from the castling rights below
+                let moved = piecetype == PieceType::Pawn
+                    && match player {
+                        Player::White => rank > pawn_rank,
+                        Player::Black => rank < pawn_rank,
+                    };
+                pieces.push(Piece {
+                    player,
+                    piecetype,
+                    position: Some(position),
+                    moved,
+                    ..Default::default()
+                });
+                file += 1;
+            }
+            if file != 8 {
+                return err(format!("rank {} doesn't have 8 squares", rank + 1));
+            }
+        }
+
+        let mut board = Board::with_pieces(pieces);
+        if let Some(pockets) = pockets {
+            board.set_variant(VariantKind::Crazyhouse);
+            for c in pockets.chars() {
+                let piecetype = match PieceType::from_letter(c) {
+                    Some(piecetype) if piecetype != PieceType::King => piecetype,
+                    _ => return err(format!("{:?} can't be held in hand", c)),
+                };
+                let player = if c.is_ascii_uppercase() {
+                    Player::White
+                } else {
+                    Player::Black
+                };
+                board.pockets.get_mut(player).add(piecetype);
+            }
+        }
+        board.turn = match fields[1] {
+            "w" => Player::White,
+            "b" => Player::Black,
+            other => return err(format!("{:?} isn't a side to move", other)),
+        };
+        board.read_castling(fields[2])?;
+        board.en_passant = match fields[3] {
+            "-" => None,
+            square => {
+                let square: Square = match square.parse() {
+                    Ok(square) => square,
+                    Err(e) => return err(e.to_string()),
+                };
+                let rank = match board.turn {
+                    Player::White => 5,
+                    Player::Black => 2,
+                };
+                if square.rank() != rank {
+                    return err(format!("{} can't be an en passant square", square));
+                }
+                Some(square)
+            }
+        };
+        if let Some(clock) = fields.get(4) {
+            board.halfmove_clock = match clock.parse() {
+                Ok(clock) => clock,
+                Err(_) => return err(format!("{:?} isn't a halfmove clock", clock)),
+            };
+        }
+        if let Some(number) = fields.get(5) {
+            board.fullmove_number = match number.parse() {
+                Ok(number) if number > 0 => number,
+                _ => return err(format!("{:?} isn't a move number", number)),
+            };
+        }
+        Ok(board)
+    }
+
+    /// Mark every king and rook as moved unless `castling` says it can still
+    /// castle.
+    fn read_castling(&mut self, castling: &str) -> Result<(), FenError> {
+        for piece in self.pieces.iter_mut() {
+            if piece.piecetype == PieceType::King || piece.piecetype == PieceType::Rook {
+                piece.moved = true;
+            }
+        }
+        if castling == "-" {
+            return Ok(());
+        }
+        for c in castling.chars() {
+            let player = if c.is_ascii_uppercase() {
+                Player::White
+            } else {
+                Player::Black
+            };
+            let rank = back_rank(player);
+            let king = match self.get_king(player).and_then(|king| king.position) {
+                Some(king) if king.rank() == rank => king.file(),
+                _ => {
+                    return err(format!(
+                        "{:?} can't castle without a king on its back rank",
+                        c
+                    ))
+                }
+            };
+            let mut rooks = self
+                .iter_pieces(player)
+                .filter(|p| p.piecetype == PieceType::Rook)
+                .map(|p| p.position.unwrap())
+                .filter(|sq| sq.rank() == rank)
+                .map(|sq| sq.file());
+            let rook = match c.to_ascii_lowercase() {
+                'k' => rooks.filter(|&f| f > king).max(),
+                'q' => rooks.filter(|&f| f < king).min(),
+                file @ 'a'..='h' => {
+                    let file = file as usize - 'a' as usize;
+                    rooks.find(|&f| f == file && f != king)
+                }
+                _ => return err(format!("{:?} isn't a castling right", c)),
+            };
+            let rook = match rook {
+                Some(rook) => rook,
+                None => return err(format!("there's no rook to castle with for {:?}", c)),
+            };
+            for square in &[(king, rank), (rook, rank)] {
+                let idx = self.map[square.0][square.1].unwrap().get() as usize - 1;
+                self.pieces[idx].moved = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// The position as X-FEN, which is plain FEN unless Chess960 castling
+    /// rights need a rook's file to tell them apart.
+    pub fn to_fen(&self) -> String {
+        self.write_fen(false, false)
+    }
+
+    /// The position as Shredder-FEN, where castling rights are always given
+    /// by the rook's file.
+    pub fn to_shredder_fen(&self) -> String {
+        self.write_fen(true, false)
+    }
+
+    /// The position as FEN for a program that has nothing else to go on:
+    /// Shredder-FEN if a castling right is for a king or rook that isn't
+    /// where it starts in standard chess, and in variants with drops, with
+    /// the pieces in hand in brackets after the placement.
+    pub fn to_variant_fen(&self) -> String {
+        let shredder = [Player::White, Player::Black].iter().any(|&player| {
+            let king = self.get_king(player).and_then(|king| king.position);
+            let letters = self.castling_letters(player, true);
+            !letters.is_empty()
+                && (king.is_none_or(|king| king.file() != 4)
+                    || letters
+                        .iter()
+                        .any(|letter| !matches!(letter.to_ascii_lowercase(), 'a' | 'h')))
+        });
+        self.write_fen(shredder, self.variant.rules().has_drops())
+    }
+
+    fn write_fen(&self, shredder: bool, pockets: bool) -> String {
+        let mut placement = String::new();
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                match self.map[file][rank] {
+                    Some(idx) => {
+                        if empty > 0 {
+                            placement.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        let piece = &self.pieces[idx.get() as usize - 1];
+                        placement.push(match piece.player {
+                            Player::White => piece.piecetype.letter(),
+                            Player::Black => piece.piecetype.letter().to_ascii_lowercase(),
+                        });
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                placement.push_str(&empty.to_string());
+            }
+            if rank > 0 {
+                placement.push('/');
+            }
+        }
+        if pockets {
+            placement.push('[');
+            for &player in &[Player::White, Player::Black] {
+                let pocket = self.pockets.get(player);
+                for &piecetype in &[
+                    PieceType::Queen,
+                    PieceType::Rook,
+                    PieceType::Bishop,
+                    PieceType::Knight,
+                    PieceType::Pawn,
+                ] {
+                    let letter = match player {
+                        Player::White => piecetype.letter(),
+                        Player::Black => piecetype.letter().to_ascii_lowercase(),
+                    };
+                    for _ in 0..pocket.count(piecetype) {
+                        placement.push(letter);
+                    }
+                }
+            }
+            placement.push(']');
+        }
+
+        let mut castling = String::new();
+        for &player in &[Player::White, Player::Black] {
+            castling.extend(self.castling_letters(player, shredder));
+        }
+        if castling.is_empty() {
+            castling.push('-');
+        }
+
+        format!(
+            "{} {} {} {} {} {}",
+            placement,
+            match self.turn {
+                Player::White => 'w',
+                Player::Black => 'b',
+            },
+            castling,
+            self.en_passant
+                .map_or_else(|| "-".to_string(), |sq| sq.to_string()),
+            self.halfmove_clock,
+            self.fullmove_number
+        )
+    }
+
+    /// The castling rights `player` still has, king side first.
+    pub(super) fn castling_letters(&self, player: Player, shredder: bool) -> Vec<char> {
+        let rank = back_rank(player);
+        let king = match self.get_king(player) {
+            Some(king) if !king.moved && king.position.unwrap().rank() == rank => {
+                king.position.unwrap().file()
+            }
+            _ => return vec![],
+        };
+        let rooks: Vec<(usize, bool)> = self
+            .iter_pieces(player)
+            .filter(|p| p.piecetype == PieceType::Rook)
+            .map(|p| (p.position.unwrap(), p.moved))
+            .filter(|(sq, _)| sq.rank() == rank)
+            .map(|(sq, moved)| (sq.file(), moved))
+            .collect();
+        let mut letters = vec![];
+        for &king_side in &[true, false] {
+            let on_side = |f: usize| if king_side { f > king } else { f < king };
+            // the one castling would use, the outermost if there are two
+            let rook = rooks
+                .iter()
+                .filter(|(f, moved)| on_side(*f) && !moved)
+                .map(|(f, _)| *f)
+                .max_by_key(|&f| if king_side { f } else { 7 - f });
+            let rook = match rook {
+                Some(rook) => rook,
+                None => continue,
+            };
+            // X-FEN only names the file when another rook is further out
+            let outermost = !rooks
+                .iter()
+                .any(|(f, _)| on_side(*f) && if king_side { *f > rook } else { *f < rook });
+            let letter = if shredder || !outermost {
+                (b'a' + rook as u8) as char
+            } else if king_side {
+                'k'
+            } else {
+                'q'
+            };
+            letters.push(match player {
+                Player::White => letter.to_ascii_uppercase(),
+                Player::Black => letter,
+            });
+        }
+        letters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    #[test]
+    fn chess960_518_is_the_standard_start() {
+        assert_eq!(Board::chess960(518).unwrap().to_fen(), START);
+    }
+
+    #[test]
+    fn fen_round_trips() {
+        for fen in &[
+            START,
+            "rnbqkbnr/pp1ppppp/8/2p5/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2",
+            "r3k2r/8/8/3pP3/8/8/8/R3K2R w Kq d6 0 30",
+            "8/8/4k3/8/8/3K4/8/8 b - - 12 60",
+        ] {
+            assert_eq!(Board::from_fen(fen).unwrap().to_fen(), *fen);
+        }
+    }
+
+    #[test]
+    fn chess960_castling_round_trips() {
+        for position in [0, 100, 517, 959].iter().copied() {
+            let board = Board::chess960(position).unwrap();
+            let xfen = board.to_fen();
+            let shredder = board.to_shredder_fen();
+            assert_eq!(Board::from_fen(&xfen).unwrap().to_fen(), xfen);
+            assert_eq!(Board::from_fen(&shredder).unwrap().to_fen(), xfen);
+            assert_eq!(Board::from_fen(&xfen).unwrap().to_shredder_fen(), shredder);
+        }
+    }
+
+    #[test]
+    fn castling_names_the_outermost_rook() {
+        // both king side rooks can castle, and castling takes the h-file one
+        let board = Board::from_fen("4k3/8/8/8/8/8/8/4K1RR w GH - 0 1").unwrap();
+        assert_eq!(board.castling_letters(Player::White, false), vec!['K']);
+        assert_eq!(board.castling_letters(Player::White, true), vec!['H']);
+        // once that one has gone, the other is named by its file
+        let board = Board::from_fen("4k3/8/8/8/8/8/8/4K1RR w G - 0 1").unwrap();
+        assert_eq!(board.castling_letters(Player::White, false), vec!['G']);
+    }
+
+    #[test]
+    fn variant_fen_says_what_the_board_alone_cannot() {
+        assert_eq!(Board::default().to_variant_fen(), START);
+        // the rooks start on f and h, so plain KQkq would say the wrong ones
+        let board = Board::chess960(0).unwrap();
+        assert_eq!(board.to_variant_fen(), board.to_shredder_fen());
+        assert_eq!(
+            board.to_variant_fen(),
+            "bbqnnrkr/pppppppp/8/8/8/8/PPPPPPPP/BBQNNRKR w HFhf - 0 1"
+        );
+
+        let mut board = Board::default();
+        board.set_variant(VariantKind::Crazyhouse);
+        for mv in ["e2e4", "d7d5", "e4d5"].iter() {
+            let player = board.turn();
+            board.play_uci(player, mv.parse().unwrap()).unwrap();
+        }
+        assert_eq!(
+            board.to_variant_fen(),
+            "rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR[P] b KQkq - 0 2"
+        );
+    }
+
+    #[test]
+    fn pieces_in_hand_round_trip() {
+        let fen = "r1bqkb1r/ppp2ppp/2n5/3p4/8/8/PPPP1PPP/RNBQKB1R[QNPbp] w KQkq - 0 6";
+        let board = Board::from_fen(fen).unwrap();
+        assert_eq!(board.variant(), VariantKind::Crazyhouse);
+        assert_eq!(board.pockets.get(Player::White).count(PieceType::Knight), 1);
+        assert_eq!(board.pockets.get(Player::Black).count(PieceType::Pawn), 1);
+        assert_eq!(board.to_variant_fen(), fen);
+        // empty pockets are still a variant with drops
+        let fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1";
+        assert_eq!(Board::from_fen(fen).unwrap().to_variant_fen(), fen);
+
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[K] w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[Q w KQkq - 0 1",
+        ] {
+            assert!(Board::from_fen(fen).is_err());
+        }
+    }
+}
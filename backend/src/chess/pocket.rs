@@ -0,0 +1,273 @@
+//! Pieces held in hand for variants with drops, like Crazyhouse, where a
+//! captured piece changes sides and can be put back on any empty square.
+
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU8;
+
+use super::{Board, Move, MovePieceError, Piece, PieceType, Player, Square, Undo};
+
+/// The pieces one player has in hand, by kind
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pocket {
+    pawn: u8,
+    knight: u8,
+    bishop: u8,
+    rook: u8,
+    queen: u8,
+}
+
+impl Pocket {
+    fn count_mut(&mut self, piecetype: PieceType) -> Option<&mut u8> {
+        match piecetype {
+            PieceType::Pawn => Some(&mut self.pawn),
+            PieceType::Knight => Some(&mut self.knight),
+            PieceType::Bishop => Some(&mut self.bishop),
+            PieceType::Rook => Some(&mut self.rook),
+            PieceType::Queen => Some(&mut self.queen),
+            // kings are never captured in variants with drops
+            PieceType::King => None,
+        }
+    }
+
+    pub fn add(&mut self, piecetype: PieceType) {
+        if let Some(count) = self.count_mut(piecetype) {
+            *count += 1;
+        }
+    }
+
+    /// Take a piece out of the pocket, if there's one of that kind in it.
+    pub(super) fn take(&mut self, piecetype: PieceType) -> bool {
+        match self.count_mut(piecetype) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How many pieces of a kind there are.
+    pub(super) fn count(&self, piecetype: PieceType) -> u8 {
+        match piecetype {
+            PieceType::Pawn => self.pawn,
+            PieceType::Knight => self.knight,
+            PieceType::Bishop => self.bishop,
+            PieceType::Rook => self.rook,
+            PieceType::Queen => self.queen,
+            PieceType::King => 0,
+        }
+    }
+
+    /// Every kind of piece there's at least one of.
+    pub(super) fn kinds(&self) -> Vec<PieceType> {
+        [
+            (PieceType::Pawn, self.pawn),
+            (PieceType::Knight, self.knight),
+            (PieceType::Bishop, self.bishop),
+            (PieceType::Rook, self.rook),
+            (PieceType::Queen, self.queen),
+        ]
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(piecetype, _)| *piecetype)
+        .collect()
+    }
+}
+
+/// Both players' pockets
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pockets {
+    white: Pocket,
+    black: Pocket,
+}
+
+impl Pockets {
+    pub fn get(&self, player: Player) -> &Pocket {
+        match player {
+            Player::White => &self.white,
+            Player::Black => &self.black,
+        }
+    }
+
+    pub fn get_mut(&mut self, player: Player) -> &mut Pocket {
+        match player {
+            Player::White => &mut self.white,
+            Player::Black => &mut self.black,
+        }
+    }
+}
+
+impl Board {
+    /// Put a piece into `player`'s pocket, like one their partner captured
+    /// in Bughouse.
+    pub fn give_to_pocket(&mut self, player: Player, piecetype: PieceType) {
+        self.pockets.get_mut(player).add(piecetype);
+    }
+
+    /// What the last move captured, the way it would go into a pocket, with
+    /// promoted pieces turned back into pawns.
+    pub fn last_captured(&self) -> Option<PieceType> {
+        let (idx, _) = self.undo.last()?.captured?;
+        let piece = &self.pieces[idx];
+        Some(if piece.promoted {
+            PieceType::Pawn
+        } else {
+            piece.piecetype
+        })
+    }
+
+    /// Drop a piece from `player`'s pocket onto `to`, returning a record of
+    /// the move.
+    pub fn drop_piece(
+        &mut self,
+        player: Player,
+        piecetype: PieceType,
+        to: Square,
+    ) -> Result<Move, MovePieceError> {
+        let before = self.clone();
+        let mv = self.try_drop(player, piecetype, to)?;
+        Ok(self.finish_move(before, mv))
+    }
+
+    /// Apply a drop if it's legal, without recording it in the history, the
+    /// same way `try_move` does for moves.
+    pub(super) fn try_drop(
+        &mut self,
+        player: Player,
+        piecetype: PieceType,
+        to: Square,
+    ) -> Result<Move, MovePieceError> {
+        let backup_board = self.clone();
+        let rules = self.variant.rules();
+
+        if self.outcome.is_some() {
+            return Err(MovePieceError::GameOver);
+        }
+        if player != self.turn {
+            return Err(MovePieceError::NotYourTurn);
+        }
+        if !rules.has_drops() {
+            return Err(MovePieceError::DropsNotAllowed);
+        }
+        let (t_x, t_y) = to.coords();
+        if self.map[t_x][t_y].is_some() {
+            return Err(MovePieceError::SquareOccupied);
+        }
+        if piecetype == PieceType::Pawn && (t_y == 0 || t_y == 7) {
+            return Err(MovePieceError::PawnOnBackRank);
+        }
+        if !self.pockets.get_mut(player).take(piecetype) {
+            return Err(MovePieceError::NotInPocket);
+        }
+
+        let start_rank = match player {
+            Player::White => 1,
+            Player::Black => 6,
+        };
+        let piece = Piece {
+            player,
+            piecetype,
+            position: Some(to),
+            // a pawn dropped on its starting rank can still move two squares,
+            // but a dropped rook can never castle
+            moved: !(piecetype == PieceType::Pawn && t_y == start_rank),
+            ..Default::default()
+        };
+        // reuse a captured piece's slot so the piece list doesn't grow
+        // without bound over a long game
+        let (piece_idx, replaced) = match self.pieces.iter().position(|p| !p.alive) {
+            Some(idx) => (idx, Some(std::mem::replace(&mut self.pieces[idx], piece))),
+            None => {
+                self.pieces.push(piece);
+                (self.pieces.len() - 1, None)
+            }
+        };
+        self.map[t_x][t_y] = NonZeroU8::new(piece_idx as u8 + 1);
+
+        if rules.has_check() && self.is_check(player) {
+            *self = backup_board;
+            return Err(MovePieceError::KingIsInCheck);
+        }
+        let mv = Move {
+            player,
+            from: to,
+            to,
+            piece: piecetype,
+            captured: None,
+            promotion: None,
+            castling: None,
+            en_passant: false,
+            check: false,
+            dropped: true,
+            san: String::new(),
+        };
+        if let Err(e) = rules.check_move(&backup_board, &mv) {
+            *self = backup_board;
+            return Err(e);
+        }
+
+        self.undo.push(Undo {
+            piece_idx,
+            from: to,
+            to,
+            moved: false,
+            promoted: false,
+            captured: None,
+            exploded: vec![],
+            pocketed: None,
+            castling: None,
+            en_passant: self.en_passant,
+            halfmove_clock: self.halfmove_clock,
+            dropped: Some(replaced),
+        });
+        self.en_passant = None;
+        if piecetype == PieceType::Pawn {
+            self.halfmove_clock = 0;
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if player == Player::Black {
+            self.fullmove_number += 1;
+        }
+        self.turn = !player;
+
+        Ok(mv)
+    }
+
+    /// Could `player` get out of check by dropping a piece, if they had one
+    /// to drop?
+    pub(super) fn drop_could_block(&self, player: Player) -> bool {
+        let mut board = self.clone();
+        board.give_to_pocket(player, PieceType::Knight);
+        board.has_legal_drop(player)
+    }
+
+    /// Could `player` drop anything anywhere?
+    pub(super) fn has_legal_drop(&self, player: Player) -> bool {
+        self.pockets
+            .get(player)
+            .kinds()
+            .into_iter()
+            .any(|piecetype| {
+                (0..8).any(|x| {
+                    (0..8).any(|y| {
+                        let to = Square::new(x, y).unwrap();
+                        self.map[x][y].is_none()
+                            && self.clone().try_drop(player, piecetype, to).is_ok()
+                    })
+                })
+            })
+    }
+
+    /// Take back a drop, the part of `unmake_move` that's different for them.
+    /// `replaced` is the captured piece whose slot the dropped piece took.
+    pub(super) fn unmake_drop(&mut self, undo: &Undo, replaced: Option<Piece>) {
+        let (t_x, t_y) = undo.to.coords();
+        self.map[t_x][t_y] = None;
+        let dropped = match replaced {
+            Some(replaced) => std::mem::replace(&mut self.pieces[undo.piece_idx], replaced),
+            None => self.pieces.pop().unwrap(),
+        };
+        self.pockets.get_mut(dropped.player).add(dropped.piecetype);
+    }
+}
@@ -0,0 +1,332 @@
+//! Looking ahead from a position to find the best moves in it: an alpha-beta
+//! search over the board's own move rules, scored with [`Board::evaluate`],
+//! deepening one ply at a time until it's told to stop.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Board, Book, Move, Outcome, PieceType, Player, UciMove};
+
+/// What a line is worth, from White's point of view like [`Evaluation`]
+///
+/// [`Evaluation`]: super::Evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Score {
+    Centipawns(i32),
+    /// Mate in this many moves, negative when Black is the one mating
+    Mate(i32),
+}
+
+/// One of the best lines found in a position
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalysisLine {
+    pub depth: u32,
+    /// Where the line ranks, starting from 1 for the best
+    pub multipv: usize,
+    pub score: Score,
+    /// The moves of the line in SAN, starting with the move to play
+    pub pv: Vec<String>,
+}
+
+// scores inside the search are for the player to move, with mate in `n` plies
+// scored `MATE - n`
+const MATE: i32 = 100_000;
+const INFINITY: i32 = 1_000_000;
+// any more and an unstopped search would run for ever without getting anywhere
+const MAX_DEPTH: u32 = 64;
+
+/// A move at the root of the search and what it was found to be worth last
+/// time round
+struct RootMove {
+    mv: Move,
+    board: Board,
+    score: i32,
+    pv: Vec<Move>,
+}
+
+struct Searcher<'a> {
+    stop: &'a AtomicBool,
+}
+
+impl Searcher<'_> {
+    /// The score of `board` for the player to move, and the moves of the best
+    /// line from it, or `None` if the search was stopped.
+    fn negamax(
+        &self,
+        board: &Board,
+        depth: u32,
+        mut alpha: i32,
+        beta: i32,
+        ply: i32,
+    ) -> Option<(i32, Vec<Move>)> {
+        if self.stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        if let Some(outcome) = board.outcome {
+            return Some((outcome_score(outcome, board.turn, ply), vec![]));
+        }
+        if depth == 0 {
+            return Some((self.quiesce(board, alpha, beta, ply)?, vec![]));
+        }
+        let mut moves = board.successors(false);
+        if moves.is_empty() {
+            // only drops are left, which the search doesn't look at
+            return Some((board.static_score(), vec![]));
+        }
+        order(&mut moves);
+
+        let mut best = (-INFINITY, vec![]);
+        for (mv, after) in moves {
+            let (score, pv) = self.negamax(&after, depth - 1, -beta, -alpha, ply + 1)?;
+            let score = -score;
+            if score > best.0 {
+                best = (score, std::iter::once(mv).chain(pv).collect());
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+        Some(best)
+    }
+
+    /// Play out the captures at the end of a line, so it isn't scored in the
+    /// middle of an exchange.
+    fn quiesce(&self, board: &Board, mut alpha: i32, beta: i32, ply: i32) -> Option<i32> {
+        if self.stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        if let Some(outcome) = board.outcome {
+            return Some(outcome_score(outcome, board.turn, ply));
+        }
+        let stand_pat = board.static_score();
+        if stand_pat >= beta {
+            return Some(stand_pat);
+        }
+        alpha = alpha.max(stand_pat);
+        let mut captures = board.successors(true);
+        order(&mut captures);
+        for (_, after) in captures {
+            let score = -self.quiesce(&after, -beta, -alpha, ply + 1)?;
+            if score >= beta {
+                return Some(score);
+            }
+            alpha = alpha.max(score);
+        }
+        Some(alpha)
+    }
+
+    /// Search every root move `depth` plies deep, well enough to know the
+    /// best `multipv` of them exactly, and sort them best first.
+    fn search_root(&self, root: &mut [RootMove], depth: u32, multipv: usize) -> Option<()> {
+        let mut best_scores = vec![];
+        for rm in root.iter_mut() {
+            // a move that can't beat the worst of the lines already found
+            // doesn't need an exact score
+            let alpha = if best_scores.len() >= multipv {
+                best_scores[multipv - 1]
+            } else {
+                -INFINITY
+            };
+            let (score, pv) = self.negamax(&rm.board, depth - 1, -INFINITY, -alpha, 1)?;
+            rm.score = -score;
+            rm.pv = std::iter::once(rm.mv.clone()).chain(pv).collect();
+            let idx = best_scores.partition_point(|&s| s >= rm.score);
+            best_scores.insert(idx, rm.score);
+        }
+        root.sort_by_key(|rm| Reverse(rm.score));
+        Some(())
+    }
+}
+
+/// The score of a finished game for `player`, sooner mates counting for more
+fn outcome_score(outcome: Outcome, player: Player, ply: i32) -> i32 {
+    match outcome {
+        Outcome::Win { winner, .. } if winner == player => MATE - ply,
+        Outcome::Win { .. } => -(MATE - ply),
+        Outcome::Draw { .. } => 0,
+    }
+}
+
+/// Search captures first, the most valuable victims by the least valuable
+/// attackers, and promotions along with them.
+fn order(moves: &mut [(Move, Board)]) {
+    let value = |piecetype: PieceType| match piecetype {
+        PieceType::Pawn => 1,
+        PieceType::Knight | PieceType::Bishop => 3,
+        PieceType::Rook => 5,
+        PieceType::Queen => 9,
+        PieceType::King => 10,
+    };
+    moves.sort_by_key(|(mv, _)| {
+        let capture = mv
+            .captured
+            .map_or(0, |victim| 10 * value(victim) - value(mv.piece));
+        let promotion = mv.promotion.map_or(0, value);
+        Reverse(capture + 10 * promotion)
+    });
+}
+
+impl Board {
+    /// Search the position ever deeper, up to `depth` plies or until `stop` is
+    /// set, handing each of the best `multipv` lines to `report` whenever a
+    /// depth is finished. Drops aren't searched.
+    pub fn analyze(
+        &self,
+        depth: Option<u32>,
+        multipv: usize,
+        stop: &AtomicBool,
+        mut report: impl FnMut(AnalysisLine),
+    ) {
+        let root = self.searchable();
+        if root.outcome.is_some() {
+            return;
+        }
+        let mut root_moves: Vec<RootMove> = root
+            .successors(false)
+            .into_iter()
+            .map(|(mv, board)| RootMove {
+                mv,
+                board,
+                score: -INFINITY,
+                pv: vec![],
+            })
+            .collect();
+        let multipv = multipv.clamp(1, root_moves.len().max(1));
+        let searcher = Searcher { stop };
+        for depth in 1..=depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH) {
+            if root_moves.is_empty()
+                || searcher
+                    .search_root(&mut root_moves, depth, multipv)
+                    .is_none()
+            {
+                return;
+            }
+            for (idx, rm) in root_moves.iter().take(multipv).enumerate() {
+                report(AnalysisLine {
+                    depth,
+                    multipv: idx + 1,
+                    score: self.score(rm.score),
+                    pv: self.line_san(&rm.pv),
+                });
+            }
+        }
+    }
+
+    /// The move the built-in opponent plays here: one of `book`'s, by weight,
+    /// while it knows the position, or else the best it finds searching
+    /// `depth` plies deep.
+    pub fn engine_move(&self, book: Option<&Book>, depth: u32) -> Option<UciMove> {
+        if let Some(mv) = book.and_then(|book| book.pick(self, &mut rand::thread_rng())) {
+            return Some(UciMove::Move {
+                from: mv.from,
+                to: mv.to,
+                promotion: mv.promotion,
+            });
+        }
+        let (_, line) = self.best_line(depth);
+        line.first().and_then(|mv| mv.uci().parse().ok())
+    }
+
+    /// The best line from here searched `depth` plies deep, and what it's
+    /// worth to the player to move. A finished game is worth what it ended as.
+    pub(super) fn best_line(&self, depth: u32) -> (i32, Vec<Move>) {
+        let stop = AtomicBool::new(false);
+        Searcher { stop: &stop }
+            .negamax(&self.searchable(), depth, -INFINITY, INFINITY, 0)
+            .unwrap_or_default()
+    }
+
+    /// A copy of the board that's cheaper to keep copying. The history is only
+    /// needed for the checks Three-Check counts, and the SAN is the expensive
+    /// part of it.
+    fn searchable(&self) -> Board {
+        let mut board = self.clone();
+        for mv in board.history.iter_mut() {
+            mv.san.clear();
+        }
+        board
+    }
+
+    /// The static evaluation for the player to move.
+    fn static_score(&self) -> i32 {
+        match self.turn {
+            Player::White => self.evaluate().score,
+            Player::Black => -self.evaluate().score,
+        }
+    }
+
+    /// A search score for the player to move here, turned round to White's
+    /// point of view.
+    pub(super) fn score(&self, score: i32) -> Score {
+        let white = match self.turn {
+            Player::White => score,
+            Player::Black => -score,
+        };
+        if score.abs() > MATE - MAX_DEPTH as i32 * 2 {
+            let plies = MATE - score.abs();
+            Score::Mate(white.signum() * (plies + 1) / 2)
+        } else {
+            Score::Centipawns(white)
+        }
+    }
+
+    /// Write out a line of moves from here in SAN.
+    pub(super) fn line_san(&self, line: &[Move]) -> Vec<String> {
+        let mut board = self.clone();
+        line.iter()
+            .map_while(|mv| {
+                board
+                    .move_piece(mv.player, mv.from, mv.to, mv.promotion)
+                    .ok()
+                    .map(|mv| mv.san)
+            })
+            .collect()
+    }
+
+    /// Every legal move, or just the captures, each with the board it leads
+    /// to and everything `finish_move` would work out about it except the
+    /// SAN.
+    fn successors(&self, captures_only: bool) -> Vec<(Move, Board)> {
+        let rules = self.variant.rules();
+        let mut moves = self.legal_moves();
+        if captures_only {
+            moves.retain(|(mv, _)| mv.captured.is_some());
+        }
+        for (mv, after) in moves.iter_mut() {
+            mv.check = rules.has_check() && after.is_check(!mv.player);
+            after.history.push(mv.clone());
+            after.outcome = rules.outcome(after);
+        }
+        moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uci(mv: &str) -> Option<UciMove> {
+        Some(mv.parse().unwrap())
+    }
+
+    #[test]
+    fn engine_plays_from_the_book_first() {
+        // one entry: e2e4 from the starting position
+        let mut entry = 0x463b_9618_1691_fc9c_u64.to_be_bytes().to_vec();
+        let e2e4: u16 = 4 | 3 << 3 | 4 << 6 | 1 << 9;
+        entry.extend(&e2e4.to_be_bytes());
+        entry.extend(&[0, 1, 0, 0, 0, 0]);
+        let book = Book::from_bytes(&entry).unwrap();
+        assert_eq!(Board::default().engine_move(Some(&book), 1), uci("e2e4"));
+    }
+
+    #[test]
+    fn engine_searches_once_out_of_the_book() {
+        let board = Board::from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap();
+        let book = Book::default();
+        assert_eq!(board.engine_move(Some(&book), 2), uci("a1a8"));
+        assert_eq!(board.engine_move(None, 2), uci("a1a8"));
+    }
+}
@@ -0,0 +1,1259 @@
+//! Endgame tablebases in the Syzygy format. WDL tables (`.rtbw`) say whether
+//! a position is won, drawn or lost with perfect play, and DTZ tables
+//! (`.rtbz`) how many plies it takes to reach the next capture or pawn move
+//! while keeping that result. The decoding follows the probing code that
+//! comes with the tables, by way of Stockfish's rewrite of it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::{Board, PieceType, Player, Square, VariantKind};
+
+/// The result of a position for the player to move. A cursed win is a win
+/// that takes too long to force under the fifty-move rule, so it's really a
+/// draw, and a blessed loss is a loss the same rule saves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Wdl {
+    Loss,
+    BlessedLoss,
+    Draw,
+    CursedWin,
+    Win,
+}
+
+impl Wdl {
+    fn from_score(score: i32) -> Wdl {
+        match score {
+            i32::MIN..=-2 => Wdl::Loss,
+            -1 => Wdl::BlessedLoss,
+            0 => Wdl::Draw,
+            1 => Wdl::CursedWin,
+            _ => Wdl::Win,
+        }
+    }
+}
+
+/// A legal move in a tablebase position and where it leads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TablebaseMove {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<PieceType>,
+    pub san: String,
+    /// The result for the player making the move
+    pub wdl: Wdl,
+    /// Plies to the next capture or pawn move once the move is played,
+    /// positive when the player making it is winning
+    pub dtz: i32,
+}
+
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+// flags for each table in a file; all but the last are only used by DTZ
+const STM: u8 = 1;
+const MAPPED: u8 = 2;
+const WIN_PLIES: u8 = 4;
+const LOSS_PLIES: u8 = 8;
+const WIDE: u8 = 16;
+const SINGLE_VALUE: u8 = 128;
+
+// flags for the whole file
+const SPLIT: u8 = 1;
+const HAS_PAWNS: u8 = 2;
+
+const A1: usize = 0;
+const D4: usize = 27;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Wdl,
+    Dtz,
+}
+
+impl Kind {
+    fn extension(self) -> &'static str {
+        match self {
+            Kind::Wdl => "rtbw",
+            Kind::Dtz => "rtbz",
+        }
+    }
+}
+
+fn rank(sq: usize) -> usize {
+    sq / 8
+}
+
+fn file(sq: usize) -> usize {
+    sq % 8
+}
+
+/// How far above the a1-h8 diagonal a square is
+fn off_diagonal(sq: usize) -> i32 {
+    rank(sq) as i32 - file(sq) as i32
+}
+
+fn flip_diagonal(sq: usize) -> usize {
+    ((sq >> 3) | (sq << 3)) & 63
+}
+
+fn read<const N: usize>(data: &[u8], pos: usize) -> Option<[u8; N]> {
+    data.get(pos..pos + N)?.try_into().ok()
+}
+
+fn u16_le(data: &[u8], pos: usize) -> Option<u16> {
+    read(data, pos).map(u16::from_le_bytes)
+}
+
+fn u32_le(data: &[u8], pos: usize) -> Option<u32> {
+    read(data, pos).map(u32::from_le_bytes)
+}
+
+fn u32_be(data: &[u8], pos: usize) -> Option<u32> {
+    read(data, pos).map(u32::from_be_bytes)
+}
+
+fn u64_be(data: &[u8], pos: usize) -> Option<u64> {
+    read(data, pos).map(u64::from_be_bytes)
+}
+
+/// Lookup tables for turning a position into an index into a table. They're
+/// the same for every table.
+#[derive(Debug)]
+struct Encoding {
+    // the squares below the a1-h8 diagonal, numbered 0 to 27
+    map_b1h1h7: [usize; 64],
+    // the a1-d1-d4 triangle, numbered 0 to 9 with the diagonal last
+    map_a1d1d4: [usize; 64],
+    // the 462 ways to place two kings with the first in the a1-d1-d4 triangle
+    map_kk: [[usize; 64]; 10],
+    // [k][n] ways to choose k things from n
+    binomial: [[u64; 64]; 7],
+    // a2 to h7 numbered 0 to 47, highest nearest the edge and lowest rank
+    map_pawns: [usize; 64],
+    // [leading pawns][square of the first]
+    lead_pawn_idx: [[u64; 64]; 6],
+    // [leading pawns][file]
+    lead_pawns_size: [[u64; 4]; 6],
+}
+
+impl Encoding {
+    fn new() -> Encoding {
+        let mut map_b1h1h7 = [0; 64];
+        let mut code = 0;
+        for (sq, mapped) in map_b1h1h7.iter_mut().enumerate() {
+            if off_diagonal(sq) < 0 {
+                *mapped = code;
+                code += 1;
+            }
+        }
+
+        let mut map_a1d1d4 = [usize::MAX; 64];
+        let mut diagonal = vec![];
+        code = 0;
+        for (sq, mapped) in map_a1d1d4.iter_mut().enumerate().take(D4 + 1) {
+            if off_diagonal(sq) < 0 && file(sq) <= 3 {
+                *mapped = code;
+                code += 1;
+            } else if off_diagonal(sq) == 0 && file(sq) <= 3 {
+                diagonal.push(sq);
+            }
+        }
+        for sq in diagonal {
+            map_a1d1d4[sq] = code;
+            code += 1;
+        }
+
+        // when the first king is on the diagonal the second can't be above
+        // it, and positions with both on it come last
+        let mut map_kk = [[0; 64]; 10];
+        let mut both_on_diagonal = vec![];
+        code = 0;
+        for (idx, map_kk) in map_kk.iter_mut().enumerate() {
+            for s1 in (A1..=D4).filter(|&sq| map_a1d1d4[sq] == idx) {
+                for (s2, mapped) in map_kk.iter_mut().enumerate() {
+                    let adjacent = (file(s1) as i32 - file(s2) as i32).abs() <= 1
+                        && (rank(s1) as i32 - rank(s2) as i32).abs() <= 1;
+                    if adjacent || (off_diagonal(s1) == 0 && off_diagonal(s2) > 0) {
+                        continue;
+                    }
+                    if off_diagonal(s1) == 0 && off_diagonal(s2) == 0 {
+                        both_on_diagonal.push((idx, s2));
+                    } else {
+                        *mapped = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (idx, s2) in both_on_diagonal {
+            map_kk[idx][s2] = code;
+            code += 1;
+        }
+
+        let mut binomial = [[0; 64]; 7];
+        for n in 0..64 {
+            binomial[0][n] = 1;
+            for k in 1..7 {
+                binomial[k][n] = if n == 0 {
+                    0
+                } else {
+                    binomial[k - 1][n - 1] + binomial[k][n - 1]
+                };
+            }
+        }
+
+        let mut map_pawns = [0; 64];
+        let mut lead_pawn_idx = [[0; 64]; 6];
+        let mut lead_pawns_size = [[0; 4]; 6];
+        let mut available: usize = 47;
+        for lead_pawns in 1..=5 {
+            for (f, size) in lead_pawns_size[lead_pawns].iter_mut().enumerate() {
+                let mut idx = 0;
+                for r in 1..=6 {
+                    let sq = r * 8 + f;
+                    if lead_pawns == 1 {
+                        map_pawns[sq] = available;
+                        map_pawns[sq ^ 7] = available - 1;
+                        available = available.saturating_sub(2);
+                    }
+                    lead_pawn_idx[lead_pawns][sq] = idx;
+                    idx += binomial[lead_pawns - 1][map_pawns[sq]];
+                }
+                *size = idx;
+            }
+        }
+
+        Encoding {
+            map_b1h1h7,
+            map_a1d1d4,
+            map_kk,
+            binomial,
+            map_pawns,
+            lead_pawn_idx,
+            lead_pawns_size,
+        }
+    }
+}
+
+/// How to find the values for one side to move, and one file of the leading
+/// pawn when there are pawns, within a table's file
+#[derive(Debug, Clone, Default)]
+struct PairsData {
+    flags: u8,
+    min_sym_len: u8,
+    // every `span` values there's an entry in the sparse index
+    span: usize,
+    block_size: usize,
+    num_blocks: usize,
+    block_length_size: usize,
+    sparse_index_size: usize,
+    // where things start in the file
+    lowest_sym: usize,
+    btree: usize,
+    block_lengths: usize,
+    sparse_index: usize,
+    data: usize,
+    // the Huffman code's lowest symbol of each length, padded to 64 bits
+    base64: Vec<u64>,
+    // how many values, less one, each symbol stands for
+    symlen: Vec<u8>,
+    // the pieces in the order they're encoded, which makes up the groups
+    pieces: [u8; 7],
+    group_idx: [u64; 8],
+    group_len: [usize; 8],
+    // DTZ only: where the values for a win, loss, cursed win and blessed loss
+    // are remapped
+    map_idx: [usize; 4],
+}
+
+impl PairsData {
+    fn left(&self, data: &[u8], sym: usize) -> Option<usize> {
+        let lr: [u8; 3] = read(data, self.btree + 3 * sym)?;
+        Some(((lr[1] as usize & 0xF) << 8) | lr[0] as usize)
+    }
+
+    fn right(&self, data: &[u8], sym: usize) -> Option<usize> {
+        let lr: [u8; 3] = read(data, self.btree + 3 * sym)?;
+        Some(((lr[2] as usize) << 4) | (lr[1] as usize >> 4))
+    }
+
+    fn block_length(&self, data: &[u8], block: usize) -> Option<i64> {
+        u16_le(data, self.block_lengths + 2 * block).map(i64::from)
+    }
+}
+
+/// One WDL or DTZ file, read into memory
+#[derive(Debug)]
+struct Table {
+    kind: Kind,
+    data: Vec<u8>,
+    // both sides have the same pieces, so only White to move is stored
+    symmetric: bool,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    piece_count: usize,
+    // the leading side's pawns, then the other side's
+    pawn_count: [usize; 2],
+    // [side to move][file of the leading pawn]
+    items: Vec<Vec<PairsData>>,
+}
+
+/// The piece codes tables use: White's pawn to king are 1 to 6, and Black's
+/// are the same plus 8.
+fn piece_code(player: Player, piecetype: PieceType) -> u8 {
+    let code = match piecetype {
+        PieceType::Pawn => 1,
+        PieceType::Knight => 2,
+        PieceType::Bishop => 3,
+        PieceType::Rook => 4,
+        PieceType::Queen => 5,
+        PieceType::King => 6,
+    };
+    match player {
+        Player::White => code,
+        Player::Black => code + 8,
+    }
+}
+
+fn piece_from_letter(letter: char) -> Option<PieceType> {
+    match letter {
+        'K' => Some(PieceType::King),
+        'Q' => Some(PieceType::Queen),
+        'R' => Some(PieceType::Rook),
+        'B' => Some(PieceType::Bishop),
+        'N' => Some(PieceType::Knight),
+        'P' => Some(PieceType::Pawn),
+        _ => None,
+    }
+}
+
+fn invalid(path: &Path, why: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), why),
+    )
+}
+
+impl Table {
+    /// Read the table for `name`, like "KRvK", from `path`.
+    fn load(encoding: &Encoding, path: &Path, name: &str, kind: Kind) -> io::Result<Table> {
+        let mut sides = name.split('v');
+        let (white, black) = match (sides.next(), sides.next()) {
+            (Some(white), Some(black)) => (white, black),
+            _ => return Err(invalid(path, "not a tablebase name")),
+        };
+        let mut counts = HashMap::new();
+        for (player, letters) in [(Player::White, white), (Player::Black, black)].iter() {
+            for letter in letters.chars() {
+                let piecetype =
+                    piece_from_letter(letter).ok_or_else(|| invalid(path, "not a piece"))?;
+                *counts.entry((*player, piecetype)).or_insert(0) += 1;
+            }
+        }
+        let count = |player, piecetype| counts.get(&(player, piecetype)).copied().unwrap_or(0);
+        let white_pawns = count(Player::White, PieceType::Pawn);
+        let black_pawns = count(Player::Black, PieceType::Pawn);
+        // the side with fewer pawns leads, since that compresses better
+        let white_leads = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+        let pawn_count = if white_leads {
+            [white_pawns, black_pawns]
+        } else {
+            [black_pawns, white_pawns]
+        };
+
+        let mut table = Table {
+            kind,
+            data: fs::read(path)?,
+            symmetric: white == black,
+            has_pawns: white_pawns + black_pawns > 0,
+            has_unique_pieces: counts
+                .iter()
+                .any(|(&(_, piecetype), &n)| piecetype != PieceType::King && n == 1),
+            piece_count: white.len() + black.len(),
+            pawn_count,
+            items: vec![],
+        };
+        let magic = match kind {
+            Kind::Wdl => WDL_MAGIC,
+            Kind::Dtz => DTZ_MAGIC,
+        };
+        if !table.data.starts_with(&magic) {
+            return Err(invalid(path, "not a Syzygy table"));
+        }
+        table
+            .read_header(encoding)
+            .ok_or_else(|| invalid(path, "corrupted table"))?;
+        Ok(table)
+    }
+
+    fn read_header(&mut self, encoding: &Encoding) -> Option<()> {
+        let data = &self.data;
+        let mut pos = 4;
+        let flags = *data.get(pos)?;
+        pos += 1;
+        if (flags & HAS_PAWNS != 0) != self.has_pawns || (flags & SPLIT != 0) == self.symmetric {
+            return None;
+        }
+        let sides = if self.kind == Kind::Wdl && !self.symmetric {
+            2
+        } else {
+            1
+        };
+        let files = if self.has_pawns { 4 } else { 1 };
+        let both_have_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut items = vec![vec![PairsData::default(); files]; sides];
+
+        for f in 0..files {
+            let order = *data.get(pos)?;
+            let pawn_order = if both_have_pawns {
+                *data.get(pos + 1)?
+            } else {
+                0xFF
+            };
+            let orders = [
+                [order & 0xF, pawn_order & 0xF],
+                [order >> 4, pawn_order >> 4],
+            ];
+            pos += 1 + both_have_pawns as usize;
+            for k in 0..self.piece_count {
+                let pieces = *data.get(pos)?;
+                for (side, items) in items.iter_mut().enumerate() {
+                    items[f].pieces[k] = if side == 0 { pieces & 0xF } else { pieces >> 4 };
+                }
+                pos += 1;
+            }
+            for (side, items) in items.iter_mut().enumerate() {
+                self.set_groups(encoding, &mut items[f], orders[side], f)?;
+            }
+        }
+        pos += pos & 1;
+
+        for f in 0..files {
+            for items in items.iter_mut() {
+                pos = set_sizes(&mut items[f], data, pos)?;
+            }
+        }
+
+        if self.kind == Kind::Dtz {
+            for d in items[0].iter_mut() {
+                if d.flags & MAPPED == 0 {
+                    continue;
+                }
+                if d.flags & WIDE != 0 {
+                    pos += pos & 1;
+                    for map_idx in d.map_idx.iter_mut() {
+                        *map_idx = pos + 2;
+                        pos += 2 * u16_le(data, pos)? as usize + 2;
+                    }
+                } else {
+                    for map_idx in d.map_idx.iter_mut() {
+                        *map_idx = pos + 1;
+                        pos += *data.get(pos)? as usize + 1;
+                    }
+                }
+            }
+            pos += pos & 1;
+        }
+
+        for f in 0..files {
+            for items in items.iter_mut() {
+                items[f].sparse_index = pos;
+                pos += items[f].sparse_index_size * 6;
+            }
+        }
+        for f in 0..files {
+            for items in items.iter_mut() {
+                items[f].block_lengths = pos;
+                pos += items[f].block_length_size * 2;
+            }
+        }
+        for f in 0..files {
+            for items in items.iter_mut() {
+                pos = (pos + 0x3F) & !0x3F;
+                items[f].data = pos;
+                pos += items[f].num_blocks * items[f].block_size;
+            }
+        }
+        if pos > data.len() {
+            return None;
+        }
+        self.items = items;
+        Some(())
+    }
+
+    /// Work out which pieces are encoded together, and how big each group's
+    /// share of the index is. Pieces of the same kind and colour go together,
+    /// except that without pawns the first group is the first three pieces, or
+    /// just the kings if there are no other unique pieces. `order` says which
+    /// place the leading group and the other side's pawns take in the index.
+    fn set_groups(
+        &self,
+        encoding: &Encoding,
+        d: &mut PairsData,
+        order: [u8; 2],
+        f: usize,
+    ) -> Option<()> {
+        let mut n = 0;
+        let mut first_len: i32 = if self.has_pawns {
+            0
+        } else if self.has_unique_pieces {
+            3
+        } else {
+            2
+        };
+        d.group_len[0] = 1;
+        for i in 1..self.piece_count {
+            first_len -= 1;
+            if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+                d.group_len[n] += 1;
+            } else {
+                n += 1;
+                d.group_len[n] = 1;
+            }
+        }
+        n += 1;
+        d.group_len[n] = 0;
+
+        let both_have_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = if both_have_pawns { 2 } else { 1 };
+        let mut free_squares =
+            64 - d.group_len[0] - if both_have_pawns { d.group_len[1] } else { 0 };
+        let mut idx: u64 = 1;
+        let mut k = 0;
+        while next < n || k == order[0] as usize || k == order[1] as usize {
+            if k == order[0] as usize {
+                d.group_idx[0] = idx;
+                idx *= if self.has_pawns {
+                    *encoding.lead_pawns_size.get(d.group_len[0])?.get(f)?
+                } else if self.has_unique_pieces {
+                    31332
+                } else {
+                    462
+                };
+            } else if k == order[1] as usize {
+                d.group_idx[1] = idx;
+                idx *= encoding.binomial.get(d.group_len[1])?[48 - d.group_len[0]];
+            } else {
+                d.group_idx[next] = idx;
+                idx *= encoding.binomial.get(d.group_len[next])?[free_squares];
+                free_squares -= d.group_len[next];
+                next += 1;
+            }
+            k += 1;
+        }
+        d.group_idx[n] = idx;
+        Some(())
+    }
+
+    fn get(&self, stm: usize, f: usize) -> &PairsData {
+        &self.items[stm % self.items.len()][if self.has_pawns { f } else { 0 }]
+    }
+}
+
+/// Read the sizes of things and the Huffman code for one `PairsData`,
+/// returning where the next one starts.
+fn set_sizes(d: &mut PairsData, data: &[u8], mut pos: usize) -> Option<usize> {
+    d.flags = *data.get(pos)?;
+    pos += 1;
+    if d.flags & SINGLE_VALUE != 0 {
+        // every position has the same value, which is stored here
+        d.min_sym_len = *data.get(pos)?;
+        return Some(pos + 1);
+    }
+
+    let groups = d.group_len.iter().take(7).position(|&len| len == 0);
+    let tb_size = d.group_idx[groups.unwrap_or(7)];
+    d.block_size = 1usize.checked_shl(*data.get(pos)? as u32)?;
+    d.span = 1usize.checked_shl(*data.get(pos + 1)? as u32)?;
+    pos += 2;
+    d.sparse_index_size = tb_size.div_ceil(d.span as u64) as usize;
+    let padding = *data.get(pos)? as usize;
+    pos += 1;
+    d.num_blocks = u32_le(data, pos)? as usize;
+    pos += 4;
+    d.block_length_size = d.num_blocks + padding;
+    let max_sym_len = *data.get(pos)?;
+    d.min_sym_len = *data.get(pos + 1)?;
+    pos += 2;
+    if max_sym_len < d.min_sym_len {
+        return None;
+    }
+    d.lowest_sym = pos;
+
+    // longer codes have lower values, so base64[i] >= base64[i + 1], and a
+    // code of length l padded to 64 bits lies between base64[l - 1] and
+    // base64[l]
+    let lengths = (max_sym_len - d.min_sym_len) as usize + 1;
+    d.base64 = vec![0; lengths];
+    for i in (0..lengths - 1).rev() {
+        let lowest = u16_le(data, d.lowest_sym + 2 * i)? as u64;
+        let next_lowest = u16_le(data, d.lowest_sym + 2 * (i + 1))? as u64;
+        d.base64[i] = d.base64[i + 1]
+            .wrapping_add(lowest)
+            .wrapping_sub(next_lowest)
+            / 2;
+    }
+    for (i, base) in d.base64.iter_mut().enumerate() {
+        *base = base
+            .checked_shl((64 - i - d.min_sym_len as usize) as u32)
+            .unwrap_or(0);
+    }
+    pos += lengths * 2;
+
+    let symbols = u16_le(data, pos)? as usize;
+    pos += 2;
+    d.btree = pos;
+    d.symlen = vec![0; symbols];
+    let mut visited = vec![false; symbols];
+    for sym in 0..symbols {
+        if !visited[sym] {
+            d.symlen[sym] = set_symlen(d, data, sym, &mut visited)?;
+        }
+    }
+    Some(pos + symbols * 3 + (symbols & 1))
+}
+
+/// Each symbol is either a value or a pair of other symbols. Work out how many
+/// values `sym` expands to, less one.
+fn set_symlen(d: &mut PairsData, data: &[u8], sym: usize, visited: &mut [bool]) -> Option<u8> {
+    visited[sym] = true;
+    let right = d.right(data, sym)?;
+    if right == 0xFFF {
+        return Some(0);
+    }
+    let left = d.left(data, sym)?;
+    for &child in &[left, right] {
+        if !*visited.get(child)? {
+            d.symlen[child] = set_symlen(d, data, child, visited)?;
+        }
+    }
+    Some(d.symlen[left].wrapping_add(d.symlen[right]).wrapping_add(1))
+}
+
+/// Find the value stored at `idx`.
+fn decompress_pairs(d: &PairsData, data: &[u8], idx: u64) -> Option<i32> {
+    if d.flags & SINGLE_VALUE != 0 {
+        return Some(d.min_sym_len as i32);
+    }
+
+    // the sparse index entry nearest `idx` says which block it's in, give or
+    // take a few, and how far into it
+    let k = (idx / d.span as u64) as usize;
+    if k >= d.sparse_index_size {
+        return None;
+    }
+    let mut block = u32_le(data, d.sparse_index + 6 * k)? as usize;
+    let mut offset = u16_le(data, d.sparse_index + 6 * k + 4)? as i64;
+    offset += (idx % d.span as u64) as i64 - (d.span / 2) as i64;
+    while offset < 0 {
+        block = block.checked_sub(1)?;
+        offset += d.block_length(data, block)? + 1;
+    }
+    while offset > d.block_length(data, block)? {
+        offset -= d.block_length(data, block)? + 1;
+        block += 1;
+    }
+
+    // read symbols from the start of the block until reaching the one that
+    // covers our value
+    let mut ptr = d.data + block * d.block_size;
+    let mut buf64 = u64_be(data, ptr)?;
+    ptr += 8;
+    let mut buf64_size = 64;
+    let mut sym;
+    loop {
+        let mut len = 0;
+        while buf64 < *d.base64.get(len)? {
+            len += 1;
+        }
+        let shift = (64 - len - d.min_sym_len as usize) as u32;
+        sym = ((buf64 - d.base64[len]).checked_shr(shift).unwrap_or(0)) as u16;
+        sym = sym.wrapping_add(u16_le(data, d.lowest_sym + 2 * len)?);
+        let values = *d.symlen.get(sym as usize)? as i64 + 1;
+        if offset < values {
+            break;
+        }
+        offset -= values;
+        len += d.min_sym_len as usize;
+        buf64 = buf64.checked_shl(len as u32).unwrap_or(0);
+        buf64_size -= len as i32;
+        if buf64_size <= 32 {
+            buf64_size += 32;
+            buf64 |= (u32_be(data, ptr)? as u64) << (64 - buf64_size);
+            ptr += 4;
+        }
+    }
+
+    // then expand it, pair by pair, down to the value
+    let mut sym = sym as usize;
+    while d.symlen[sym] != 0 {
+        let left = d.left(data, sym)?;
+        let values = *d.symlen.get(left)? as i64 + 1;
+        if offset < values {
+            sym = left;
+        } else {
+            offset -= values;
+            sym = d.right(data, sym)?;
+        }
+    }
+    d.left(data, sym).map(|value| value as i32)
+}
+
+/// The DTZ of the move before a capture or pawn move, given the result after
+/// it.
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        2 => 1,
+        1 => 101,
+        -1 => -101,
+        -2 => -1,
+        _ => 0,
+    }
+}
+
+// a table's name, like "KRvK", and which kind it is
+type TableKey = (String, Kind);
+
+enum Probe {
+    Value(i32),
+    // DTZ tables only store one side to move, and this isn't it
+    ChangeStm,
+}
+
+/// Syzygy tablebases found in one or more directories
+#[derive(Debug)]
+pub struct Tablebase {
+    dirs: Vec<PathBuf>,
+    // the names of every table found, like "KRvK", by kind
+    available: HashSet<TableKey>,
+    max_pieces: usize,
+    encoding: Encoding,
+    // tables are read the first time they're needed
+    tables: Mutex<HashMap<TableKey, Option<Arc<Table>>>>,
+}
+
+impl Tablebase {
+    /// Look for tables in `dirs`.
+    pub fn open(dirs: Vec<PathBuf>) -> io::Result<Tablebase> {
+        let mut available = HashSet::new();
+        let mut max_pieces = 0;
+        for dir in dirs.iter() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let kind = match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("rtbw") => Kind::Wdl,
+                    Some("rtbz") => Kind::Dtz,
+                    _ => continue,
+                };
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    if kind == Kind::Wdl {
+                        max_pieces = max_pieces.max(name.len() - 1);
+                    }
+                    available.insert((name.to_string(), kind));
+                }
+            }
+        }
+        Ok(Tablebase {
+            dirs,
+            available,
+            max_pieces,
+            encoding: Encoding::new(),
+            tables: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The most pieces, kings included, in any of the WDL tables.
+    pub fn max_pieces(&self) -> usize {
+        self.max_pieces
+    }
+
+    fn table(&self, name: &str, kind: Kind) -> Option<Arc<Table>> {
+        let key = (name.to_string(), kind);
+        if !self.available.contains(&key) {
+            return None;
+        }
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .entry(key)
+            .or_insert_with(|| {
+                let file = format!("{}.{}", name, kind.extension());
+                let path = self
+                    .dirs
+                    .iter()
+                    .map(|dir| dir.join(&file))
+                    .find(|path| path.exists())?;
+                match Table::load(&self.encoding, &path, name, kind) {
+                    Ok(table) => Some(Arc::new(table)),
+                    Err(e) => {
+                        tracing::error!("Failed to read tablebase: {}", e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    /// Whether `board` is a position the tables can answer for.
+    pub fn covers(&self, board: &Board) -> bool {
+        let pieces = board.pieces.iter().filter(|p| p.alive).count();
+        board.variant == VariantKind::Standard
+            && pieces <= self.max_pieces.max(2)
+            && board.get_king(Player::White).is_some()
+            && board.get_king(Player::Black).is_some()
+            && board.castling_letters(Player::White, true).is_empty()
+            && board.castling_letters(Player::Black, true).is_empty()
+    }
+
+    /// The result of `board` for the player to move, if it's in the tables.
+    pub fn probe_wdl(&self, board: &Board) -> Option<Wdl> {
+        if !self.covers(board) {
+            return None;
+        }
+        let (wdl, _) = self.search(&Tablebase::bare(board), false)?;
+        Some(Wdl::from_score(wdl))
+    }
+
+    /// How many plies it takes to reach the next capture or pawn move, keeping
+    /// the result the tables give, if the position's in them. It's positive
+    /// when the player to move is winning, negative when they're losing, and
+    /// zero for a draw.
+    pub fn probe_dtz(&self, board: &Board) -> Option<i32> {
+        if !self.covers(board) {
+            return None;
+        }
+        self.dtz(&Tablebase::bare(board))
+    }
+
+    /// Every legal move in `board` with the result it leads to, best first.
+    pub fn moves(&self, board: &Board) -> Vec<TablebaseMove> {
+        if !self.covers(board) {
+            return vec![];
+        }
+        let bare = Tablebase::bare(board);
+        let mut moves = vec![];
+        for (mv, after) in bare.legal_moves() {
+            let zeroing = mv.captured.is_some() || mv.piece == PieceType::Pawn;
+            let (wdl, dtz) = if zeroing {
+                match self.search(&after, false) {
+                    Some((wdl, _)) => (-wdl, dtz_before_zeroing(-wdl)),
+                    None => return vec![],
+                }
+            } else {
+                match (self.search(&after, false), self.dtz(&after)) {
+                    (Some((wdl, _)), Some(dtz)) => (-wdl, -dtz + (-dtz).signum()),
+                    _ => return vec![],
+                }
+            };
+            let san = board
+                .clone()
+                .move_piece(board.turn, mv.from, mv.to, mv.promotion)
+                .map(|mv| mv.san)
+                .unwrap_or_default();
+            // mate is as quick as a win gets
+            let checkmate = san.ends_with('#');
+            moves.push(TablebaseMove {
+                from: mv.from,
+                to: mv.to,
+                promotion: mv.promotion,
+                san,
+                wdl: Wdl::from_score(wdl),
+                dtz: if checkmate { 1 } else { dtz },
+            });
+        }
+        // the best result, then the quickest win or the slowest loss
+        moves.sort_by_key(|mv| (std::cmp::Reverse(mv.wdl as i32), mv.dtz));
+        moves
+    }
+
+    /// A copy of `board` without its history, which would only slow down all
+    /// the copying of the board a search does.
+    fn bare(board: &Board) -> Board {
+        let mut board = board.clone();
+        board.history.clear();
+        board.undo.clear();
+        board
+    }
+
+    /// The WDL score of `board` for the player to move, from -2 for a loss to
+    /// 2 for a win, and whether the best move is a capture or pawn move,
+    /// after which the DTZ table has nothing useful to say. Tables don't store
+    /// positions where there's a capture that's at least as good, or where en
+    /// passant is possible, so captures are searched first.
+    fn search(&self, board: &Board, check_zeroing: bool) -> Option<(i32, bool)> {
+        let moves = board.legal_moves();
+        let mut best = -2;
+        let mut searched = 0;
+        for (mv, after) in moves.iter() {
+            if mv.captured.is_none() && !(check_zeroing && mv.piece == PieceType::Pawn) {
+                continue;
+            }
+            searched += 1;
+            let (value, _) = self.search(after, false)?;
+            let value = -value;
+            if value > best {
+                best = value;
+                if value >= 2 {
+                    return Some((value, true));
+                }
+            }
+        }
+
+        // when every move has been searched, the table could be wrong
+        let no_more_moves = searched > 0 && searched == moves.len();
+        let value = if no_more_moves {
+            best
+        } else {
+            match self.probe_table(board, Kind::Wdl, 0)? {
+                Probe::Value(value) => value,
+                Probe::ChangeStm => return None,
+            }
+        };
+        if best >= value {
+            Some((best, best > 0 || no_more_moves))
+        } else {
+            Some((value, false))
+        }
+    }
+
+    fn dtz(&self, board: &Board) -> Option<i32> {
+        let (wdl, zeroing) = self.search(board, true)?;
+        if wdl == 0 {
+            return Some(0);
+        }
+        if zeroing {
+            return Some(dtz_before_zeroing(wdl));
+        }
+        match self.probe_table(board, Kind::Dtz, wdl)? {
+            Probe::Value(dtz) => {
+                let cursed = wdl == 1 || wdl == -1;
+                Some((dtz + if cursed { 100 } else { 0 }) * wdl.signum())
+            }
+            // the table only has the other side to move, so look one move
+            // ahead for the move that gets to zero quickest
+            Probe::ChangeStm => {
+                let mut min_dtz = None;
+                for (mv, after) in board.legal_moves() {
+                    let zeroing = mv.captured.is_some() || mv.piece == PieceType::Pawn;
+                    let mut dtz = if zeroing {
+                        -dtz_before_zeroing(self.search(&after, false)?.0)
+                    } else {
+                        -self.dtz(&after)?
+                    };
+                    if dtz == 1 && after.is_check(after.turn) && after.legal_moves().is_empty() {
+                        min_dtz = Some(1);
+                    }
+                    if !zeroing {
+                        dtz += dtz.signum();
+                    }
+                    if dtz.signum() == wdl.signum() && min_dtz.is_none_or(|min| dtz < min) {
+                        min_dtz = Some(dtz);
+                    }
+                }
+                // no legal moves means it's mate
+                Some(min_dtz.unwrap_or(-1))
+            }
+        }
+    }
+
+    /// Look up `board` in a table. `wdl` is its WDL score, which DTZ tables
+    /// need to make sense of what they store.
+    fn probe_table(&self, board: &Board, kind: Kind, wdl: i32) -> Option<Probe> {
+        let encoding = &self.encoding;
+        // every piece on the board, by square
+        let mut pieces: Vec<(usize, u8)> = board
+            .pieces
+            .iter()
+            .filter(|p| p.alive)
+            .map(|p| {
+                let sq = p.position.unwrap();
+                (sq.rank() * 8 + sq.file(), piece_code(p.player, p.piecetype))
+            })
+            .collect();
+        pieces.sort_unstable();
+        if pieces.len() == 2 {
+            return Some(Probe::Value(0));
+        }
+
+        // tables are named with the stronger side first and stored as if it
+        // were White, so a position with Black stronger is looked up with the
+        // colours swapped and the board flipped
+        let material = |player| {
+            let mut letters = String::new();
+            for (letter, piecetype) in [
+                ('K', PieceType::King),
+                ('Q', PieceType::Queen),
+                ('R', PieceType::Rook),
+                ('B', PieceType::Bishop),
+                ('N', PieceType::Knight),
+                ('P', PieceType::Pawn),
+            ]
+            .iter()
+            {
+                let code = piece_code(player, *piecetype);
+                for _ in pieces.iter().filter(|(_, c)| *c == code) {
+                    letters.push(*letter);
+                }
+            }
+            letters
+        };
+        let (white, black) = (material(Player::White), material(Player::Black));
+        let (table, black_stronger) = match self.table(&format!("{}v{}", white, black), kind) {
+            Some(table) => (table, false),
+            None => (self.table(&format!("{}v{}", black, white), kind)?, true),
+        };
+        let data = &table.data;
+
+        // symmetric tables only have White to move
+        let flip = black_stronger || (table.symmetric && board.turn == Player::Black);
+        let flip_color = if flip { 8 } else { 0 };
+        let flip_squares = if flip { 56 } else { 0 };
+        let stm = flip as usize ^ (board.turn == Player::Black) as usize;
+
+        // with pawns there's a table for each file the leading pawn can be on,
+        // a to d; it's the one nearest the edge and then lowest down
+        let mut squares = vec![];
+        let mut codes = vec![];
+        let mut lead_pawns = 0;
+        let mut lead_code = 0;
+        let mut tb_file = 0;
+        if table.has_pawns {
+            lead_code = table.get(0, 0).pieces[0] ^ flip_color;
+            for &(sq, code) in pieces.iter() {
+                if code == lead_code {
+                    squares.push(sq ^ flip_squares);
+                    codes.push(code ^ flip_color);
+                }
+            }
+            lead_pawns = squares.len();
+            let first = (0..lead_pawns).max_by_key(|&i| encoding.map_pawns[squares[i]])?;
+            squares.swap(0, first);
+            tb_file = file(squares[0]).min(7 - file(squares[0]));
+        }
+
+        if kind == Kind::Dtz {
+            let dtz_stm = (table.get(stm, tb_file).flags & STM) as usize;
+            if dtz_stm != stm && (table.has_pawns || !table.symmetric) {
+                return Some(Probe::ChangeStm);
+            }
+        }
+
+        for &(sq, code) in pieces.iter() {
+            if !(table.has_pawns && code == lead_code) {
+                squares.push(sq ^ flip_squares);
+                codes.push(code ^ flip_color);
+            }
+        }
+        let size = squares.len();
+        let d = table.get(stm, tb_file);
+
+        // put the pieces in the order the table encodes them
+        for i in lead_pawns..size - 1 {
+            if let Some(j) = (i + 1..size).find(|&j| d.pieces[i] == codes[j]) {
+                codes.swap(i, j);
+                squares.swap(i, j);
+            }
+        }
+
+        // mirror the board so the first piece is on files a to d
+        if file(squares[0]) > 3 {
+            for sq in squares.iter_mut() {
+                *sq ^= 7;
+            }
+        }
+
+        let mut idx;
+        if table.has_pawns {
+            idx = encoding.lead_pawn_idx[lead_pawns][squares[0]];
+            squares[1..lead_pawns].sort_by_key(|&sq| encoding.map_pawns[sq]);
+            for (i, &sq) in squares.iter().enumerate().take(lead_pawns).skip(1) {
+                idx += encoding.binomial[i][encoding.map_pawns[sq]];
+            }
+        } else {
+            // without pawns the board can also be flipped top to bottom and
+            // along the diagonal, to get the first piece into the a1-d1-d4
+            // triangle
+            if rank(squares[0]) > 3 {
+                for sq in squares.iter_mut() {
+                    *sq ^= 56;
+                }
+            }
+            for i in 0..d.group_len[0] {
+                let off = off_diagonal(squares[i]);
+                if off == 0 {
+                    continue;
+                }
+                if off > 0 {
+                    for sq in squares[i..].iter_mut() {
+                        *sq = flip_diagonal(*sq);
+                    }
+                }
+                break;
+            }
+
+            let (s0, s1) = (squares[0], squares[1]);
+            idx = if table.has_unique_pieces {
+                // three unique pieces go together, the first below the
+                // diagonal unless they're all on it
+                let s2 = squares[2];
+                let adjust1 = (s1 > s0) as usize;
+                let adjust2 = (s2 > s0) as usize + (s2 > s1) as usize;
+                let below = |sq: usize| off_diagonal(sq) != 0;
+                (if below(s0) {
+                    (encoding.map_a1d1d4[s0] * 63 + (s1 - adjust1)) * 62 + s2 - adjust2
+                } else if below(s1) {
+                    (6 * 63 + rank(s0) * 28 + encoding.map_b1h1h7[s1]) * 62 + s2 - adjust2
+                } else if below(s2) {
+                    6 * 63 * 62
+                        + 4 * 28 * 62
+                        + rank(s0) * 7 * 28
+                        + (rank(s1) - adjust1) * 28
+                        + encoding.map_b1h1h7[s2]
+                } else {
+                    6 * 63 * 62
+                        + 4 * 28 * 62
+                        + 4 * 7 * 28
+                        + rank(s0) * 7 * 6
+                        + (rank(s1) - adjust1) * 6
+                        + (rank(s2) - adjust2)
+                }) as u64
+            } else {
+                // otherwise it's just the kings
+                *encoding.map_kk.get(encoding.map_a1d1d4[s0])?.get(s1)? as u64
+            };
+        }
+
+        // then every other group, each piece's square counted without the
+        // squares the groups before it have taken
+        idx *= d.group_idx[0];
+        let mut start = d.group_len[0];
+        let mut remaining_pawns = table.has_pawns && table.pawn_count[1] > 0;
+        let mut next = 1;
+        while d.group_len[next] != 0 {
+            let len = d.group_len[next];
+            squares[start..start + len].sort_unstable();
+            let mut n = 0;
+            for i in 0..len {
+                let sq = squares[start + i];
+                let taken = squares[..start].iter().filter(|&&s| sq > s).count();
+                let square = sq - taken - if remaining_pawns { 8 } else { 0 };
+                n += encoding.binomial[i + 1][square];
+            }
+            remaining_pawns = false;
+            idx += n * d.group_idx[next];
+            start += len;
+            next += 1;
+        }
+
+        let value = decompress_pairs(d, data, idx)?;
+        Some(Probe::Value(match kind {
+            Kind::Wdl => value - 2,
+            Kind::Dtz => map_dtz(&table, tb_file, value, wdl)?,
+        }))
+    }
+}
+
+/// Turn a value from a DTZ table into plies. Values are stored most common
+/// first for each result, with a map back to the real ones, and in moves
+/// rather than plies when that's exact enough.
+fn map_dtz(table: &Table, tb_file: usize, value: i32, wdl: i32) -> Option<i32> {
+    let d = table.get(0, tb_file);
+    let mut value = value;
+    if d.flags & MAPPED != 0 {
+        // the maps are stored win, loss, cursed win, blessed loss
+        let map = d.map_idx[match wdl {
+            2 => 0,
+            -2 => 1,
+            1 => 2,
+            _ => 3,
+        }];
+        value = if d.flags & WIDE != 0 {
+            u16_le(&table.data, map + 2 * value as usize)? as i32
+        } else {
+            *table.data.get(map + value as usize)? as i32
+        };
+    }
+    let in_moves = (wdl == 2 && d.flags & WIN_PLIES == 0)
+        || (wdl == -2 && d.flags & LOSS_PLIES == 0)
+        || wdl == 1
+        || wdl == -1;
+    if in_moves {
+        value *= 2;
+    }
+    Some(value + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a KBvK table, which is a draw whatever the position, so each side to
+    // move stores one value and no compressed data
+    fn fixtures() -> Tablebase {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/syzygy");
+        Tablebase::open(vec![dir]).unwrap()
+    }
+
+    fn board(fen: &str) -> Board {
+        Board::from_fen(fen).unwrap()
+    }
+
+    #[test]
+    fn probes_a_table_on_disk() {
+        let tablebase = fixtures();
+        assert_eq!(tablebase.max_pieces(), 3);
+        for fen in [
+            "8/8/4k3/8/8/2B5/8/4K3 w - - 0 1",
+            "8/8/4k3/8/8/2B5/8/4K3 b - - 0 1",
+            // Black's the stronger side, so it's looked up flipped
+            "4k3/8/2b5/8/8/4K3/8/8 w - - 0 1",
+        ]
+        .iter()
+        {
+            let board = board(fen);
+            assert_eq!(tablebase.probe_wdl(&board), Some(Wdl::Draw), "{}", fen);
+            assert_eq!(tablebase.probe_dtz(&board), Some(0), "{}", fen);
+            let moves = tablebase.moves(&board);
+            assert!(!moves.is_empty());
+            assert!(moves.iter().all(|mv| mv.wdl == Wdl::Draw && mv.dtz == 0));
+        }
+    }
+
+    #[test]
+    fn only_probes_positions_it_has_tables_for() {
+        let tablebase = fixtures();
+        // two kings need no table
+        assert_eq!(
+            tablebase.probe_wdl(&board("8/8/4k3/8/8/8/8/4K3 w - - 0 1")),
+            Some(Wdl::Draw)
+        );
+        // no KRvK table
+        assert_eq!(
+            tablebase.probe_wdl(&board("8/8/4k3/8/8/2R5/8/4K3 w - - 0 1")),
+            None
+        );
+        // too many pieces
+        assert_eq!(
+            tablebase.probe_wdl(&board("8/8/4k3/8/8/2BB4/8/4K3 w - - 0 1")),
+            None
+        );
+        assert!(!tablebase.covers(&Board::default()));
+    }
+
+    #[test]
+    fn encodes_kings_and_pawns_like_the_tables() {
+        let encoding = Encoding::new();
+        // 462 ways to place two kings with the first in the a1-d1-d4 triangle
+        let kings: HashSet<usize> = encoding.map_kk.iter().flatten().copied().collect();
+        assert_eq!(kings.len(), 462);
+        assert_eq!(kings.iter().max(), Some(&461));
+        // a single leading pawn can be on any of six ranks of its file
+        assert_eq!(encoding.lead_pawns_size[1], [6; 4]);
+        assert_eq!(encoding.binomial[6][13], 1716);
+    }
+}
@@ -0,0 +1,127 @@
+//! Moves in the long algebraic notation UCI engines use. It only says which
+//! squares a move goes between, so a move can be written down before the
+//! position it's played in exists, and checked against it once it does.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use super::{Board, Move, MovePieceError, PieceType, Player, Square};
+
+/// A move as UCI writes it: "e2e4", "e7e8q", or "N@f3" for a drop. Castling is
+/// the king moving two squares, or onto its own rook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum UciMove {
+    Move {
+        from: Square,
+        to: Square,
+        promotion: Option<PieceType>,
+    },
+    Drop {
+        piece: PieceType,
+        to: Square,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidUciMove(String);
+
+impl fmt::Display for InvalidUciMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} isn't a move in UCI notation", self.0)
+    }
+}
+
+impl FromStr for UciMove {
+    type Err = InvalidUciMove;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidUciMove(s.to_string());
+        if let Some((piece, to)) = s.split_once('@') {
+            let mut letters = piece.chars();
+            let piece = match (letters.next(), letters.next()) {
+                (Some(letter), None) => PieceType::from_letter(letter).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            };
+            let to = to.parse().map_err(|_| invalid())?;
+            return Ok(UciMove::Drop { piece, to });
+        }
+        let square = |range| {
+            s.get(range)
+                .and_then(|square: &str| square.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let (from, to) = (square(0..2)?, square(2..4)?);
+        let mut promotion = s.get(4..).ok_or_else(invalid)?.chars();
+        let promotion = match (promotion.next(), promotion.next()) {
+            (None, _) => None,
+            (Some(letter), None) => Some(PieceType::from_letter(letter).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        Ok(UciMove::Move {
+            from,
+            to,
+            promotion,
+        })
+    }
+}
+
+impl fmt::Display for UciMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciMove::Move {
+                from,
+                to,
+                promotion,
+            } => {
+                write!(f, "{}{}", from, to)?;
+                match promotion {
+                    Some(piece) => write!(f, "{}", piece.letter().to_ascii_lowercase()),
+                    None => Ok(()),
+                }
+            }
+            UciMove::Drop { piece, to } => write!(f, "{}@{}", piece.letter(), to),
+        }
+    }
+}
+
+impl TryFrom<String> for UciMove {
+    type Error = InvalidUciMove;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<UciMove> for String {
+    fn from(mv: UciMove) -> Self {
+        mv.to_string()
+    }
+}
+
+impl Board {
+    /// Play `mv` for `player`, returning a record of it.
+    pub fn play_uci(&mut self, player: Player, mv: UciMove) -> Result<Move, MovePieceError> {
+        match mv {
+            UciMove::Move {
+                from,
+                to,
+                promotion,
+            } => self.move_piece(player, from, to, promotion),
+            UciMove::Drop { piece, to } => self.drop_piece(player, piece, to),
+        }
+    }
+
+    /// Whether `mv` is the move that was played last, however it's written.
+    pub fn last_move_is(&self, mv: UciMove) -> bool {
+        let last = match self.history.last() {
+            Some(last) => last,
+            None => return false,
+        };
+        let mut before = self.clone();
+        before.unmake_move();
+        before.play_uci(last.player, mv).as_ref() == Ok(last)
+    }
+}
@@ -0,0 +1,469 @@
+//! Rule sets other than standard chess. Each variant is a [`Variant`] that the
+//! board consults wherever its rules differ, and a game picks one with a
+//! [`VariantKind`].
+
+use serde::{Deserialize, Serialize};
+
+use super::{Board, Move, MovePieceError, PieceType, Player};
+
+/// Which rules a game is played under
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum VariantKind {
+    #[default]
+    Standard,
+    /// Getting your king to one of the four centre squares wins
+    KingOfTheHill,
+    /// Giving check three times wins
+    ThreeCheck,
+    /// Captures are compulsory and losing every piece wins
+    Antichess,
+    /// White has a horde of pawns and no king, and loses when they're gone
+    Horde,
+    /// Captured pieces change sides and can be dropped back onto the board
+    Crazyhouse,
+    /// Captures explode, and blowing up the enemy king wins
+    Atomic,
+    /// Crazyhouse for two teams of two on two boards, where what you capture
+    /// goes to your partner
+    Bughouse,
+}
+
+impl VariantKind {
+    pub fn rules(self) -> &'static dyn Variant {
+        match self {
+            VariantKind::Standard => &Standard,
+            VariantKind::KingOfTheHill => &KingOfTheHill,
+            VariantKind::ThreeCheck => &ThreeCheck,
+            VariantKind::Antichess => &Antichess,
+            VariantKind::Horde => &Horde,
+            VariantKind::Crazyhouse => &Crazyhouse,
+            VariantKind::Atomic => &Atomic,
+            VariantKind::Bughouse => &Bughouse,
+        }
+    }
+}
+
+/// How a game ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    Win { winner: Player, reason: WinReason },
+    Draw { reason: DrawReason },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WinReason {
+    Checkmate,
+    /// The winner's king reached the centre
+    KingOfTheHill,
+    /// The winner gave check for the third time
+    ThreeChecks,
+    /// The loser has nothing left on the board
+    NoPiecesLeft,
+    /// The loser's king was caught in an explosion
+    KingExploded,
+    /// The loser ran out of time
+    Timeout,
+    /// The loser resigned
+    Resignation,
+    /// The winner's team won on the other board of a Bughouse game
+    OtherBoard,
+    /// The winner had no moves left to play, which wins in Antichess
+    NoMovesLeft,
+    /// The endgame tablebases say the winner can force mate
+    Tablebase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrawReason {
+    Stalemate,
+    /// Fifty moves each without a capture or a pawn move
+    FiftyMoveRule,
+    /// The other board of a Bughouse game was drawn
+    OtherBoard,
+    /// The endgame tablebases say neither side can force a win
+    Tablebase,
+}
+
+/// The ways a variant can change the rules. Everything defaults to standard
+/// chess, so a variant only overrides what it does differently.
+pub trait Variant: Sync {
+    /// The position a game starts from when it isn't given one.
+    fn start_position(&self) -> Board {
+        Board::default()
+    }
+
+    /// Whether kings can be put in check. Without it a king is just another
+    /// piece, free to walk into attack and be captured.
+    fn has_check(&self) -> bool {
+        true
+    }
+
+    /// Is `player`'s king in check?
+    fn is_check(&self, board: &Board, player: Player) -> bool {
+        board.king_attacked(player)
+    }
+
+    fn allows_castling(&self) -> bool {
+        true
+    }
+
+    fn can_promote_to(&self, piecetype: PieceType) -> bool {
+        piecetype != PieceType::Pawn && piecetype != PieceType::King
+    }
+
+    /// Whether pieces can be dropped from a pocket onto the board.
+    fn has_drops(&self) -> bool {
+        false
+    }
+
+    /// Whether captured pieces go into the capturer's own pocket.
+    fn pockets_captures(&self) -> bool {
+        self.has_drops()
+    }
+
+    /// Whether captures blow up everything around them, as in Atomic.
+    fn explodes(&self) -> bool {
+        false
+    }
+
+    /// Refuse a move the pieces can make but the variant doesn't allow.
+    /// `board` is the position before the move.
+    fn check_move(&self, _board: &Board, _mv: &Move) -> Result<(), MovePieceError> {
+        Ok(())
+    }
+
+    /// Whether the game is over now that a move has been played. The player
+    /// to move next is `board.turn()`.
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        standard_outcome(board)
+    }
+}
+
+/// Checkmate, stalemate and the fifty-move rule
+fn standard_outcome(board: &Board) -> Option<Outcome> {
+    let player = board.turn();
+    if !board.has_legal_move(player) {
+        return Some(if board.is_check(player) {
+            Outcome::Win {
+                winner: !player,
+                reason: WinReason::Checkmate,
+            }
+        } else {
+            Outcome::Draw {
+                reason: DrawReason::Stalemate,
+            }
+        });
+    }
+    if board.halfmove_clock >= 100 {
+        return Some(Outcome::Draw {
+            reason: DrawReason::FiftyMoveRule,
+        });
+    }
+    None
+}
+
+struct Standard;
+
+impl Variant for Standard {}
+
+struct KingOfTheHill;
+
+impl Variant for KingOfTheHill {
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        let mover = !board.turn();
+        let on_the_hill = board.get_king(mover).is_some_and(|king| {
+            let (x, y) = king.position.unwrap().coords();
+            (3..=4).contains(&x) && (3..=4).contains(&y)
+        });
+        if on_the_hill {
+            return Some(Outcome::Win {
+                winner: mover,
+                reason: WinReason::KingOfTheHill,
+            });
+        }
+        standard_outcome(board)
+    }
+}
+
+struct ThreeCheck;
+
+impl Variant for ThreeCheck {
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        let mover = !board.turn();
+        let checks = board
+            .history()
+            .iter()
+            .filter(|mv| mv.player == mover && mv.check)
+            .count();
+        if checks >= 3 {
+            return Some(Outcome::Win {
+                winner: mover,
+                reason: WinReason::ThreeChecks,
+            });
+        }
+        standard_outcome(board)
+    }
+}
+
+struct Antichess;
+
+impl Variant for Antichess {
+    fn start_position(&self) -> Board {
+        Board::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1").unwrap()
+    }
+
+    fn has_check(&self) -> bool {
+        false
+    }
+
+    fn allows_castling(&self) -> bool {
+        false
+    }
+
+    fn can_promote_to(&self, piecetype: PieceType) -> bool {
+        piecetype != PieceType::Pawn
+    }
+
+    fn check_move(&self, board: &Board, mv: &Move) -> Result<(), MovePieceError> {
+        if mv.captured.is_none() && board.has_legal_capture(mv.player) {
+            return Err(MovePieceError::MustCapture);
+        }
+        Ok(())
+    }
+
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        // losing everything, or being stalemated, wins
+        let player = board.turn();
+        if !board.has_legal_move(player) {
+            return Some(Outcome::Win {
+                winner: player,
+                reason: WinReason::NoMovesLeft,
+            });
+        }
+        if board.halfmove_clock >= 100 {
+            return Some(Outcome::Draw {
+                reason: DrawReason::FiftyMoveRule,
+            });
+        }
+        None
+    }
+}
+
+struct Horde;
+
+impl Variant for Horde {
+    fn start_position(&self) -> Board {
+        Board::from_fen(
+            "rnbqkbnr/pppppppp/8/1PP2PP1/PPPPPPPP/PPPPPPPP/PPPPPPPP/PPPPPPPP w kq - 0 1",
+        )
+        .unwrap()
+    }
+
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        if board.iter_pieces(Player::White).next().is_none() {
+            return Some(Outcome::Win {
+                winner: Player::Black,
+                reason: WinReason::NoPiecesLeft,
+            });
+        }
+        standard_outcome(board)
+    }
+}
+
+struct Crazyhouse;
+
+impl Variant for Crazyhouse {
+    fn has_drops(&self) -> bool {
+        true
+    }
+}
+
+struct Bughouse;
+
+impl Variant for Bughouse {
+    fn has_drops(&self) -> bool {
+        true
+    }
+
+    // captures are handed to the partner on the other board instead
+    fn pockets_captures(&self) -> bool {
+        false
+    }
+
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        // a check that a dropped piece could block isn't mate, since the
+        // partner might still send one over, so the player waits for it
+        let player = board.turn();
+        if board.is_check(player) && !board.has_legal_move(player) && board.drop_could_block(player)
+        {
+            return None;
+        }
+        standard_outcome(board)
+    }
+}
+
+struct Atomic;
+
+impl Atomic {
+    fn king_square(board: &Board, player: Player) -> Option<(usize, usize)> {
+        board
+            .get_king(player)
+            .map(|king| king.position.unwrap().coords())
+    }
+}
+
+fn adjacent((a_x, a_y): (usize, usize), (b_x, b_y): (usize, usize)) -> bool {
+    (a_x as isize - b_x as isize).abs() <= 1 && (a_y as isize - b_y as isize).abs() <= 1
+}
+
+impl Variant for Atomic {
+    fn explodes(&self) -> bool {
+        true
+    }
+
+    fn is_check(&self, board: &Board, player: Player) -> bool {
+        // a king can't capture, so it's safe from anything while it's next to
+        // the enemy king, and once either king has gone the game's over anyway
+        match (
+            Atomic::king_square(board, player),
+            Atomic::king_square(board, !player),
+        ) {
+            (Some(ours), Some(theirs)) => !adjacent(ours, theirs) && board.king_attacked(player),
+            _ => false,
+        }
+    }
+
+    fn check_move(&self, board: &Board, mv: &Move) -> Result<(), MovePieceError> {
+        if mv.captured.is_none() {
+            return Ok(());
+        }
+        if mv.piece == PieceType::King {
+            return Err(MovePieceError::KingCannotCapture);
+        }
+        let blows_up_own_king = Atomic::king_square(board, mv.player)
+            .is_some_and(|king| adjacent(king, mv.to.coords()));
+        if blows_up_own_king {
+            return Err(MovePieceError::ExplodesOwnKing);
+        }
+        Ok(())
+    }
+
+    fn outcome(&self, board: &Board) -> Option<Outcome> {
+        let player = board.turn();
+        if board.get_king(player).is_none() {
+            return Some(Outcome::Win {
+                winner: !player,
+                reason: WinReason::KingExploded,
+            });
+        }
+        standard_outcome(board)
+    }
+}
+
+impl Board {
+    /// Play the game under `variant`'s rules from here on.
+    pub fn set_variant(&mut self, variant: VariantKind) {
+        self.variant = variant;
+    }
+
+    /// How the game ended, if it has.
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
+
+    /// End the game for a reason outside the board, like a clock running out.
+    /// A game that's already over stays the way it ended.
+    pub fn end_game(&mut self, outcome: Outcome) {
+        self.outcome.get_or_insert(outcome);
+    }
+
+    /// Can `player` capture anything this move? Only captures are tried, so
+    /// the variant's own check of each one never comes back here.
+    fn has_legal_capture(&self, player: Player) -> bool {
+        let targets: Vec<_> = self
+            .iter_pieces(!player)
+            .map(|piece| piece.position.unwrap())
+            .collect();
+        self.iter_pieces(player).any(|piece| {
+            let from = piece.position.unwrap();
+            // anything but a pawn moving to the en passant square isn't
+            // capturing there
+            let en_passant = self
+                .en_passant
+                .filter(|_| piece.piecetype == PieceType::Pawn);
+            targets.iter().copied().chain(en_passant).any(|to| {
+                self.clone()
+                    .try_move(player, from, to, Some(PieceType::Queen))
+                    .is_ok_and(|mv| mv.captured.is_some())
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(variant: VariantKind) -> Board {
+        let mut board = variant.rules().start_position();
+        board.set_variant(variant);
+        board
+    }
+
+    fn play(board: &mut Board, moves: &[&str]) -> Result<(), MovePieceError> {
+        for mv in moves {
+            let player = board.turn();
+            board.play_uci(player, mv.parse().unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn after(fen: &str, variant: VariantKind, mv: &str) -> Board {
+        let mut board = Board::from_fen(fen).unwrap();
+        board.set_variant(variant);
+        play(&mut board, &[mv]).unwrap();
+        board
+    }
+
+    #[test]
+    fn bughouse_mate_needs_a_check_no_drop_can_block() {
+        // the rook check along the first rank could be blocked by a drop
+        let fen = "k7/8/8/8/8/8/PPP4r/1K6 b - - 0 1";
+        assert_eq!(after(fen, VariantKind::Bughouse, "h2h1").outcome(), None);
+        assert_eq!(
+            after(fen, VariantKind::Crazyhouse, "h2h1").outcome(),
+            Some(Outcome::Win {
+                winner: Player::Black,
+                reason: WinReason::Checkmate,
+            })
+        );
+        // but nothing blocks a knight
+        let fen = "k7/8/8/8/8/4n3/PP6/KR6 b - - 0 1";
+        assert_eq!(
+            after(fen, VariantKind::Bughouse, "e3c2").outcome(),
+            Some(Outcome::Win {
+                winner: Player::Black,
+                reason: WinReason::Checkmate,
+            })
+        );
+    }
+
+    #[test]
+    fn antichess_captures_are_compulsory() {
+        let mut board = start(VariantKind::Antichess);
+        play(&mut board, &["e2e4", "d7d5"]).unwrap();
+        assert_eq!(
+            play(&mut board, &["a2a3"]),
+            Err(MovePieceError::MustCapture)
+        );
+        play(&mut board, &["e4d5"]).unwrap();
+    }
+
+    #[test]
+    fn antichess_knight_onto_en_passant_square_is_not_a_capture() {
+        // the knight on f5 can reach e3, where only a pawn could capture
+        let mut board = start(VariantKind::Antichess);
+        play(&mut board, &["a2a3", "g8h6", "a3a4", "h6f5", "e2e4"]).unwrap();
+        play(&mut board, &["a7a6"]).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::chess::Player;
+
+/// A day, the unit correspondence games are timed in
+pub const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How much time each player gets: either a clock for the whole game, or for
+/// correspondence games, a number of days for every move
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeControl {
+    #[serde(default)]
+    pub initial_ms: u64,
+    /// Added to a player's clock after each of their moves
+    #[serde(default)]
+    pub increment_ms: u64,
+    /// How many days each player has for every move of a correspondence
+    /// game, which the rest is ignored for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days_per_move: Option<u32>,
+}
+
+impl TimeControl {
+    /// How long a player has for each move, if it's a correspondence game.
+    pub fn per_move_ms(&self) -> Option<u64> {
+        self.days_per_move.map(|days| u64::from(days) * DAY_MS)
+    }
+}
+
+/// A chess clock for one board. Times are milliseconds since the Unix epoch,
+/// taken from when each event was logged so that replaying a game runs the
+/// clocks exactly the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clock {
+    white_ms: u64,
+    black_ms: u64,
+    increment_ms: u64,
+    // a correspondence game's clocks go back to this after every move
+    #[serde(default)]
+    per_move_ms: Option<u64>,
+    // whose clock is ticking, and since when
+    running: Option<(Player, u64)>,
+}
+
+impl Clock {
+    pub fn new(time_control: TimeControl) -> Self {
+        let per_move_ms = time_control.per_move_ms();
+        let initial_ms = per_move_ms.unwrap_or(time_control.initial_ms);
+        Self {
+            white_ms: initial_ms,
+            black_ms: initial_ms,
+            increment_ms: time_control.increment_ms,
+            per_move_ms,
+            running: None,
+        }
+    }
+
+    fn left_mut(&mut self, player: Player) -> &mut u64 {
+        match player {
+            Player::White => &mut self.white_ms,
+            Player::Black => &mut self.black_ms,
+        }
+    }
+
+    /// Start `player`'s clock.
+    pub fn start(&mut self, player: Player, now: u64) {
+        self.running = Some((player, now));
+    }
+
+    /// `player` has moved: stop their clock, give them their increment, or
+    /// their full time again in a correspondence game, and start their
+    /// opponent's.
+    pub fn press(&mut self, player: Player, now: u64) {
+        self.stop(now);
+        match self.per_move_ms {
+            Some(per_move_ms) => *self.left_mut(player) = per_move_ms,
+            None => *self.left_mut(player) += self.increment_ms,
+        }
+        self.start(!player, now);
+    }
+
+    /// Stop whichever clock is running, keeping the time it had left.
+    pub fn stop(&mut self, now: u64) {
+        if let Some((player, since)) = self.running.take() {
+            let left = self.left_mut(player);
+            *left = left.saturating_sub(now.saturating_sub(since));
+        }
+    }
+
+    /// How long `player` has left at `now`, counting down their clock if it's
+    /// the one running.
+    pub fn left(&self, player: Player, now: u64) -> u64 {
+        let left = match player {
+            Player::White => self.white_ms,
+            Player::Black => self.black_ms,
+        };
+        match self.running {
+            Some((running, since)) if running == player => {
+                left.saturating_sub(now.saturating_sub(since))
+            }
+            _ => left,
+        }
+    }
+
+    /// The player whose time has run out, if any.
+    pub fn flagged(&self, now: u64) -> Option<Player> {
+        let (player, since) = self.running?;
+        let left = match player {
+            Player::White => self.white_ms,
+            Player::Black => self.black_ms,
+        };
+        if now.saturating_sub(since) >= left {
+            Some(player)
+        } else {
+            None
+        }
+    }
+}
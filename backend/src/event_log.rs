@@ -0,0 +1,404 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use tracing::{error, info};
+
+use crate::chess::Board;
+use crate::{ClientMessage, GameState, InternalMessage, ServerMessage};
+
+/// Something that happened to a game, in the order it happened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GameEvent {
+    Client(ClientMessage),
+    /// Something the server told the game itself, like a clock running out
+    Internal(InternalMessage),
+    Server(ServerMessage),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    /// When the event happened, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub time: u64,
+    pub event: GameEvent,
+}
+
+/// An ordered, append-only stream of the events of a single game. Each event is
+/// written as one line of JSON soon after it is recorded, so the file can be
+/// replayed after a crash, less whatever hadn't been written yet.
+#[derive(Debug, Clone, Default)]
+pub struct GameLog {
+    // `None` keeps the log in memory only
+    path: Option<PathBuf>,
+    events: Vec<LoggedEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplayError {
+    /// The event at `seq` didn't come out the same way it did originally
+    OutcomeMismatch {
+        seq: u64,
+        expected: Option<Box<ServerMessage>>,
+        found: Option<Box<ServerMessage>>,
+    },
+    /// Every outcome matched but the boards ended up somewhere else
+    FinalPositionMismatch {
+        expected: Vec<Board>,
+        found: Vec<Board>,
+    },
+    /// A server message appeared without a client message before it
+    UnexpectedServerEvent { seq: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::OutcomeMismatch {
+                seq,
+                expected,
+                found,
+            } => write!(
+                f,
+                "event {}: expected {:?} but replay produced {:?}",
+                seq, expected, found
+            ),
+            ReplayError::FinalPositionMismatch { expected, found } => write!(
+                f,
+                "final position differs, expected {:?} but replay produced {:?}",
+                expected, found
+            ),
+            ReplayError::UnexpectedServerEvent { seq } => {
+                write!(f, "event {}: server message without a client message", seq)
+            }
+        }
+    }
+}
+
+impl GameLog {
+    pub fn record(&mut self, event: GameEvent, time: u64) {
+        let logged = LoggedEvent {
+            seq: self.events.len() as u64,
+            time,
+            event,
+        };
+        if let Some(path) = &self.path {
+            write_behind(path.clone(), logged.clone());
+        }
+        self.events.push(logged);
+    }
+}
+
+// what's waiting for the thread that writes every log
+enum Queued {
+    Event(PathBuf, LoggedEvent),
+    // everything sent before this is on disk once it's answered
+    Flush(Sender<()>),
+}
+
+static WRITER: OnceLock<Sender<Queued>> = OnceLock::new();
+
+/// Hand `event` to the thread that writes every log, in the order events are
+/// recorded, so that a game never waits on the disk while it's locked. The
+/// move has already been played and sent out by the time it's written, so if
+/// the process dies before the writer catches up, the last few events are
+/// lost and the game restores to a position the players have seen pass.
+fn write_behind(path: PathBuf, event: LoggedEvent) {
+    let writer = WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Queued>();
+        thread::Builder::new()
+            .name("event-log".to_string())
+            .spawn(move || {
+                for queued in rx {
+                    match queued {
+                        Queued::Event(path, event) => {
+                            if let Err(e) = append(&path, &event) {
+                                error!(
+                                    "Failed to write event {} to {}: {}",
+                                    event.seq,
+                                    path.display(),
+                                    e
+                                );
+                            }
+                        }
+                        Queued::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .expect("Could not start the event log writer");
+        tx
+    });
+    // the writer only stops if it panics, and then there's nowhere to write
+    let _ = writer.send(Queued::Event(path, event));
+}
+
+/// Wait until every event recorded so far has been written to disk, so none
+/// are lost when the server shuts down.
+pub fn flush() {
+    let writer = match WRITER.get() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let (done, written) = mpsc::channel();
+    if writer.send(Queued::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+fn append(path: &Path, event: &LoggedEvent) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let line = serde_json::to_string(event)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()
+}
+
+/// Read every event from a log file.
+pub fn load(path: &Path) -> io::Result<Vec<LoggedEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: LoggedEvent = serde_json::from_str(&line)?;
+        if event.seq != events.len() as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "expected event {} but found event {}",
+                    events.len(),
+                    event.seq
+                ),
+            ));
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Rebuild a game from its events, checking along the way that every client
+/// message produces exactly the outcomes that were recorded for it and that
+/// the final position matches the last one that was sent out.
+/// `game_state` is the game before anything happened, with the server's
+/// settings.
+pub fn replay(events: &[LoggedEvent], mut game_state: GameState) -> Result<GameState, ReplayError> {
+    let mut last_boards = None;
+    let mut idx = 0;
+    while idx < events.len() {
+        let time = events[idx].time;
+        let client_msg = match &events[idx].event {
+            GameEvent::Client(msg) => msg.clone(),
+            GameEvent::Internal(msg) => ClientMessage::Internal(msg.clone()),
+            GameEvent::Server(_) => {
+                return Err(ReplayError::UnexpectedServerEvent {
+                    seq: events[idx].seq,
+                })
+            }
+        };
+        idx += 1;
+
+        // seats were given random id tokens, so give out the same ones again
+        game_state.replayed_id = events[idx..]
+            .iter()
+            .map_while(|e| match &e.event {
+                GameEvent::Server(msg) => Some(msg),
+                GameEvent::Client(_) | GameEvent::Internal(_) => None,
+            })
+            .find_map(|msg| match msg {
+                ServerMessage::Welcome { id_token, .. } => Some(id_token.clone()),
+                _ => None,
+            });
+        let mut outcomes = game_state.apply_message(client_msg, time).into_iter();
+        // a player taking back a seat they already had is welcomed too
+        game_state.replayed_id = None;
+        loop {
+            let expected = match events.get(idx).map(|e| &e.event) {
+                Some(GameEvent::Server(msg)) => Some(msg),
+                _ => None,
+            };
+            let found = outcomes.next();
+            if expected.is_none() && found.is_none() {
+                break;
+            }
+            if expected != found.as_ref() {
+                return Err(ReplayError::OutcomeMismatch {
+                    seq: events.get(idx).map_or(events.len() as u64, |e| e.seq),
+                    expected: expected.cloned().map(Box::new),
+                    found: found.map(Box::new),
+                });
+            }
+            match expected {
+                Some(ServerMessage::BoardState(board)) => last_boards = Some(vec![board.clone()]),
+                Some(ServerMessage::BoardsState { boards, .. }) => {
+                    last_boards = Some(boards.clone())
+                }
+                _ => {}
+            }
+            idx += 1;
+        }
+    }
+
+    if let Some(expected) = last_boards {
+        if expected != game_state.boards {
+            return Err(ReplayError::FinalPositionMismatch {
+                expected,
+                found: game_state.boards,
+            });
+        }
+    }
+    game_state.log.events = events.to_vec();
+    Ok(game_state)
+}
+
+/// Restore the game recorded at `path`, or start a new one there if nothing
+/// has been recorded yet, from `game_state`. New events are appended to the
+/// same file.
+pub fn restore(path: &Path, game_state: GameState) -> io::Result<GameState> {
+    let events = match load(path) {
+        Ok(events) => events,
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    let mut game_state = replay(&events, game_state).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("could not restore game from {}: {}", path.display(), e),
+        )
+    })?;
+    if !events.is_empty() {
+        info!(
+            "Restored game from {} events in {}",
+            events.len(),
+            path.display()
+        );
+    }
+    game_state.log.path = Some(path.to_path_buf());
+    Ok(game_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::{Outcome, Player, WinReason};
+    use crate::clock::{Clock, TimeControl};
+
+    fn sit(game: &mut GameState) -> String {
+        game.process_message(ClientMessage::Connect)
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Welcome { id_token, .. } => Some(id_token),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn move_piece(id_token: &str, from: &str, to: &str) -> ClientMessage {
+        ClientMessage::MovePiece {
+            id_token: id_token.to_string(),
+            prev_location: from.parse().unwrap(),
+            location: to.parse().unwrap(),
+            promotion: None,
+        }
+    }
+
+    #[test]
+    fn replays_a_resigned_game() {
+        let mut game = GameState::default();
+        let white = sit(&mut game);
+        let black = sit(&mut game);
+        game.process_message(move_piece(&white, "e2", "e4"));
+
+        // turned away, so there's nothing to log
+        let logged = game.log.events.len();
+        game.process_message(move_piece(&white, "d2", "d4"));
+        assert_eq!(game.log.events.len(), logged);
+
+        game.process_message(ClientMessage::Resign { id_token: black });
+        let resigned = Some(Outcome::Win {
+            winner: Player::White,
+            reason: WinReason::Resignation,
+        });
+        assert_eq!(game.outcomes(), vec![resigned]);
+
+        let replayed = replay(&game.log.events, GameState::default()).unwrap();
+        assert_eq!(replayed.outcomes(), vec![resigned]);
+        assert_eq!(replayed.boards, game.boards);
+    }
+
+    #[test]
+    fn flushing_writes_everything_recorded() {
+        let path =
+            std::env::temp_dir().join(format!("chess-server-flush-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut game = restore(&path, GameState::default()).unwrap();
+        let white = sit(&mut game);
+        sit(&mut game);
+        game.process_message(move_piece(&white, "e2", "e4"));
+        flush();
+        let written = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.unwrap(), game.log.events);
+    }
+
+    #[test]
+    fn replays_what_the_server_told_the_game() {
+        let time_control = TimeControl {
+            initial_ms: 0,
+            increment_ms: 0,
+            days_per_move: None,
+        };
+        let new_game = GameState {
+            clocks: vec![Clock::new(time_control)],
+            time_control: Some(time_control),
+            ..Default::default()
+        };
+        let mut game = new_game.clone();
+        sit(&mut game);
+        sit(&mut game);
+        game.process_message(ClientMessage::Internal(InternalMessage::CheckClocks));
+        let flagged = game.outcomes();
+        assert!(flagged[0].is_some());
+        assert!(game
+            .log
+            .events
+            .iter()
+            .any(|e| e.event == GameEvent::Internal(InternalMessage::CheckClocks)));
+
+        let json: Vec<String> = game
+            .log
+            .events
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap())
+            .collect();
+        let events: Vec<LoggedEvent> = json
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let replayed = replay(&events, new_game).unwrap();
+        assert_eq!(replayed.outcomes(), flagged);
+    }
+
+    #[test]
+    fn replays_the_random_seat_tokens() {
+        let mut game = GameState::default();
+        let white = sit(&mut game);
+        let black = sit(&mut game);
+        assert_ne!(white, black);
+        assert_eq!(white.len(), 2 * crate::ID_TOKEN_BYTES);
+
+        let mut replayed = replay(&game.log.events, GameState::default()).unwrap();
+        assert_eq!(replayed.ids, game.ids);
+        // the players carry on with the tokens they were given
+        replayed.process_message(move_piece(&white, "e2", "e4"));
+        replayed.process_message(move_piece(&black, "e7", "e5"));
+        assert_eq!(replayed.boards[0].history().len(), 2);
+    }
+}
@@ -0,0 +1,678 @@
+//! A plain HTTP API on the same port as the websocket, for scripts and bots
+//! that only want to look something up or set something up, without keeping
+//! a socket open. Requests for paths under `/api/` are answered here, one to
+//! a connection, and anything else is taken to be a websocket upgrade.
+//!
+//! - `GET /api/health`
+//! - `GET /api/games`: every game still going
+//! - `GET /api/games/{id}`: a game as JSON, or as PGN or FEN with
+//!   `?format=pgn` or `?format=fen`, whether it's still going or not
+//! - `GET /api/players/{username}/games`: the player's finished games, most
+//!   recent first
+//! - `POST /api/challenges`: put up a challenge, taking the same fields
+//!   `Challenge` does as a JSON body
+//! - `POST /api/challenges/{id}/accept` and `POST /api/challenges/{id}/decline`
+//! - `GET /api/bot/stream`: for bot accounts, the events of the games they
+//!   play, one JSON object a line, for as long as the connection stays open
+//! - `POST /api/bot/games/{id}/move/{uci}`: play a move as a bot
+//!
+//! Everything that acts as an account takes its session token as
+//! `Authorization: Bearer <token>`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use crate::accounts::{AccountError, Accounts};
+use crate::bots;
+use crate::challenges::ChallengeColor;
+use crate::chess::{Board, Outcome, Player, VariantKind};
+use crate::clock::{Clock, TimeControl};
+use crate::event_log;
+use crate::{now_ms, GameMode, GameSettings, GameState, Server, ServerMessage, DEFAULT_GAME};
+
+const API_PREFIX: &str = "/api/";
+const BOT_STREAM_PATH: &str = "/api/bot/stream";
+// a stream has no length, and ends when the connection does
+const STREAM_HEAD: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+    Cache-Control: no-cache\r\nConnection: close\r\n\r\n";
+const MAX_HEAD_LENGTH: usize = 16 * 1024;
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+// how long to wait for enough of the request line to tell what a new
+// connection is for
+const PEEK_ATTEMPTS: u32 = 100;
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A game as the API shows it
+#[derive(Debug, Clone, Serialize)]
+struct GameInfo {
+    id: String,
+    seats: Vec<SeatInfo>,
+    time_control: Option<TimeControl>,
+    casual: bool,
+    boards: Vec<BoardInfo>,
+    clocks: Vec<Clock>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SeatInfo {
+    board: usize,
+    color: Player,
+    /// Who sat down with `Join`, or `None` for anonymous players
+    account: Option<String>,
+    bot: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BoardInfo {
+    variant: VariantKind,
+    fen: String,
+    /// Every move so far, in SAN
+    moves: Vec<String>,
+    turn: Player,
+    outcome: Option<Outcome>,
+}
+
+/// The body of `POST /api/challenges`
+#[derive(Debug, Clone, Deserialize)]
+struct NewChallenge {
+    #[serde(default)]
+    to: Option<String>,
+    #[serde(default)]
+    time_control: Option<TimeControl>,
+    #[serde(default)]
+    color: ChallengeColor,
+    #[serde(default)]
+    variant: VariantKind,
+    #[serde(default)]
+    rated: bool,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    // the session token from an `Authorization: Bearer` header
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(body).expect("Could not serialize response"),
+        }
+    }
+
+    fn text(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Response::json(status, &json!({ "error": message.to_string() }))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        );
+        [head.as_bytes(), self.body.as_bytes()].concat()
+    }
+}
+
+/// Whether a new connection is asking for the API rather than a websocket,
+/// going by the path in its request line, without taking anything off the
+/// stream.
+pub async fn is_api_request(stream: &TcpStream) -> bool {
+    let mut buf = [0; 64];
+    for _ in 0..PEEK_ATTEMPTS {
+        let read = match stream.peek(&mut buf).await {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => read,
+        };
+        let head = &buf[..read];
+        // the path comes after the method and a space
+        let path = head
+            .iter()
+            .position(|&byte| byte == b' ')
+            .map(|space| &head[space + 1..]);
+        match path {
+            Some(path) if path.len() >= API_PREFIX.len() || path.contains(&b' ') => {
+                return path.starts_with(API_PREFIX.as_bytes())
+            }
+            _ if read == buf.len() => return false,
+            _ => tokio::time::sleep(PEEK_INTERVAL).await,
+        }
+    }
+    false
+}
+
+/// Answer the one request on `stream` and close it, or keep it open to
+/// stream a bot's events down.
+pub async fn serve(mut stream: TcpStream, server: Arc<Server>) {
+    let response = match read_request(&mut stream).await {
+        Ok(request) if request.method == "GET" && request.path == BOT_STREAM_PATH => {
+            debug!("HTTP {} {}", request.method, request.path);
+            match bot(&server, &request) {
+                Ok(account) => {
+                    let default_game = server.games.lock().unwrap().get(DEFAULT_GAME).cloned();
+                    match default_game {
+                        Some(default_game) => {
+                            if stream.write_all(STREAM_HEAD).await.is_ok() {
+                                bots::stream(stream, server, account, default_game).await;
+                            }
+                            return;
+                        }
+                        None => Response::error(503, "There's no game for bots to wait in"),
+                    }
+                }
+                Err(response) => response,
+            }
+        }
+        Ok(request) => {
+            debug!("HTTP {} {}", request.method, request.path);
+            // answering can mean reading a finished game's log and replaying
+            // it, or saving the accounts, so it's kept off the runtime
+            let answered = tokio::task::spawn_blocking(move || route(&server, &request)).await;
+            answered.unwrap_or_else(|_| Response::error(500, "The request couldn't be answered"))
+        }
+        Err(response) => response,
+    };
+    if let Err(e) = stream.write_all(&response.to_bytes()).await {
+        warn!("Failed to send an HTTP response: {}", e);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    let head_length = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_HEAD_LENGTH {
+            return Err(Response::error(431, "The request head is too long"));
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err(Response::error(400, "The request ended early")),
+            Ok(read) => buf.extend_from_slice(&chunk[..read]),
+        }
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if !matches!(
+        parsed.parse(&buf[..head_length]),
+        Ok(httparse::Status::Complete(_))
+    ) {
+        return Err(Response::error(400, "The request couldn't be read"));
+    }
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+            .map(str::trim)
+    };
+    let body_length = match header("Content-Length").map(str::parse::<usize>) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return Err(Response::error(400, "The Content-Length isn't a number")),
+        None => 0,
+    };
+    if body_length > MAX_BODY_LENGTH {
+        return Err(Response::error(413, "The request body is too long"));
+    }
+    let token = header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let target = parsed.path.unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: parsed.method.unwrap_or("GET").to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        token,
+        body: buf[head_length..].to_vec(),
+    };
+
+    while request.body.len() < body_length {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err(Response::error(400, "The request ended early")),
+            Ok(read) => request.body.extend_from_slice(&chunk[..read]),
+        }
+    }
+    request.body.truncate(body_length);
+    Ok(request)
+}
+
+fn route(server: &Server, request: &Request) -> Response {
+    let path = request.path[API_PREFIX.len()..].trim_end_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["health"]) => health(server),
+        ("GET", ["games"]) => active_games(server),
+        ("GET", ["games", id]) => game(server, id, query_value(&request.query, "format")),
+        ("GET", ["players", username, "games"]) => match server.accounts.games(username) {
+            Ok((username, games)) => {
+                Response::json(200, &json!({ "username": username, "games": games }))
+            }
+            Err(e) => Response::error(404, e),
+        },
+        ("POST", ["challenges"]) => challenge(server, request),
+        ("POST", ["challenges", id, answer @ ("accept" | "decline")]) => {
+            answer_challenge(server, request, id, answer == &"accept")
+        }
+        ("POST", ["bot", "games", id, "move", uci]) => bot_move(server, request, id, uci),
+        (_, ["health"])
+        | (_, ["games"])
+        | (_, ["games", _])
+        | (_, ["players", _, "games"])
+        | (_, ["challenges"])
+        | (_, ["challenges", _, "accept" | "decline"])
+        | (_, ["bot", "stream"])
+        | (_, ["bot", "games", _, "move", _]) => {
+            Response::error(405, "That method isn't supported here")
+        }
+        _ => Response::error(404, "There's nothing here"),
+    }
+}
+
+/// The value of `name` in a query string, if it's there.
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn health(server: &Server) -> Response {
+    let connections = server.connections.lock().unwrap().len();
+    let games = server.games.lock().unwrap().len();
+    Response::json(
+        200,
+        &json!({ "status": "ok", "connections": connections, "games": games }),
+    )
+}
+
+fn active_games(server: &Server) -> Response {
+    let games: Vec<(String, _)> = server
+        .games
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, game)| (id.clone(), game.clone()))
+        .collect();
+    let mut active: Vec<GameInfo> = games
+        .into_iter()
+        .filter_map(|(id, game)| {
+            let game = game.lock().unwrap();
+            let going = game.outcomes().iter().any(Option::is_none);
+            going.then(|| game_info(id, &game, &server.accounts))
+        })
+        .collect();
+    active.sort_by(|a, b| a.id.cmp(&b.id));
+    Response::json(200, &active)
+}
+
+fn game(server: &Server, id: &str, format: Option<&str>) -> Response {
+    let going = server.games.lock().unwrap().get(id).cloned();
+    let game = match going {
+        Some(game) => game.lock().unwrap().clone(),
+        None => match load_game(server, id) {
+            Ok(Some(game)) => game,
+            Ok(None) => return Response::error(404, format!("There's no game {}", id)),
+            Err(e) => return Response::error(500, e),
+        },
+    };
+    match format.unwrap_or("json") {
+        "json" => Response::json(200, &game_info(id.to_string(), &game, &server.accounts)),
+        "pgn" => {
+            let pgns: Vec<String> = game
+                .boards
+                .iter()
+                .enumerate()
+                .map(|(idx, board)| {
+                    let name = |player| game.account_at(idx, player);
+                    board.to_pgn(
+                        name(Player::White).as_deref().unwrap_or("?"),
+                        name(Player::Black).as_deref().unwrap_or("?"),
+                    )
+                })
+                .collect();
+            Response::text("application/x-chess-pgn", pgns.join("\n"))
+        }
+        "fen" => {
+            let fens: Vec<String> = game
+                .boards
+                .iter()
+                .map(|board| format!("{}\n", fen(&game, board)))
+                .collect();
+            Response::text("text/plain", fens.concat())
+        }
+        other => Response::error(400, format!("There's no format {}", other)),
+    }
+}
+
+/// A finished game the server started, from its log, or `None` if there's no
+/// such game.
+pub fn load_game(server: &Server, id: &str) -> Result<Option<GameState>, String> {
+    // ids are only ever hex, and anything else could reach outside the
+    // directory
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let path = server.games_dir.join(format!("{}.jsonl", id));
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let events = event_log::load(&path).map_err(|e| e.to_string())?;
+    event_log::replay(&events, server.new_game.clone())
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// The position on `board` as FEN, or Shredder-FEN in Chess960, where
+/// every rook's file is needed.
+fn fen(game: &GameState, board: &Board) -> String {
+    match game.mode {
+        GameMode::Chess960 { .. } => board.to_shredder_fen(),
+        _ => board.to_fen(),
+    }
+}
+
+fn game_info(id: String, game: &GameState, accounts: &Accounts) -> GameInfo {
+    let mut seats: Vec<SeatInfo> = game
+        .ids
+        .iter()
+        .map(|(id_token, seat)| {
+            let account = game.accounts.get(id_token).cloned();
+            SeatInfo {
+                board: seat.board,
+                color: seat.player,
+                bot: account
+                    .as_deref()
+                    .is_some_and(|account| accounts.is_bot(account)),
+                account,
+            }
+        })
+        .collect();
+    seats.sort_by_key(|seat| (seat.board, seat.color == Player::Black));
+    GameInfo {
+        id,
+        seats,
+        time_control: game.time_control,
+        casual: game.casual,
+        boards: game
+            .boards
+            .iter()
+            .map(|board| BoardInfo {
+                variant: board.variant(),
+                fen: fen(game, board),
+                moves: board.history().iter().map(|mv| mv.san.clone()).collect(),
+                turn: board.turn(),
+                outcome: board.outcome(),
+            })
+            .collect(),
+        clocks: game.clocks.clone(),
+    }
+}
+
+/// The account signed in with the request's session token.
+fn signed_in(server: &Server, request: &Request) -> Result<String, Response> {
+    request
+        .token
+        .as_deref()
+        .and_then(|token| server.accounts.username(token, now_ms()))
+        .ok_or_else(|| Response::error(401, AccountError::NotSignedIn))
+}
+
+/// The bot account signed in with the request's session token.
+fn bot(server: &Server, request: &Request) -> Result<String, Response> {
+    let account = signed_in(server, request)?;
+    if server.accounts.is_bot(&account) {
+        Ok(account)
+    } else {
+        Err(Response::error(403, AccountError::NotABot))
+    }
+}
+
+fn challenge(server: &Server, request: &Request) -> Response {
+    let account = match signed_in(server, request) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let new: NewChallenge = match serde_json::from_slice(&request.body) {
+        Ok(new) => new,
+        Err(e) => return Response::error(400, e),
+    };
+    let settings = GameSettings {
+        variant: new.variant,
+        time_control: new.time_control,
+        rated: new.rated,
+    };
+    match server.create_challenge(account, None, new.to.as_deref(), new.color, settings) {
+        ServerMessage::ChallengeCreated(info) => Response::json(201, &info),
+        ServerMessage::AccountRejected { reason, message } => {
+            let status = if reason == AccountError::NoSuchAccount {
+                404
+            } else {
+                400
+            };
+            Response::error(status, message)
+        }
+        ServerMessage::Rejected(message) => Response::error(400, message),
+        other => Response::error(500, format!("{:?}", other)),
+    }
+}
+
+fn answer_challenge(server: &Server, request: &Request, id: &str, accept: bool) -> Response {
+    let account = match signed_in(server, request) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    if server.challenges.lock().unwrap().get(id).is_none() {
+        return Response::error(404, format!("There's no challenge {}", id));
+    }
+    let rejected = if accept {
+        server.accept_challenge(&account, None, id)
+    } else {
+        match server.decline_challenge(&account, id) {
+            ServerMessage::ChallengeDeclined { .. } => None,
+            rejected => Some(rejected),
+        }
+    };
+    match rejected {
+        None => Response::json(200, &json!({ "ok": true })),
+        Some(ServerMessage::Rejected(message)) => Response::error(400, message),
+        Some(other) => Response::error(500, format!("{:?}", other)),
+    }
+}
+
+fn bot_move(server: &Server, request: &Request, id: &str, uci: &str) -> Response {
+    let account = match bot(server, request) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let game = match server.games.lock().unwrap().get(id) {
+        Some(game) => game.clone(),
+        None => return Response::error(404, format!("There's no game {}", id)),
+    };
+    match bots::play(server, &game, &account, uci) {
+        None => Response::json(200, &json!({ "ok": true })),
+        Some(ServerMessage::MoveRejected { message, .. })
+        | Some(ServerMessage::IllegalMove(message))
+        | Some(ServerMessage::Rejected(message)) => Response::error(400, message),
+        Some(ServerMessage::UnrecognizedPlayer(_)) => {
+            Response::error(400, "You're not playing in that game")
+        }
+        Some(other) => Response::error(500, format!("{:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch_dir, server};
+    use std::fs;
+    use tokio::net::TcpListener;
+
+    fn request(method: &str, target: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            token: None,
+            body: vec![],
+        }
+    }
+
+    /// What `read_request` makes of `bytes` sent down a connection.
+    async fn read(bytes: Vec<u8>) -> Result<Request, Response> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            // hang up, so a request that says there's more ends early
+            let _ = stream.shutdown(Shutdown::Write);
+            stream
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request(&mut stream).await;
+        drop(client.await.unwrap());
+        request
+    }
+
+    fn status<T>(result: Result<T, Response>) -> Option<u16> {
+        result.err().map(|response| response.status)
+    }
+
+    fn status_line(response: &Response) -> String {
+        let bytes = response.to_bytes();
+        let text = String::from_utf8_lossy(&bytes);
+        text.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn status_lines_give_the_reason() {
+        assert_eq!(
+            status_line(&Response::error(503, "busy")),
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        assert_eq!(
+            status_line(&Response::error(404, "gone")),
+            "HTTP/1.1 404 Not Found"
+        );
+    }
+
+    #[test]
+    fn routes_turn_away_unknown_paths_and_methods() {
+        let dir = scratch_dir("route");
+        let server = server(&dir);
+        let answer = |method, target| route(&server, &request(method, target)).status;
+        assert_eq!(answer("GET", "/api/health"), 200);
+        assert_eq!(answer("GET", "/api/games/"), 200);
+        assert_eq!(answer("GET", "/api/nothing"), 404);
+        assert_eq!(answer("GET", "/api/games/0f/moves"), 404);
+        assert_eq!(answer("DELETE", "/api/games"), 405);
+        assert_eq!(answer("GET", "/api/challenges"), 405);
+        assert_eq!(answer("GET", "/api/bot/games/0f/move/e2e4"), 405);
+        assert_eq!(answer("GET", "/api/games/0f"), 404);
+        assert_eq!(answer("GET", "/api/games/0f?format=pgn"), 404);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_values_are_found_by_name() {
+        assert_eq!(query_value("format=pgn", "format"), Some("pgn"));
+        assert_eq!(query_value("a=1&format=fen&b", "format"), Some("fen"));
+        assert_eq!(query_value("formats=pgn", "format"), None);
+        assert_eq!(query_value("", "format"), None);
+    }
+
+    #[test]
+    fn only_hex_ids_are_looked_up_on_disk() {
+        let dir = scratch_dir("load-game");
+        let games_dir = dir.join("games");
+        fs::create_dir_all(&games_dir).unwrap();
+        let server = server(&games_dir);
+        // a log just outside the games directory
+        fs::write(dir.join("secret.jsonl"), "").unwrap();
+        fs::write(games_dir.join("0f.jsonl"), "").unwrap();
+        for id in ["../secret", "..%2fsecret", "", "0f.jsonl", "0f/"] {
+            assert!(matches!(load_game(&server, id), Ok(None)), "{:?}", id);
+        }
+        // an empty log is a game nobody's done anything in yet
+        assert!(matches!(load_game(&server, "0f"), Ok(Some(_))));
+        assert!(matches!(load_game(&server, "1e"), Ok(None)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_are_read_within_limits() {
+        let request = read(
+            b"POST /api/challenges?x=1 HTTP/1.1\r\nAuthorization: Bearer abc\r\n\
+              Content-Length: 2\r\n\r\n{}"
+                .to_vec(),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/challenges");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.token.as_deref(), Some("abc"));
+        assert_eq!(request.body, b"{}");
+
+        let endless_head = vec![b'a'; MAX_HEAD_LENGTH + 4096];
+        assert_eq!(status(read(endless_head).await), Some(431));
+        let huge_body = format!(
+            "POST /api/challenges HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LENGTH + 1
+        );
+        assert_eq!(status(read(huge_body.into_bytes()).await), Some(413));
+        let short_body = b"POST /api/challenges HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}";
+        assert_eq!(status(read(short_body.to_vec()).await), Some(400));
+        let not_a_length = b"GET /api/health HTTP/1.1\r\nContent-Length: lots\r\n\r\n";
+        assert_eq!(status(read(not_a_length.to_vec()).await), Some(400));
+    }
+}
@@ -1,48 +1,2519 @@
+mod accounts;
+mod bots;
+mod challenges;
+mod chat;
 mod chess;
+mod clock;
+mod event_log;
+mod http;
+mod matchmaking;
+mod ratings;
+mod tournaments;
 
 //use futures::{SinkExt, StreamExt};
 use futures_channel::mpsc::{unbounded, UnboundedSender};
 use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::num::NonZeroU8;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{env, io::Error};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    env,
+    io::{Error, ErrorKind},
+};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, info, trace, warn};
-use tracing_subscriber;
+use tracing::{debug, error, info, warn};
 
-use crate::chess::{Board, PieceType, Player};
+use crate::accounts::{AccountError, Accounts, PlayedGame};
+use crate::challenges::{
+    Challenge, ChallengeColor, ChallengeInfo, Challenges, CHALLENGE_LIFETIME_MS,
+};
+use crate::chat::{ChatError, ChatLimiter, ChatLine, ChatRoom};
+use crate::chess::{
+    AnalysisLine, Annotation, Board, Book, BookMove, DrawReason, Evaluation, Move, MovePieceError,
+    Outcome, PieceType, Player, Square, Tablebase, TablebaseMove, UciMove, VariantKind, Wdl,
+    WinReason,
+};
+use crate::clock::{Clock, TimeControl};
+use crate::event_log::{GameEvent, GameLog};
+use crate::matchmaking::{first_plays_white, Matchmaker, Seek};
+use crate::ratings::{Category, LeaderboardEntry, PlayerRating, RatingChange, RatingSystem};
+use crate::tournaments::{
+    PairingResult, Standing, Tournament, TournamentError, TournamentFormat, Tournaments,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 enum ServerMessage {
-    Welcome { id_token: String },
+    Welcome {
+        id_token: String,
+        // which Bughouse team the seat plays for, the same for both partners
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        team: Option<usize>,
+        // the account sitting in the seat, for seats taken with `Join`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        account: Option<String>,
+    },
+    /// Signed in, with the token to take seats with from now on
+    LoggedIn {
+        username: String,
+        session_token: String,
+    },
+    LoggedOut,
+    AccountRejected {
+        reason: AccountError,
+        message: String,
+    },
+    /// How a rated game that just finished moved both players' ratings
+    RatingsChanged {
+        category: Category,
+        white: RatingChange,
+        black: RatingChange,
+    },
+    Ratings {
+        username: String,
+        ratings: Vec<PlayerRating>,
+    },
+    Leaderboard {
+        category: Category,
+        entries: Vec<LeaderboardEntry>,
+    },
+    /// Waiting in the matchmaking queue for a game rated in `category`
+    Seeking {
+        category: Category,
+    },
+    SeekCancelled,
+    /// A challenge this connection made is standing
+    ChallengeCreated(ChallengeInfo),
+    /// Someone has challenged this player
+    Challenged(ChallengeInfo),
+    ChallengeDeclined {
+        id: String,
+    },
+    ChallengeCancelled {
+        id: String,
+    },
+    ChallengeExpired {
+        id: String,
+    },
+    Chat(ChatLine),
+    ChatRejected {
+        reason: ChatError,
+        message: String,
+    },
+    /// Everyone whose chat the player doesn't see
+    Muted {
+        usernames: Vec<String>,
+    },
+    /// The challenges the player can see
+    Lobby {
+        challenges: Vec<ChallengeInfo>,
+    },
+    /// A tournament the player plays in or runs, as it is now
+    Tournament(Tournament),
+    Tournaments {
+        tournaments: Vec<Tournament>,
+    },
+    Standings {
+        id: String,
+        standings: Vec<Standing>,
+    },
+    /// The tournament's crosstable as plain text, for posting or printing
+    Crosstable {
+        id: String,
+        crosstable: String,
+    },
+    TournamentRejected {
+        reason: TournamentError,
+        message: String,
+    },
+    /// Paired with an opponent in a new game, by matchmaking, a challenge or
+    /// a tournament, which the connection's messages go to from now on
+    Matched {
+        game: String,
+        color: Player,
+        opponent: String,
+        opponent_rating: i32,
+        opponent_bot: bool,
+        time_control: Option<TimeControl>,
+    },
     BoardState(Board),
+    /// Every board in the game and their clocks, for games with more than one
+    /// board or with a time control
+    BoardsState {
+        boards: Vec<Board>,
+        clocks: Vec<Clock>,
+    },
     IllegalMove(String),
+    /// Something asked for other than a move was turned down, and why
+    Rejected(String),
+    MoveRejected {
+        reason: MovePieceError,
+        message: String,
+    },
     UnrecognizedMessage(String),
     UnrecognizedPlayer(String),
+    TakebackRequested {
+        player: Player,
+    },
+    TakebackDeclined {
+        player: Player,
+    },
+    /// The move the player has queued, which only they are told about
+    PremoveQueued {
+        prev_location: Square,
+        location: Square,
+        promotion: Option<PieceType>,
+    },
+    PremoveCancelled,
+    /// A queued move wasn't legal by the time it came to be played, so it's
+    /// been dropped
+    PremoveDiscarded {
+        board: usize,
+        player: Player,
+        reason: MovePieceError,
+        message: String,
+    },
+    /// The lines of conditional moves the player has set
+    ConditionalMoves {
+        lines: Vec<Vec<UciMove>>,
+    },
+    GameOver(Outcome),
+    /// What the opening book has for a position, and one of them picked the
+    /// way an engine playing from the book would
+    BookMoves {
+        moves: Vec<BookMove>,
+        pick: Option<BookMove>,
+    },
+    /// What the endgame tablebases say about a position, with every move
+    /// best first
+    TablebaseResult {
+        wdl: Wdl,
+        dtz: Option<i32>,
+        moves: Vec<TablebaseMove>,
+    },
+    /// A static evaluation of a position, with what it's made up of
+    Evaluation(Evaluation),
+    /// One of the best lines an analysis has found so far
+    AnalysisInfo(AnalysisLine),
+    /// The built-in opponent has sat down to play
+    ComputerJoined {
+        board: usize,
+        player: Player,
+    },
+    /// The game on a board gone back over once it's finished
+    GameAnnotated {
+        board: usize,
+        annotation: Annotation,
+    },
+}
+
+/// What the server tells a game without any client asking
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum InternalMessage {
+    /// End the game if someone's time has run out. The server sends this
+    /// when a clock runs out, so a player who never comes back to a
+    /// correspondence game still loses it.
+    CheckClocks,
+    /// End the game on `board` if the tablebases know how it ends, as long as
+    /// it's still `plies` moves in. The server sends this once it's read the
+    /// tables the position needs without holding up the game.
+    Adjudicate { board: usize, plies: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum ClientMessage {
+    Connect,
+    /// Make an account and sign in to it. Bot accounts are for programs
+    /// playing through the bot API, and are marked as bots wherever they show
+    /// up.
+    Register {
+        username: String,
+        password: String,
+        #[serde(default)]
+        bot: bool,
+    },
+    /// Sign in to an account
+    Login {
+        username: String,
+        password: String,
+    },
+    /// End a session, signing out
+    Logout {
+        session_token: String,
+    },
+    /// Take a seat as the account signed in with `session_token`, or get back
+    /// the seat it already has in this game
+    Join {
+        session_token: String,
+        // filled in by the server from the session token, so the log says who
+        // sat down without keeping the token
+        #[serde(default)]
+        account: Option<String>,
+    },
+    /// Look up an account's ratings
+    GetRating {
+        username: String,
+    },
+    /// Wait for an opponent who wants the same time control, or no clock if
+    /// there isn't one, and has a similar rating
+    Seek {
+        session_token: String,
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+    },
+    /// Stop waiting for an opponent
+    CancelSeek,
+    /// Challenge one player, or anyone in the lobby if `to` is left out, to
+    /// a game played exactly this way
+    Challenge {
+        session_token: String,
+        #[serde(default)]
+        to: Option<String>,
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+        #[serde(default)]
+        color: ChallengeColor,
+        #[serde(default)]
+        variant: VariantKind,
+        #[serde(default)]
+        rated: bool,
+    },
+    AcceptChallenge {
+        session_token: String,
+        id: String,
+    },
+    DeclineChallenge {
+        session_token: String,
+        id: String,
+    },
+    CancelChallenge {
+        session_token: String,
+        id: String,
+    },
+    /// The open challenges, and those to or from the player if they're
+    /// signed in
+    GetLobby {
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+    /// Set up a tournament, run by the player who sets it up, for others to
+    /// join until its first round is paired
+    CreateTournament {
+        session_token: String,
+        name: String,
+        format: TournamentFormat,
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+        #[serde(default)]
+        rated: bool,
+    },
+    JoinTournament {
+        session_token: String,
+        id: String,
+    },
+    LeaveTournament {
+        session_token: String,
+        id: String,
+    },
+    /// Pair a tournament's next round and start its games, for the organiser
+    PairRound {
+        session_token: String,
+        id: String,
+    },
+    /// Settle the game on `board` of `round`, counting both from 0, for the
+    /// organiser
+    ReportResult {
+        session_token: String,
+        id: String,
+        round: usize,
+        board: usize,
+        result: PairingResult,
+    },
+    GetTournaments,
+    GetStandings {
+        id: String,
+    },
+    GetCrosstable {
+        id: String,
+    },
+    /// Go to a game the server started, to watch it or to get back to a seat
+    /// in it with `Join`
+    OpenGame {
+        game: String,
+    },
+    /// Say something in the game the connection is in, as the account it's
+    /// signed in to: to the other players if they're playing, or else to
+    /// the other spectators. The game everyone starts in is `default`.
+    Chat {
+        game_id: String,
+        text: String,
+    },
+    /// Stop seeing a player's chat
+    Mute {
+        session_token: String,
+        username: String,
+    },
+    Unmute {
+        session_token: String,
+        username: String,
+    },
+    /// The highest rated players in a pool, leaving out provisional ratings
+    GetLeaderboard {
+        category: Category,
+        // ten unless it's given
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    MovePiece {
+        id_token: String,
+        prev_location: Square,
+        location: Square,
+        // what a pawn reaching the last rank becomes
+        #[serde(default)]
+        promotion: Option<PieceType>,
+    },
+    /// Put a piece from the player's pocket onto an empty square
+    DropPiece {
+        id_token: String,
+        piece: PieceType,
+        location: Square,
+    },
+    /// Queue a move to play the moment the opponent has moved, at no cost on
+    /// the clock. It's dropped if it isn't legal by then, and played straight
+    /// away if it's already the player's turn.
+    Premove {
+        id_token: String,
+        prev_location: Square,
+        location: Square,
+        #[serde(default)]
+        promotion: Option<PieceType>,
+    },
+    CancelPremove {
+        id_token: String,
+    },
+    /// Line up replies in a correspondence game or one without a clock, while
+    /// the opponent is thinking. Each line alternates between a move the
+    /// opponent might make and the reply to it, in UCI notation, so
+    /// `["g8f6", "e4e5"]` answers Nf6 with e5. These replace any lines set
+    /// before, and none clears them.
+    SetConditionalMoves {
+        id_token: String,
+        lines: Vec<Vec<UciMove>>,
+    },
+    Resign {
+        id_token: String,
+    },
+    RequestTakeback {
+        id_token: String,
+    },
+    AcceptTakeback {
+        id_token: String,
+    },
+    DeclineTakeback {
+        id_token: String,
+    },
+    /// Something the server tells a game itself. Clients can't send these,
+    /// and the log keeps them apart from what clients sent.
+    #[serde(skip_deserializing)]
+    Internal(InternalMessage),
+    /// Start the game over from a different setup, before anyone has moved
+    NewGame {
+        id_token: String,
+        mode: GameMode,
+        #[serde(default)]
+        variant: VariantKind,
+        // Bughouse is always played with a clock, three minutes each unless
+        // it's given one
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+        /// Don't rate the game, even between two accounts
+        #[serde(default)]
+        casual: bool,
+    },
+    /// Look up the current position of a board in the opening book
+    BookMoves {
+        #[serde(default)]
+        board: usize,
+    },
+    /// Look up the current position of a board in the endgame tablebases
+    ProbeTablebase {
+        #[serde(default)]
+        board: usize,
+    },
+    /// Evaluate the current position of a board
+    Evaluate {
+        #[serde(default)]
+        board: usize,
+    },
+    /// Sit the built-in opponent down in the next free seat, to play from the
+    /// opening book while it lasts and then search `depth` plies deep
+    AddComputer {
+        #[serde(default)]
+        depth: Option<u32>,
+    },
+    /// Search a position in the background, sending the best `multipv` lines
+    /// back each time it gets a ply deeper, until it reaches `depth` or it's
+    /// stopped. The position is `fen` if it's given, otherwise the position a
+    /// board is in, or ended in, in the game `game_id`, or in the game this
+    /// connection is in if there's no id.
+    Analyze {
+        #[serde(default)]
+        fen: Option<String>,
+        #[serde(default)]
+        game_id: Option<String>,
+        #[serde(default)]
+        board: usize,
+        #[serde(default)]
+        depth: Option<u32>,
+        #[serde(default)]
+        multipv: Option<usize>,
+    },
+    /// Stop the analysis this connection asked for
+    StopAnalysis,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+enum GameMode {
+    #[default]
+    Standard,
+    /// Fischer Random, from one of the 960 start positions. Leave `position`
+    /// out for a random one.
+    Chess960 {
+        #[serde(default)]
+        position: Option<u16>,
+    },
+    /// Any position, as FEN, X-FEN, or Shredder-FEN
+    FromPosition { fen: String },
+}
+
+impl ServerMessage {
+    fn rejected(reason: MovePieceError) -> Self {
+        ServerMessage::MoveRejected {
+            reason,
+            message: reason.to_string(),
+        }
+    }
+
+    /// Whether this turns down what a client asked for.
+    fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            ServerMessage::IllegalMove(_)
+                | ServerMessage::Rejected(_)
+                | ServerMessage::MoveRejected { .. }
+                | ServerMessage::UnrecognizedPlayer(_)
+                | ServerMessage::AccountRejected { .. }
+        )
+    }
+
+    fn account_rejected(reason: AccountError) -> Self {
+        ServerMessage::AccountRejected {
+            reason,
+            message: reason.to_string(),
+        }
+    }
+
+    fn tournament_rejected(reason: TournamentError) -> Self {
+        ServerMessage::TournamentRejected {
+            reason,
+            message: reason.to_string(),
+        }
+    }
+
+    fn chat_rejected(reason: ChatError) -> Self {
+        ServerMessage::ChatRejected {
+            reason,
+            message: reason.to_string(),
+        }
+    }
+}
+
+impl ClientMessage {
+    /// Whether handling this message can change the state of the game, rather
+    /// than only asking about it. Those that do change it must be written to
+    /// the game's event log.
+    fn mutates_game(&self) -> bool {
+        match self {
+            ClientMessage::Connect
+            | ClientMessage::Join { .. }
+            | ClientMessage::MovePiece { .. }
+            | ClientMessage::DropPiece { .. }
+            | ClientMessage::Premove { .. }
+            | ClientMessage::CancelPremove { .. }
+            | ClientMessage::SetConditionalMoves { .. }
+            | ClientMessage::Resign { .. }
+            | ClientMessage::RequestTakeback { .. }
+            | ClientMessage::AcceptTakeback { .. }
+            | ClientMessage::DeclineTakeback { .. }
+            | ClientMessage::Internal(_)
+            | ClientMessage::NewGame { .. } => true,
+            ClientMessage::Register { .. } => false,
+            ClientMessage::Login { .. } => false,
+            ClientMessage::Logout { .. } => false,
+            ClientMessage::GetRating { .. } => false,
+            ClientMessage::GetLeaderboard { .. } => false,
+            ClientMessage::Seek { .. } => false,
+            ClientMessage::CancelSeek => false,
+            ClientMessage::Challenge { .. } => false,
+            ClientMessage::AcceptChallenge { .. } => false,
+            ClientMessage::DeclineChallenge { .. } => false,
+            ClientMessage::CancelChallenge { .. } => false,
+            ClientMessage::GetLobby { .. } => false,
+            ClientMessage::CreateTournament { .. } => false,
+            ClientMessage::JoinTournament { .. } => false,
+            ClientMessage::LeaveTournament { .. } => false,
+            ClientMessage::PairRound { .. } => false,
+            ClientMessage::ReportResult { .. } => false,
+            ClientMessage::GetTournaments => false,
+            ClientMessage::GetStandings { .. } => false,
+            ClientMessage::GetCrosstable { .. } => false,
+            ClientMessage::OpenGame { .. } => false,
+            ClientMessage::Chat { .. } => false,
+            ClientMessage::Mute { .. } => false,
+            ClientMessage::Unmute { .. } => false,
+            ClientMessage::BookMoves { .. } => false,
+            ClientMessage::ProbeTablebase { .. } => false,
+            ClientMessage::Evaluate { .. } => false,
+            ClientMessage::Analyze { .. } => false,
+            ClientMessage::AddComputer { .. } => false,
+            ClientMessage::StopAnalysis => false,
+        }
+    }
+}
+
+type SharedGame = Arc<Mutex<GameState>>;
+
+/// A client's connection, and the game its messages go to
+#[derive(Debug, Clone)]
+pub struct Connection {
+    tx: UnboundedSender<ServerMessage>,
+    // changes when the player is matched into a game of their own
+    game: Arc<Mutex<SharedGame>>,
+    // whoever last showed a session token here
+    account: Arc<Mutex<Option<String>>>,
+}
+
+/// One of the two players of a game the server is starting
+struct Entrant<'a> {
+    account: &'a str,
+    // `None` when they're not connected, to come back to their seat later
+    connection: Option<&'a Connection>,
+}
+
+/// How a game the server starts is to be played
+#[derive(Debug, Clone, Copy)]
+struct GameSettings {
+    variant: VariantKind,
+    time_control: Option<TimeControl>,
+    rated: bool,
+}
+
+/// What the server shares between every game
+#[derive(Debug)]
+struct Server {
+    accounts: Accounts,
+    matchmaker: Mutex<Matchmaker>,
+    challenges: Mutex<Challenges>,
+    tournaments: Tournaments,
+    // the game everyone starts in, and those the server started that are
+    // still going or still have anyone in them, by id
+    games: Mutex<HashMap<String, SharedGame>>,
+    chat: Mutex<ChatLimiter>,
+    // everyone connected, to reach players by account
+    connections: Mutex<Vec<Connection>>,
+    // the settings every new game starts from
+    new_game: GameState,
+    // where games the server starts are logged, one file each
+    games_dir: PathBuf,
+}
+
+/// A finished game between two accounts, for rating
+#[derive(Debug, Clone, PartialEq)]
+struct RatedGame {
+    white: String,
+    black: String,
+    /// 1 for a White win, ½ for a draw and 0 for a Black win
+    white_score: f64,
+    category: Category,
+}
+
+/// Where a player sits: which board, and which side of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Seat {
+    board: usize,
+    player: Player,
+}
+
+impl Seat {
+    /// The seat for the `n`th player to connect, filling each board in turn.
+    fn nth(n: usize) -> Self {
+        let player = if n.is_multiple_of(2) {
+            Player::White
+        } else {
+            Player::Black
+        };
+        Seat {
+            board: n / 2,
+            player,
+        }
+    }
+
+    /// Which Bughouse team this seat plays for. Partners play opposite colours
+    /// on the two boards.
+    fn team(self) -> usize {
+        if (self.board == 0) == (self.player == Player::White) {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GameState {
+    // just the one, except in Bughouse
+    boards: Vec<Board>,
+    // one for each board, or none for games without a time control
+    clocks: Vec<Clock>,
+    mode: GameMode,
+    time_control: Option<TimeControl>,
+    // never rated, even between two accounts
+    casual: bool,
+    ids: HashMap<String, Seat>,
+    // the account sitting in each seat taken with `Join`, by id token
+    accounts: HashMap<String, String>,
+    connections: Vec<UnboundedSender<ServerMessage>>,
+    log: GameLog,
+    // the player waiting on their opponent to agree to a takeback
+    takeback: Option<Player>,
+    // the clock as it stood as each move on the board was made, stopped, so
+    // a takeback can give back the time spent since
+    clock_history: Vec<Clock>,
+    // the move each player has queued to play as soon as it's their turn
+    premoves: HashMap<Seat, UciMove>,
+    // the lines of replies each player has lined up, what's left of each
+    // starting with a move their opponent might make next
+    conditional: HashMap<Seat, Vec<Vec<UciMove>>>,
+    // the seats the built-in opponent plays, by id token
+    computers: HashMap<String, Computer>,
+    book: Option<Arc<Book>>,
+    tablebase: Option<Arc<Tablebase>>,
+    // end games as soon as they reach a position the tablebases know
+    adjudicate: bool,
+    // whether the tables are being read for the position on the board
+    probing: bool,
+    // the id token the next player to sit down gets, so a replay can hand
+    // out the one the log says they got instead of a new random one
+    replayed_id: Option<String>,
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        Self {
+            boards: vec![Board::default()],
+            clocks: vec![],
+            mode: GameMode::default(),
+            time_control: None,
+            casual: false,
+            ids: HashMap::new(),
+            accounts: HashMap::new(),
+            connections: vec![],
+            log: GameLog::default(),
+            takeback: None,
+            clock_history: vec![],
+            premoves: HashMap::new(),
+            conditional: HashMap::new(),
+            computers: HashMap::new(),
+            book: None,
+            tablebase: None,
+            adjudicate: false,
+            probing: false,
+            replayed_id: None,
+        }
+    }
+}
+
+/// A seat the built-in opponent plays
+#[derive(Debug, Clone, Copy)]
+struct Computer {
+    depth: u32,
+    // whether it's already working out its next move
+    thinking: bool,
+}
+
+/// How deep the built-in opponent searches unless it's told otherwise, and
+/// the deepest it's allowed to, so it can't tie up the server
+const COMPUTER_DEPTH: u32 = 3;
+const MAX_COMPUTER_DEPTH: u32 = 5;
+
+/// The id of the game every connection starts in
+const DEFAULT_GAME: &str = "default";
+
+/// How many plies deep every position of a finished game is searched to judge
+/// the moves
+const ANNOTATION_DEPTH: u32 = 3;
+
+/// How many random bytes go into the token a player's seat is claimed with
+const ID_TOKEN_BYTES: usize = 16;
+
+/// The message turning away a time control no game could be played to, if
+/// it is one: a correspondence game with no days for each move would be lost
+/// before anyone moved.
+fn check_time_control(time_control: Option<TimeControl>) -> Option<ServerMessage> {
+    match time_control {
+        Some(time_control) if time_control.days_per_move == Some(0) => Some(
+            ServerMessage::Rejected("There has to be at least a day for each move".to_string()),
+        ),
+        _ => None,
+    }
+}
+
+/// Milliseconds since the Unix epoch
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// `msg` as JSON for the server's own log, with the passwords and session
+/// tokens it carries blanked out.
+fn redacted(msg: &ClientMessage) -> String {
+    fn redact(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    match name.as_str() {
+                        "password" | "session_token" if !field.is_null() => {
+                            *field = "<redacted>".into()
+                        }
+                        _ => redact(field),
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+    let mut value = serde_json::to_value(msg).unwrap_or_default();
+    redact(&mut value);
+    value.to_string()
+}
+
+impl GameState {
+    /// Handle a message from a client, recording it and its outcome in the
+    /// event log if it changed the game. Returns the messages to send back to
+    /// the client.
+    fn process_message(&mut self, mut client_msg: ClientMessage) -> Vec<ServerMessage> {
+        // pick a random start position up front so the log says which one it
+        // was and replaying it sets up the same board
+        if let ClientMessage::NewGame {
+            mode: GameMode::Chess960 { position },
+            ..
+        } = &mut client_msg
+        {
+            if position.is_none() {
+                *position = Some(rand::thread_rng().gen_range(0, 960));
+            }
+        }
+        // likewise the time, which the clocks run on
+        let now = now_ms();
+        let before = self.outcomes();
+        let messages = self.apply_message(client_msg.clone(), now);
+        // a message that was turned away left the game as it was, unless a
+        // clock ran out on the way
+        let changed =
+            self.outcomes() != before || messages.iter().any(|message| !message.is_rejection());
+        if client_msg.mutates_game() && changed {
+            let event = match client_msg {
+                ClientMessage::Internal(msg) => GameEvent::Internal(msg),
+                msg => GameEvent::Client(msg),
+            };
+            self.log.record(event, now);
+            for message in messages.iter() {
+                self.log.record(GameEvent::Server(message.clone()), now);
+            }
+        }
+        messages
+    }
+
+    /// Apply a message from a client that arrived at `now` to the game without
+    /// logging it. This is the deterministic core that replaying a log re-runs.
+    fn apply_message(&mut self, client_msg: ClientMessage, now: u64) -> Vec<ServerMessage> {
+        let mut messages = self.check_clocks(now);
+        match client_msg {
+            ClientMessage::Connect => {
+                messages.extend(self.sit(None, now));
+                messages.push(self.state());
+            }
+            ClientMessage::Join { account, .. } => {
+                let account = match account {
+                    Some(account) => account,
+                    None => {
+                        return vec![ServerMessage::account_rejected(AccountError::NotSignedIn)]
+                    }
+                };
+                let seated = self
+                    .accounts
+                    .iter()
+                    .find(|(_, seated)| **seated == account)
+                    .map(|(id, _)| id.clone());
+                match seated {
+                    Some(id) => messages.push(self.welcome(id)),
+                    None => messages.extend(self.sit(Some(account), now)),
+                }
+                messages.push(self.state());
+            }
+            ClientMessage::MovePiece {
+                id_token,
+                prev_location,
+                location,
+                promotion,
+            } => {
+                messages.push(self.play(id_token, now, |board, player| {
+                    board.move_piece(player, prev_location, location, promotion)
+                }));
+            }
+            ClientMessage::DropPiece {
+                id_token,
+                piece,
+                location,
+            } => {
+                messages.push(self.play(id_token, now, |board, player| {
+                    board.drop_piece(player, piece, location)
+                }));
+            }
+            ClientMessage::Premove {
+                id_token,
+                prev_location,
+                location,
+                promotion,
+            } => {
+                let seat = match self.ids.get(&id_token) {
+                    Some(seat) => *seat,
+                    None => return vec![ServerMessage::UnrecognizedPlayer(id_token)],
+                };
+                let board = &self.boards[seat.board];
+                if board.outcome().is_some() {
+                    messages.push(ServerMessage::rejected(MovePieceError::GameOver));
+                } else if board.turn() == seat.player {
+                    // the opponent moved before the premove got here
+                    messages.push(self.play(id_token, now, |board, player| {
+                        board.move_piece(player, prev_location, location, promotion)
+                    }));
+                } else {
+                    let premove = UciMove::Move {
+                        from: prev_location,
+                        to: location,
+                        promotion,
+                    };
+                    self.premoves.insert(seat, premove);
+                    messages.push(ServerMessage::PremoveQueued {
+                        prev_location,
+                        location,
+                        promotion,
+                    });
+                }
+            }
+            ClientMessage::CancelPremove { id_token } => match self.ids.get(&id_token) {
+                Some(seat) => {
+                    let seat = *seat;
+                    self.premoves.remove(&seat);
+                    messages.push(ServerMessage::PremoveCancelled);
+                }
+                None => return vec![ServerMessage::UnrecognizedPlayer(id_token)],
+            },
+            ClientMessage::SetConditionalMoves { id_token, lines } => {
+                let seat = match self.ids.get(&id_token) {
+                    Some(seat) => *seat,
+                    None => return vec![ServerMessage::UnrecognizedPlayer(id_token)],
+                };
+                match self.check_conditional(seat, &lines) {
+                    Ok(()) => {
+                        self.conditional.remove(&seat);
+                        if !lines.is_empty() {
+                            self.conditional.insert(seat, lines.clone());
+                        }
+                        messages.push(ServerMessage::ConditionalMoves { lines });
+                    }
+                    Err(e) => messages.push(ServerMessage::IllegalMove(e)),
+                }
+            }
+            ClientMessage::RequestTakeback { id_token } => {
+                let player = match self.ids.get(&id_token) {
+                    Some(seat) => seat.player,
+                    None => return vec![ServerMessage::UnrecognizedPlayer(id_token)],
+                };
+                if !self.casual {
+                    messages.push(ServerMessage::Rejected(
+                        "Takebacks are only allowed in casual games".to_string(),
+                    ));
+                } else if self.boards.len() > 1 {
+                    messages.push(ServerMessage::Rejected(
+                        "Takebacks aren't allowed in Bughouse".to_string(),
+                    ));
+                } else if self.boards[0].outcome().is_some() {
+                    messages.push(ServerMessage::Rejected(
+                        "The game is already over".to_string(),
+                    ));
+                } else if self.takeback_plies(player) > self.boards[0].history().len() {
+                    messages.push(ServerMessage::Rejected(
+                        "You have no moves to take back".to_string(),
+                    ));
+                } else {
+                    self.takeback = Some(player);
+                    let msg = ServerMessage::TakebackRequested { player };
+                    self.broadcast(&msg);
+                    messages.push(msg);
+                }
+            }
+            ClientMessage::AcceptTakeback { id_token }
+            | ClientMessage::DeclineTakeback { id_token }
+                if !self.ids.contains_key(&id_token) =>
+            {
+                messages.push(ServerMessage::UnrecognizedPlayer(id_token));
+            }
+            ClientMessage::AcceptTakeback { id_token } => {
+                let player = self.ids[&id_token].player;
+                match self.takeback {
+                    Some(requester) if requester != player => {
+                        let mut restored = None;
+                        for _ in 0..self.takeback_plies(requester) {
+                            self.boards[0].unmake_move();
+                            restored = self.clock_history.pop().or(restored);
+                        }
+                        // each side gets back the time they had when they
+                        // made the move that's gone, and whoever's to move
+                        // again starts thinking from now
+                        if let (Some(mut clock), Some(_)) = (restored, self.clocks.first()) {
+                            clock.start(self.boards[0].turn(), now);
+                            self.clocks[0] = clock;
+                        }
+                        self.takeback = None;
+                        // they were lined up for a position that's gone
+                        self.premoves.clear();
+                        self.conditional.clear();
+                        let msg = self.state();
+                        self.broadcast(&msg);
+                        messages.push(msg);
+                    }
+                    _ => messages.push(ServerMessage::Rejected(
+                        "There is no takeback to accept".to_string(),
+                    )),
+                }
+            }
+            ClientMessage::DeclineTakeback { id_token } => {
+                let player = self.ids[&id_token].player;
+                match self.takeback {
+                    Some(requester) if requester != player => {
+                        self.takeback = None;
+                        let msg = ServerMessage::TakebackDeclined { player };
+                        self.broadcast(&msg);
+                        messages.push(msg);
+                    }
+                    _ => messages.push(ServerMessage::Rejected(
+                        "There is no takeback to decline".to_string(),
+                    )),
+                }
+            }
+            ClientMessage::NewGame {
+                id_token,
+                mode,
+                variant,
+                time_control,
+                casual,
+            } => {
+                if !self.ids.contains_key(&id_token) {
+                    return vec![ServerMessage::UnrecognizedPlayer(id_token)];
+                }
+                if self.boards.iter().any(|board| !board.history().is_empty()) {
+                    return vec![ServerMessage::Rejected(
+                        "The game has already started".to_string(),
+                    )];
+                }
+                if let Some(msg) = check_time_control(time_control) {
+                    return vec![msg];
+                }
+                let board = match &mode {
+                    GameMode::Standard => Ok(variant.rules().start_position()),
+                    GameMode::Chess960 { position } => {
+                        position.and_then(Board::chess960).ok_or_else(|| {
+                            format!("There's no Chess960 position {}", position.unwrap_or(0))
+                        })
+                    }
+                    GameMode::FromPosition { fen } => {
+                        Board::from_fen(fen).map_err(|e| e.to_string())
+                    }
+                };
+                match board {
+                    Ok(mut board) => {
+                        board.set_variant(variant);
+                        let (board_count, time_control) = match variant {
+                            VariantKind::Bughouse => (
+                                2,
+                                time_control.or(Some(TimeControl {
+                                    initial_ms: 3 * 60 * 1000,
+                                    increment_ms: 0,
+                                    days_per_move: None,
+                                })),
+                            ),
+                            _ => (1, time_control),
+                        };
+                        self.boards = vec![board; board_count];
+                        self.clocks = time_control
+                            .map(|time_control| vec![Clock::new(time_control); board_count])
+                            .unwrap_or_default();
+                        // anyone sitting at a board that's gone has to reconnect
+                        self.ids.retain(|_, seat| seat.board < board_count);
+                        let ids = &self.ids;
+                        self.accounts.retain(|id, _| ids.contains_key(id));
+                        self.mode = mode;
+                        self.time_control = time_control;
+                        self.casual = casual;
+                        self.takeback = None;
+                        self.clock_history.clear();
+                        self.premoves.clear();
+                        self.conditional.clear();
+                        self.start_clocks(now);
+                        let msg = self.state();
+                        self.broadcast(&msg);
+                        messages.push(msg);
+                    }
+                    Err(e) => messages.push(ServerMessage::Rejected(e)),
+                }
+            }
+            ClientMessage::BookMoves { board } => {
+                let book = match &self.book {
+                    Some(book) => book,
+                    None => {
+                        return vec![ServerMessage::Rejected(
+                            "There's no opening book".to_string(),
+                        )]
+                    }
+                };
+                match self.boards.get(board) {
+                    // this is never logged, so the pick doesn't have to come
+                    // out the same way on a replay
+                    Some(board) => messages.push(ServerMessage::BookMoves {
+                        moves: book.moves(board),
+                        pick: book.pick(board, &mut rand::thread_rng()),
+                    }),
+                    None => messages.push(ServerMessage::Rejected(format!(
+                        "There's no board {}",
+                        board
+                    ))),
+                }
+            }
+            ClientMessage::ProbeTablebase { board } => {
+                let tablebase = match &self.tablebase {
+                    Some(tablebase) => tablebase,
+                    None => {
+                        return vec![ServerMessage::Rejected(
+                            "There are no endgame tablebases".to_string(),
+                        )]
+                    }
+                };
+                match self.boards.get(board) {
+                    Some(board) => match tablebase.probe_wdl(board) {
+                        Some(wdl) => messages.push(ServerMessage::TablebaseResult {
+                            wdl,
+                            dtz: tablebase.probe_dtz(board),
+                            moves: tablebase.moves(board),
+                        }),
+                        None => messages.push(ServerMessage::Rejected(
+                            "The position isn't in the tablebases".to_string(),
+                        )),
+                    },
+                    None => messages.push(ServerMessage::Rejected(format!(
+                        "There's no board {}",
+                        board
+                    ))),
+                }
+            }
+            ClientMessage::Evaluate { board } => match self.boards.get(board) {
+                Some(board) => messages.push(ServerMessage::Evaluation(board.evaluate())),
+                None => messages.push(ServerMessage::Rejected(format!(
+                    "There's no board {}",
+                    board
+                ))),
+            },
+            // these belong to the connection that asked, which the lines are
+            // sent to as they're found
+            ClientMessage::Analyze { .. } | ClientMessage::StopAnalysis => {}
+            // likewise, these are about the connection's account rather than
+            // the game
+            ClientMessage::Register { .. }
+            | ClientMessage::Login { .. }
+            | ClientMessage::Logout { .. }
+            | ClientMessage::GetRating { .. }
+            | ClientMessage::GetLeaderboard { .. }
+            | ClientMessage::Seek { .. }
+            | ClientMessage::CancelSeek
+            | ClientMessage::Challenge { .. }
+            | ClientMessage::AcceptChallenge { .. }
+            | ClientMessage::DeclineChallenge { .. }
+            | ClientMessage::CancelChallenge { .. }
+            | ClientMessage::GetLobby { .. }
+            | ClientMessage::CreateTournament { .. }
+            | ClientMessage::JoinTournament { .. }
+            | ClientMessage::LeaveTournament { .. }
+            | ClientMessage::PairRound { .. }
+            | ClientMessage::ReportResult { .. }
+            | ClientMessage::GetTournaments
+            | ClientMessage::GetStandings { .. }
+            | ClientMessage::GetCrosstable { .. }
+            | ClientMessage::OpenGame { .. }
+            | ClientMessage::Chat { .. }
+            | ClientMessage::Mute { .. }
+            | ClientMessage::Unmute { .. } => {}
+            // the clocks were checked above, as they are for every message
+            ClientMessage::Internal(InternalMessage::CheckClocks) => {}
+            ClientMessage::Internal(InternalMessage::Adjudicate { board, plies }) => {
+                // a move or a takeback can get there first
+                let current = self.boards.get(board).is_some_and(|current| {
+                    current.history().len() == plies && current.outcome().is_none()
+                });
+                if current {
+                    self.adjudicate(board);
+                    if let Some(outcome) = self.boards[board].outcome() {
+                        self.game_over(board, now);
+                        self.broadcast(&self.state());
+                        self.broadcast(&ServerMessage::GameOver(outcome));
+                    }
+                }
+            }
+            // the server sits the computer down itself, with a `Connect`
+            ClientMessage::AddComputer { .. } => {}
+            ClientMessage::Resign { id_token } => {
+                let seat = match self.ids.get(&id_token) {
+                    Some(seat) => *seat,
+                    None => return vec![ServerMessage::UnrecognizedPlayer(id_token)],
+                };
+                if self.boards[seat.board].outcome().is_some() {
+                    messages.push(ServerMessage::rejected(MovePieceError::GameOver));
+                } else {
+                    let outcome = Outcome::Win {
+                        winner: !seat.player,
+                        reason: WinReason::Resignation,
+                    };
+                    self.boards[seat.board].end_game(outcome);
+                    self.game_over(seat.board, now);
+                    let msg = self.state();
+                    self.broadcast(&msg);
+                    self.broadcast(&ServerMessage::GameOver(outcome));
+                    messages.push(msg);
+                }
+            }
+        };
+        messages
+    }
+
+    /// The position an analysis is asked for: `fen`, or else the current
+    /// position of `board`.
+    fn analysis_position(&self, fen: Option<&str>, board: usize) -> Result<Board, String> {
+        match fen {
+            Some(fen) => Board::from_fen(fen).map_err(|e| e.to_string()),
+            None => self
+                .boards
+                .get(board)
+                .cloned()
+                .ok_or_else(|| format!("There's no board {}", board)),
+        }
+    }
+
+    /// How each board's game ended, if it has.
+    fn outcomes(&self) -> Vec<Option<Outcome>> {
+        self.boards.iter().map(|board| board.outcome()).collect()
+    }
+
+    /// The boards whose games were still going when `before` was taken from
+    /// `outcomes`, but have ended since.
+    fn finished_since(&self, before: &[Option<Outcome>]) -> Vec<(usize, Board)> {
+        self.boards
+            .iter()
+            .enumerate()
+            .filter(|(idx, board)| {
+                board.outcome().is_some() && before.get(*idx).is_some_and(Option::is_none)
+            })
+            .map(|(idx, board)| (idx, board.clone()))
+            .collect()
+    }
+
+    /// The game on board `idx` to rate, if it's over and counts. Only standard
+    /// chess from the usual start position between two different accounts is
+    /// rated.
+    fn rated_game(&self, idx: usize) -> Option<RatedGame> {
+        let board = self.boards.get(idx)?;
+        if self.casual
+            || self.boards.len() > 1
+            || self.mode != GameMode::Standard
+            || board.variant() != VariantKind::Standard
+        {
+            return None;
+        }
+        let white = self.account_at(idx, Player::White)?;
+        let black = self.account_at(idx, Player::Black)?;
+        if white.eq_ignore_ascii_case(&black) {
+            return None;
+        }
+        let white_score = match board.outcome()? {
+            Outcome::Win {
+                winner: Player::White,
+                ..
+            } => 1.0,
+            Outcome::Win { .. } => 0.0,
+            Outcome::Draw { .. } => 0.5,
+        };
+        Some(RatedGame {
+            white,
+            black,
+            white_score,
+            category: Category::of(self.time_control),
+        })
+    }
+
+    /// The account sitting as `player` on board `idx`, if it was taken with
+    /// `Join`.
+    fn account_at(&self, idx: usize, player: Player) -> Option<String> {
+        let (id, _) = self
+            .ids
+            .iter()
+            .find(|(_, seat)| seat.board == idx && seat.player == player)?;
+        self.accounts.get(id).cloned()
+    }
+
+    /// Give the next free seat, if there is one, to a new player who's signed
+    /// in to `account`, or who's anonymous without one, returning the
+    /// `Welcome` for them.
+    fn sit(&mut self, account: Option<String>, now: u64) -> Option<ServerMessage> {
+        if self.ids.len() >= self.seats() {
+            return None;
+        }
+        let seat = Seat::nth(self.ids.len());
+        let id = self
+            .replayed_id
+            .take()
+            .unwrap_or_else(|| accounts::random_hex(ID_TOKEN_BYTES));
+        self.ids.insert(id.clone(), seat);
+        if let Some(account) = account {
+            self.accounts.insert(id.clone(), account);
+        }
+        self.start_clocks(now);
+        Some(self.welcome(id))
+    }
+
+    /// The `Welcome` for the player with `id_token`.
+    fn welcome(&self, id_token: String) -> ServerMessage {
+        let team = if self.boards.len() > 1 {
+            Some(self.ids[&id_token].team())
+        } else {
+            None
+        };
+        ServerMessage::Welcome {
+            account: self.accounts.get(&id_token).cloned(),
+            id_token,
+            team,
+        }
+    }
+
+    /// How many players the game needs.
+    fn seats(&self) -> usize {
+        self.boards.len() * 2
+    }
+
+    /// Whether a Bughouse game is still waiting for all four players, before
+    /// which nobody can move.
+    fn waiting_for_players(&self) -> bool {
+        self.boards.len() > 1 && self.ids.len() < self.seats()
+    }
+
+    /// The state of the game to send out. A single board without a clock is
+    /// sent on its own, the way clients that only know about one board expect.
+    fn state(&self) -> ServerMessage {
+        if self.boards.len() == 1 && self.clocks.is_empty() {
+            ServerMessage::BoardState(self.boards[0].clone())
+        } else {
+            ServerMessage::BoardsState {
+                boards: self.boards.clone(),
+                clocks: self.clocks.clone(),
+            }
+        }
+    }
+
+    /// Start White's clock on every board once everyone has sat down.
+    fn start_clocks(&mut self, now: u64) {
+        let ready = self.ids.len() == self.seats()
+            && self.boards.iter().all(|board| board.history().is_empty());
+        if ready {
+            for clock in self.clocks.iter_mut() {
+                clock.start(Player::White, now);
+            }
+        }
+    }
+
+    /// End the game if anyone has run out of time, returning the messages
+    /// announcing it. This is checked before every message, and the server
+    /// sends `InternalMessage::CheckClocks` for games nobody's sending anything to.
+    fn check_clocks(&mut self, now: u64) -> Vec<ServerMessage> {
+        let flagged = self
+            .clocks
+            .iter()
+            .enumerate()
+            .find_map(|(idx, clock)| Some((idx, clock.flagged(now)?)));
+        if let Some((idx, player)) = flagged {
+            let outcome = Outcome::Win {
+                winner: !player,
+                reason: WinReason::Timeout,
+            };
+            self.boards[idx].end_game(outcome);
+            self.game_over(idx, now);
+            let messages = vec![self.state(), ServerMessage::GameOver(outcome)];
+            for msg in messages.iter() {
+                self.broadcast(msg);
+            }
+            return messages;
+        }
+        vec![]
+    }
+
+    /// Play a move on the board the player with `id_token` is sitting at.
+    fn play(
+        &mut self,
+        id_token: String,
+        now: u64,
+        play: impl FnOnce(&mut Board, Player) -> Result<Move, MovePieceError>,
+    ) -> ServerMessage {
+        let seat = match self.ids.get(&id_token) {
+            Some(seat) => *seat,
+            None => return ServerMessage::UnrecognizedPlayer(id_token),
+        };
+        if self.waiting_for_players() {
+            return ServerMessage::IllegalMove("Waiting for all four players".to_string());
+        }
+        let board = &mut self.boards[seat.board];
+        if seat.player != board.turn() {
+            return ServerMessage::rejected(MovePieceError::NotYourTurn);
+        }
+        let result = play(board, seat.player);
+        self.played(seat, result, now)
+    }
+
+    /// Let everyone know about a move that was just played, returning the
+    /// response for the player who played it.
+    fn played(
+        &mut self,
+        seat: Seat,
+        result: Result<Move, MovePieceError>,
+        now: u64,
+    ) -> ServerMessage {
+        match result {
+            Ok(_) => {
+                self.takeback = None;
+                if let Some(clock) = self.clocks.get_mut(seat.board) {
+                    // takebacks are only allowed on a single board
+                    if self.boards.len() == 1 {
+                        let mut before = clock.clone();
+                        before.stop(now);
+                        self.clock_history.push(before);
+                    }
+                    clock.press(seat.player, now);
+                }
+                // a Bughouse capture goes to the partner, who plays the other
+                // colour on the other board
+                if self.boards.len() > 1 {
+                    if let Some(captured) = self.boards[seat.board].last_captured() {
+                        let other = 1 - seat.board;
+                        self.boards[other].give_to_pocket(!seat.player, captured);
+                    }
+                }
+                let outcome = self.boards[seat.board].outcome();
+                if outcome.is_some() {
+                    self.game_over(seat.board, now);
+                }
+                let msg = self.state();
+                self.broadcast(&msg);
+                match outcome {
+                    Some(outcome) => self.broadcast(&ServerMessage::GameOver(outcome)),
+                    None => self.play_queued(seat.board, now),
+                }
+                // a queued reply may have been played since
+                self.state() // TODO: fix this
+            }
+            Err(e) => ServerMessage::rejected(e),
+        }
+    }
+
+    /// Play whatever the player now to move on `board` queued up for when
+    /// their opponent moved: the reply one of their conditional lines has to
+    /// that move, or else their premove. It costs them no time, since their
+    /// clock has only just started. A queued move that isn't legal now is
+    /// dropped, and one that can't be played yet stays queued.
+    fn play_queued(&mut self, board: usize, now: u64) {
+        if self.waiting_for_players() {
+            return;
+        }
+        let player = self.boards[board].turn();
+        let seat = Seat { board, player };
+        let premove = self.premoves.remove(&seat);
+        let queued = match self.conditional_reply(seat).or(premove) {
+            Some(queued) => queued,
+            None => return,
+        };
+        match self.boards[board].play_uci(player, queued) {
+            Ok(mv) => {
+                self.played(seat, Ok(mv), now);
+            }
+            Err(reason) => {
+                self.conditional.remove(&seat);
+                self.broadcast(&ServerMessage::PremoveDiscarded {
+                    board,
+                    player,
+                    reason,
+                    message: reason.to_string(),
+                });
+            }
+        }
+    }
+
+    /// The reply the player in `seat` lined up for the move their opponent
+    /// just played, if they have one, keeping only the lines that went that
+    /// way.
+    fn conditional_reply(&mut self, seat: Seat) -> Option<UciMove> {
+        let lines = self.conditional.remove(&seat)?;
+        let board = &self.boards[seat.board];
+        let mut reply = None;
+        let rest: Vec<Vec<UciMove>> = lines
+            .into_iter()
+            .filter(|line| board.last_move_is(line[0]))
+            .filter_map(|line| {
+                reply = Some(line[1]);
+                Some(line[2..].to_vec()).filter(|rest| !rest.is_empty())
+            })
+            .collect();
+        if !rest.is_empty() {
+            self.conditional.insert(seat, rest);
+        }
+        reply
+    }
+
+    /// Check that the player in `seat` can line up `lines` of conditional
+    /// moves: that it's a correspondence game or one without a clock, that
+    /// it's their opponent's turn, and that every line can be played out from
+    /// here and agrees with the others on what to reply to the same moves.
+    fn check_conditional(&self, seat: Seat, lines: &[Vec<UciMove>]) -> Result<(), String> {
+        let timed = self
+            .time_control
+            .is_some_and(|time_control| time_control.days_per_move.is_none());
+        if timed {
+            return Err(
+                "Conditional moves are only for correspondence games and games without a clock"
+                    .to_string(),
+            );
+        }
+        let board = &self.boards[seat.board];
+        if board.outcome().is_some() {
+            return Err(MovePieceError::GameOver.to_string());
+        }
+        if board.turn() == seat.player {
+            return Err("Conditional moves are for while your opponent is thinking".to_string());
+        }
+        for line in lines {
+            if line.is_empty() || !line.len().is_multiple_of(2) {
+                return Err("Every move in a line needs a reply".to_string());
+            }
+            let mut board = board.clone();
+            for mv in line {
+                let player = board.turn();
+                board
+                    .play_uci(player, *mv)
+                    .map_err(|e| format!("{} can't be played there: {}", mv, e))?;
+            }
+        }
+        for (idx, line) in lines.iter().enumerate() {
+            for other in lines[idx + 1..].iter() {
+                let disagree = (1..line.len().min(other.len()))
+                    .step_by(2)
+                    .find(|&reply| line[..reply] == other[..reply] && line[reply] != other[reply]);
+                if let Some(reply) = disagree {
+                    return Err(format!(
+                        "Your lines reply to {} with both {} and {}",
+                        line[reply - 1],
+                        line[reply],
+                        other[reply]
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The game on board `idx` is over. Every clock stops, nothing queued is
+    /// played, and the other board, if there is one, ends the same way for
+    /// each team.
+    fn game_over(&mut self, idx: usize, now: u64) {
+        for clock in self.clocks.iter_mut() {
+            clock.stop(now);
+        }
+        self.premoves.clear();
+        self.conditional.clear();
+        let outcome = match self.boards[idx].outcome() {
+            Some(outcome) => outcome,
+            None => return,
+        };
+        // partners play opposite colours, so the other board's winner is the
+        // other colour
+        let mirrored = match outcome {
+            Outcome::Win { winner, .. } => Outcome::Win {
+                winner: !winner,
+                reason: WinReason::OtherBoard,
+            },
+            Outcome::Draw { .. } => Outcome::Draw {
+                reason: DrawReason::OtherBoard,
+            },
+        };
+        for (other, board) in self.boards.iter_mut().enumerate() {
+            if other != idx {
+                board.end_game(mirrored);
+            }
+        }
+    }
+
+    /// End the game on board `idx` if it's reached a position the tablebases
+    /// know the result of, when the server's been asked to. A win that can't
+    /// be forced before the fifty-move rule kicks in is a draw.
+    fn adjudicate(&mut self, idx: usize) {
+        let tablebase = match &self.tablebase {
+            Some(tablebase) if self.adjudicate && self.boards.len() == 1 => tablebase,
+            _ => return,
+        };
+        let board = &self.boards[idx];
+        if board.outcome().is_some() {
+            return;
+        }
+        let player = board.turn();
+        let outcome = match tablebase.probe_wdl(board) {
+            Some(wdl @ Wdl::Win) | Some(wdl @ Wdl::Loss) => {
+                let too_slow = tablebase
+                    .probe_dtz(board)
+                    .is_some_and(|dtz| dtz.unsigned_abs() + board.halfmove_clock() > 100);
+                let winner = if wdl == Wdl::Win { player } else { !player };
+                if too_slow {
+                    Outcome::Draw {
+                        reason: DrawReason::Tablebase,
+                    }
+                } else {
+                    Outcome::Win {
+                        winner,
+                        reason: WinReason::Tablebase,
+                    }
+                }
+            }
+            Some(_) => Outcome::Draw {
+                reason: DrawReason::Tablebase,
+            },
+            None => return,
+        };
+        self.boards[idx].end_game(outcome);
+    }
+
+    /// How many moves need to be taken back to undo `player`'s last move: just
+    /// theirs if their opponent hasn't replied yet, otherwise the reply too.
+    fn takeback_plies(&self, player: Player) -> usize {
+        if self.boards[0].turn() == player {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn broadcast(&self, msg: &ServerMessage) {
+        for connection in self.connections.iter() {
+            // connections that have gone away are cleaned up elsewhere
+            let _ = connection.unbounded_send(msg.clone());
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum ClientMessage {
-    Connect,
-    MovePiece {
-        id_token: String,
-        prev_location: (Option<NonZeroU8>, Option<NonZeroU8>),
-        location: (Option<NonZeroU8>, Option<NonZeroU8>),
-    },
-    Resign {
-        id_token: String,
-    },
+impl Server {
+    /// Start a game for every pair of players the matchmaker can pair up now.
+    fn match_players(&self) {
+        let pairs = self.matchmaker.lock().unwrap().pair(now_ms());
+        for (first, second) in pairs {
+            let first_white = first_plays_white(
+                &self.accounts.recent_colors(&first.account),
+                &self.accounts.recent_colors(&second.account),
+            )
+            .unwrap_or_else(|| rand::thread_rng().gen());
+            let (white, black) = if first_white {
+                (&first, &second)
+            } else {
+                (&second, &first)
+            };
+            let settings = GameSettings {
+                variant: VariantKind::Standard,
+                time_control: first.time_control,
+                rated: true,
+            };
+            self.start_game(
+                Entrant {
+                    account: &white.account,
+                    connection: Some(&white.connection),
+                },
+                Entrant {
+                    account: &black.account,
+                    connection: Some(&black.connection),
+                },
+                settings,
+            );
+        }
+    }
+
+    /// Send `msg` to every connection signed in as `account`.
+    fn send_to_account(&self, account: &str, msg: &ServerMessage) {
+        for connection in self.connections.lock().unwrap().iter() {
+            let signed_in = connection
+                .account
+                .lock()
+                .unwrap()
+                .as_deref()
+                .is_some_and(|signed_in| signed_in.eq_ignore_ascii_case(account));
+            if signed_in {
+                let _ = connection.tx.unbounded_send(msg.clone());
+            }
+        }
+    }
+
+    /// Let the challenger, and the player it was made to if there is one,
+    /// know a challenge is gone.
+    fn challenge_gone(&self, challenge: &Challenge, msg: ServerMessage) {
+        if let Some(to) = &challenge.info.to {
+            self.send_to_account(to, &msg);
+        }
+        self.tell_challenger(challenge, msg);
+    }
+
+    /// Send `msg` to where a challenge was made from, or to wherever the
+    /// challenger is signed in if it was made over HTTP.
+    fn tell_challenger(&self, challenge: &Challenge, msg: ServerMessage) {
+        match &challenge.connection {
+            Some(connection) => {
+                let _ = connection.tx.unbounded_send(msg);
+            }
+            None => self.send_to_account(&challenge.info.challenger, &msg),
+        }
+    }
+
+    /// Take away the challenges that have lapsed.
+    fn expire_challenges(&self) {
+        let expired = self.challenges.lock().unwrap().expire(now_ms());
+        for challenge in expired {
+            let id = challenge.info.id.clone();
+            self.challenge_gone(&challenge, ServerMessage::ChallengeExpired { id });
+        }
+    }
+
+    /// Put up a challenge from `challenger`, to `to` or anyone, letting the
+    /// player it's made to know.
+    fn create_challenge(
+        &self,
+        challenger: String,
+        connection: Option<&Connection>,
+        to: Option<&str>,
+        color: ChallengeColor,
+        settings: GameSettings,
+    ) -> ServerMessage {
+        let to = match to {
+            Some(to) => match self.accounts.registered_name(to) {
+                Some(to) if to == challenger => {
+                    return ServerMessage::Rejected("You can't challenge yourself".to_string())
+                }
+                Some(to) => Some(to),
+                None => return ServerMessage::account_rejected(AccountError::NoSuchAccount),
+            },
+            None => None,
+        };
+        if settings.variant == VariantKind::Bughouse {
+            return ServerMessage::Rejected("Bughouse needs four players".to_string());
+        }
+        if settings.rated && settings.variant != VariantKind::Standard {
+            return ServerMessage::Rejected("Only standard chess is rated".to_string());
+        }
+        if let Some(msg) = check_time_control(settings.time_control) {
+            return msg;
+        }
+        let info = ChallengeInfo {
+            id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            challenger_bot: self.accounts.is_bot(&challenger),
+            challenger,
+            to,
+            time_control: settings.time_control,
+            color,
+            variant: settings.variant,
+            rated: settings.rated,
+            expires: now_ms() + CHALLENGE_LIFETIME_MS,
+        };
+        if let Some(to) = &info.to {
+            self.send_to_account(to, &ServerMessage::Challenged(info.clone()));
+        }
+        self.challenges.lock().unwrap().add(Challenge {
+            info: info.clone(),
+            connection: connection.cloned(),
+        });
+        ServerMessage::ChallengeCreated(info)
+    }
+
+    /// Accept a challenge as `account`, from `connection`, or from wherever
+    /// they're signed in if it's accepted over HTTP, starting the game.
+    /// Returns why not if it can't be.
+    fn accept_challenge(
+        &self,
+        account: &str,
+        connection: Option<&Connection>,
+        id: &str,
+    ) -> Option<ServerMessage> {
+        let challenge = {
+            let mut challenges = self.challenges.lock().unwrap();
+            match challenges.get(id) {
+                Some(challenge) if challenge.info.challenger == account => {
+                    return Some(ServerMessage::Rejected(
+                        "You can't accept your own challenge".to_string(),
+                    ))
+                }
+                Some(challenge) if challenge.open_to(account) => challenges.take(id)?,
+                _ => {
+                    return Some(ServerMessage::Rejected(format!(
+                        "There's no challenge {}",
+                        id
+                    )))
+                }
+            }
+        };
+        let challenger_white = match challenge.info.color {
+            ChallengeColor::White => true,
+            ChallengeColor::Black => false,
+            ChallengeColor::Random => rand::thread_rng().gen(),
+        };
+        let challenger_connection = challenge
+            .connection
+            .clone()
+            .or_else(|| self.connection_of(&challenge.info.challenger));
+        let challenger = Entrant {
+            account: &challenge.info.challenger,
+            connection: challenger_connection.as_ref(),
+        };
+        let accepter_connection = connection.cloned().or_else(|| self.connection_of(account));
+        let accepter = Entrant {
+            account,
+            connection: accepter_connection.as_ref(),
+        };
+        let (white, black) = if challenger_white {
+            (challenger, accepter)
+        } else {
+            (accepter, challenger)
+        };
+        let settings = GameSettings {
+            variant: challenge.info.variant,
+            time_control: challenge.info.time_control,
+            rated: challenge.info.rated,
+        };
+        self.start_game(white, black, settings);
+        None
+    }
+
+    /// Turn down a challenge made to `account`.
+    fn decline_challenge(&self, account: &str, id: &str) -> ServerMessage {
+        let challenge = {
+            let mut challenges = self.challenges.lock().unwrap();
+            let made_to_account = challenges
+                .get(id)
+                .is_some_and(|challenge| challenge.info.to.is_some() && challenge.open_to(account));
+            if !made_to_account {
+                return ServerMessage::Rejected(format!("There's no challenge {} to you", id));
+            }
+            challenges.take(id)
+        };
+        let msg = ServerMessage::ChallengeDeclined { id: id.to_string() };
+        if let Some(challenge) = challenge {
+            self.tell_challenger(&challenge, msg.clone());
+        }
+        msg
+    }
+
+    /// Call off a challenge `account` made.
+    fn cancel_challenge(&self, account: &str, id: &str) -> ServerMessage {
+        let challenge = {
+            let mut challenges = self.challenges.lock().unwrap();
+            let made_by_account = challenges
+                .get(id)
+                .is_some_and(|challenge| challenge.info.challenger == account);
+            if !made_by_account {
+                return ServerMessage::Rejected(format!("You have no challenge {}", id));
+            }
+            challenges.take(id)
+        };
+        match challenge {
+            Some(challenge) => {
+                let msg = ServerMessage::ChallengeCancelled { id: id.to_string() };
+                if let Some(to) = &challenge.info.to {
+                    self.send_to_account(to, &msg);
+                }
+                msg
+            }
+            None => ServerMessage::Rejected(format!("You have no challenge {}", id)),
+        }
+    }
+
+    /// Start a game of its own between two players, sitting them down and
+    /// moving their connections over to it, returning its id.
+    fn start_game(&self, white: Entrant, black: Entrant, settings: GameSettings) -> Option<String> {
+        let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let path = self.games_dir.join(format!("{}.jsonl", id));
+        let game = fs::create_dir_all(&self.games_dir)
+            .and_then(|_| event_log::restore(&path, self.new_game.clone()));
+        let mut game = match game {
+            Ok(game) => game,
+            Err(e) => {
+                error!(
+                    "Failed to start a game between {} and {}: {}",
+                    white.account, black.account, e
+                );
+                return None;
+            }
+        };
+        info!(
+            "Starting game {} between {} and {}",
+            id, white.account, black.account
+        );
+
+        // sitting down goes through the log like anything else, so the game
+        // can be replayed
+        let category = Category::of(settings.time_control);
+        let mut white_id = None;
+        for (entrant, color, opponent) in [
+            (&white, Player::White, &black),
+            (&black, Player::Black, &white),
+        ] {
+            let opponent_rating = self
+                .accounts
+                .rating(opponent.account, category)
+                .unwrap_or_default();
+            let mut messages = vec![ServerMessage::Matched {
+                game: id.clone(),
+                color,
+                opponent: opponent.account.to_string(),
+                opponent_rating: opponent_rating.rating.round() as i32,
+                opponent_bot: self.accounts.is_bot(opponent.account),
+                time_control: settings.time_control,
+            }];
+            messages.extend(game.process_message(ClientMessage::Join {
+                session_token: String::new(),
+                account: Some(entrant.account.to_string()),
+            }));
+            for message in messages {
+                if let ServerMessage::Welcome { id_token, .. } = &message {
+                    white_id = white_id.or_else(|| Some(id_token.clone()));
+                }
+                if let Some(connection) = entrant.connection {
+                    let _ = connection.tx.unbounded_send(message);
+                }
+            }
+        }
+        if let Some(id_token) = white_id {
+            let messages = game.process_message(ClientMessage::NewGame {
+                id_token,
+                mode: GameMode::Standard,
+                variant: settings.variant,
+                time_control: settings.time_control,
+                casual: !settings.rated,
+            });
+            for connection in [white.connection, black.connection].iter().flatten() {
+                for message in messages.iter() {
+                    let _ = connection.tx.unbounded_send(message.clone());
+                }
+            }
+        }
+        self.accounts.record_colors(white.account, black.account);
+
+        let game = Arc::new(Mutex::new(game));
+        self.games.lock().unwrap().insert(id.clone(), game.clone());
+        for connection in [white.connection, black.connection].iter().flatten() {
+            move_to(connection, &game);
+        }
+        Some(id)
+    }
+
+    /// Move `connection` over to the game with `id`, to watch it or get back
+    /// to a seat in it.
+    fn open_game(&self, connection: &Connection, id: &str) -> ServerMessage {
+        let game = match self.games.lock().unwrap().get(id) {
+            Some(game) => game.clone(),
+            None => return ServerMessage::Rejected(format!("There's no game {}", id)),
+        };
+        move_to(connection, &game);
+        let state = game.lock().unwrap().state();
+        state
+    }
+
+    /// The position an analysis is asked for: `fen`, or else the position of
+    /// `board` in the game `game_id`, which may have finished and been
+    /// forgotten, or in `current` if there's no id.
+    fn analysis_position(
+        &self,
+        current: &SharedGame,
+        fen: Option<&str>,
+        game_id: Option<&str>,
+        board: usize,
+    ) -> Result<Board, String> {
+        let id = match (fen, game_id) {
+            (None, Some(id)) => id,
+            _ => return current.lock().unwrap().analysis_position(fen, board),
+        };
+        let going = self.games.lock().unwrap().get(id).cloned();
+        if let Some(game) = going {
+            return game.lock().unwrap().analysis_position(None, board);
+        }
+        match http::load_game(self, id)? {
+            Some(game) => game.analysis_position(None, board),
+            None => Err(format!("There's no game {}", id)),
+        }
+    }
+
+    /// Forget the games that are over and that nobody's in any more.
+    fn forget_finished_games(&self) {
+        self.games.lock().unwrap().retain(|_, game| {
+            // the connections in a game each hold on to it
+            Arc::strong_count(game) > 1
+                || game.lock().unwrap().outcomes().iter().any(Option::is_none)
+        });
+    }
+
+    /// End the games where someone's time ran out while nobody was sending
+    /// anything to them, as happens when a correspondence player doesn't come
+    /// back in time.
+    fn forfeit_overdue_games(&self) {
+        let now = now_ms();
+        let games: Vec<SharedGame> = self.games.lock().unwrap().values().cloned().collect();
+        for game in games {
+            let overdue = game
+                .lock()
+                .unwrap()
+                .clocks
+                .iter()
+                .any(|clock| clock.flagged(now).is_some());
+            if overdue {
+                self.play(&game, ClientMessage::Internal(InternalMessage::CheckClocks));
+            }
+        }
+    }
+
+    /// Sit the built-in opponent down in the next free seat of `game`,
+    /// returning why not if there isn't one.
+    fn add_computer(&self, game: &SharedGame, depth: Option<u32>) -> Option<ServerMessage> {
+        let no_seat = || Some(ServerMessage::Rejected("There's no seat free".to_string()));
+        let mut game = game.lock().unwrap();
+        if game.ids.len() >= game.seats() {
+            return no_seat();
+        }
+        // it sits down the way anyone else does, so the game can be replayed
+        let id_token = game
+            .process_message(ClientMessage::Connect)
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Welcome { id_token, .. } => Some(id_token),
+                _ => None,
+            });
+        let id_token = match id_token {
+            Some(id_token) => id_token,
+            None => return no_seat(),
+        };
+        let seat = game.ids[&id_token];
+        let depth = depth.unwrap_or(COMPUTER_DEPTH).clamp(1, MAX_COMPUTER_DEPTH);
+        game.computers.insert(
+            id_token,
+            Computer {
+                depth,
+                thinking: false,
+            },
+        );
+        game.broadcast(&ServerMessage::ComputerJoined {
+            board: seat.board,
+            player: seat.player,
+        });
+        None
+    }
+
+    /// Have the built-in opponent work out its move, away from the runtime,
+    /// on any board of `game` where it's its turn, and play it.
+    fn computer_turn(self: &Arc<Self>, game: &SharedGame) {
+        let (turns, book) = {
+            let mut game = game.lock().unwrap();
+            let game = &mut *game;
+            let ids = &game.ids;
+            game.computers
+                .retain(|id_token, _| ids.contains_key(id_token));
+            let waiting = game.waiting_for_players();
+            let mut turns = vec![];
+            for (id_token, computer) in game.computers.iter_mut() {
+                let seat = game.ids[id_token];
+                let board = &game.boards[seat.board];
+                if waiting
+                    || computer.thinking
+                    || board.outcome().is_some()
+                    || board.turn() != seat.player
+                {
+                    continue;
+                }
+                computer.thinking = true;
+                turns.push((id_token.clone(), seat.board, board.clone(), computer.depth));
+            }
+            (turns, game.book.clone())
+        };
+        for (id_token, idx, board, depth) in turns {
+            let (server, game, book) = (self.clone(), game.clone(), book.clone());
+            tokio::task::spawn_blocking(move || {
+                let mv = board.engine_move(book.as_deref(), depth);
+                let current = {
+                    let mut game = game.lock().unwrap();
+                    if let Some(computer) = game.computers.get_mut(&id_token) {
+                        computer.thinking = false;
+                    }
+                    // a takeback or a new game can get there first
+                    game.boards.get(idx) == Some(&board)
+                };
+                if let Some(mv) = mv.filter(|_| current) {
+                    server.play(&game, bots::client_message(mv, id_token));
+                    server.adjudicate(&game);
+                    // the computer might be playing itself
+                    server.computer_turn(&game);
+                }
+            });
+        }
+    }
+
+    /// Read the tables the position in `game` needs away from the runtime,
+    /// when the server's been asked to end games the tablebases know the
+    /// result of, and then end it if they do.
+    fn adjudicate(self: &Arc<Self>, game: &SharedGame) {
+        let (tablebase, board) = {
+            let mut game = game.lock().unwrap();
+            let tablebase = match &game.tablebase {
+                Some(tablebase) if game.adjudicate && game.boards.len() == 1 && !game.probing => {
+                    tablebase.clone()
+                }
+                _ => return,
+            };
+            let board = &game.boards[0];
+            if board.outcome().is_some() || !tablebase.covers(board) {
+                return;
+            }
+            let board = board.clone();
+            game.probing = true;
+            (tablebase, board)
+        };
+        let (server, game) = (self.clone(), game.clone());
+        tokio::task::spawn_blocking(move || {
+            // the tables stay loaded, so looking the position up again with
+            // the game held doesn't touch the disk
+            let known = tablebase.probe_wdl(&board).is_some();
+            tablebase.probe_dtz(&board);
+            game.lock().unwrap().probing = false;
+            if known {
+                let plies = board.history().len();
+                let adjudicate = InternalMessage::Adjudicate { board: 0, plies };
+                server.play(&game, ClientMessage::Internal(adjudicate));
+            }
+        });
+    }
+
+    /// Look up every game that's reached a position the tablebases know, for
+    /// the moves that didn't come in over a connection.
+    fn adjudications(self: &Arc<Self>) {
+        let games: Vec<SharedGame> = self.games.lock().unwrap().values().cloned().collect();
+        for game in games {
+            self.adjudicate(&game);
+        }
+    }
+
+    /// Give the built-in opponent its turn in every game it's playing, for
+    /// the moves that didn't come in over a connection.
+    fn computer_turns(self: &Arc<Self>) {
+        let games: Vec<SharedGame> = self.games.lock().unwrap().values().cloned().collect();
+        for game in games {
+            self.computer_turn(&game);
+        }
+    }
+
+    /// Pick back up the correspondence and tournament games that were still
+    /// going when the server last stopped, from their logs. Their clocks kept
+    /// running in the meantime, so a long stop can flag a tournament game, and
+    /// the organiser can settle it with `ReportResult`. Any other game would
+    /// have run out of time long since, or has nobody waiting on its result.
+    fn restore_games(&self) -> Result<(), Error> {
+        let entries = match fs::read_dir(&self.games_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id) if path.extension().is_some_and(|ext| ext == "jsonl") => id.to_string(),
+                _ => continue,
+            };
+            let events = match event_log::load(&path) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Failed to read game {}: {}", id, e);
+                    continue;
+                }
+            };
+            let correspondence = events
+                .iter()
+                .rev()
+                .find_map(|logged| match &logged.event {
+                    GameEvent::Client(ClientMessage::NewGame { time_control, .. }) => {
+                        Some(*time_control)
+                    }
+                    _ => None,
+                })
+                .flatten()
+                .is_some_and(|time_control| time_control.days_per_move.is_some());
+            if !correspondence && !self.tournaments.waiting_on(&id) {
+                continue;
+            }
+            let game = match event_log::restore(&path, self.new_game.clone()) {
+                Ok(game) => game,
+                Err(e) => {
+                    error!("Failed to restore game {}: {}", id, e);
+                    continue;
+                }
+            };
+            if game.outcomes().iter().any(Option::is_none) {
+                info!("Picking up game {}", id);
+                let game = Arc::new(Mutex::new(game));
+                self.games.lock().unwrap().insert(id, game);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a line of chat from the account `connection` is signed in to, to
+    /// the room it belongs in: the players' if the account is playing the
+    /// game, or else the spectators'. Anyone who has muted the account
+    /// doesn't get it.
+    fn chat(&self, connection: &Connection, game_id: &str, text: &str) -> Option<ServerMessage> {
+        let account = match connection.account.lock().unwrap().clone() {
+            Some(account) => account,
+            None => return Some(ServerMessage::account_rejected(AccountError::NotSignedIn)),
+        };
+        let game = match self.games.lock().unwrap().get(game_id) {
+            Some(game) => game.clone(),
+            None => return Some(ServerMessage::chat_rejected(ChatError::NoSuchGame)),
+        };
+        if !Arc::ptr_eq(&connection.game.lock().unwrap(), &game) {
+            return Some(ServerMessage::chat_rejected(ChatError::NotInGame));
+        }
+        let text = match chat::clean(text) {
+            Ok(text) => text,
+            Err(e) => return Some(ServerMessage::chat_rejected(e)),
+        };
+        let now = now_ms();
+        if !self.chat.lock().unwrap().allow(&account, now) {
+            return Some(ServerMessage::chat_rejected(ChatError::TooFast));
+        }
+
+        let players: Vec<String> = game.lock().unwrap().accounts.values().cloned().collect();
+        let playing = |account: Option<&str>| {
+            account.is_some_and(|account| {
+                players
+                    .iter()
+                    .any(|player| player.eq_ignore_ascii_case(account))
+            })
+        };
+        let room = if playing(Some(&account)) {
+            ChatRoom::Players
+        } else {
+            ChatRoom::Spectators
+        };
+        let msg = ServerMessage::Chat(ChatLine {
+            game_id: game_id.to_string(),
+            room,
+            bot: self.accounts.is_bot(&account),
+            username: account.clone(),
+            text,
+            sent: now,
+        });
+        for other in self.connections.lock().unwrap().iter() {
+            if !Arc::ptr_eq(&other.game.lock().unwrap(), &game) {
+                continue;
+            }
+            let recipient = other.account.lock().unwrap().clone();
+            let in_room = playing(recipient.as_deref()) == (room == ChatRoom::Players);
+            let muted = recipient
+                .as_deref()
+                .is_some_and(|recipient| self.accounts.has_muted(recipient, &account));
+            if in_room && !muted {
+                let _ = other.tx.unbounded_send(msg.clone());
+            }
+        }
+        None
+    }
+
+    /// Handle a message for `game` from one of the connections in it, and
+    /// deal with any of its boards that finish because of it: rating them,
+    /// putting them in the players' histories and going back over them.
+    /// Returns the messages to send back to whoever sent it.
+    fn play(&self, game: &SharedGame, client_msg: ClientMessage) -> Vec<ServerMessage> {
+        let (messages, finished, rated) = {
+            let mut game = game.lock().unwrap();
+            let before = game.outcomes();
+            let messages = game.process_message(client_msg);
+            let finished = game.finished_since(&before);
+            let rated: Vec<RatedGame> = finished
+                .iter()
+                .filter_map(|(idx, _)| game.rated_game(*idx))
+                .collect();
+            (messages, finished, rated)
+        };
+        // ratings aren't part of the game, so they're never replayed and are
+        // only ever changed here, once, as the game ends
+        for rated in rated {
+            let changes = self.accounts.rate_game(
+                &rated.white,
+                &rated.black,
+                rated.white_score,
+                rated.category,
+            );
+            if let Some((white, black)) = changes {
+                let msg = ServerMessage::RatingsChanged {
+                    category: rated.category,
+                    white,
+                    black,
+                };
+                game.lock().unwrap().broadcast(&msg);
+            }
+        }
+        let game_id = if finished.is_empty() {
+            None
+        } else {
+            self.game_id(game)
+        };
+        if let Some(game_id) = game_id.filter(|id| id != DEFAULT_GAME) {
+            self.game_over(game, &game_id, &finished);
+        }
+        for (idx, board) in finished {
+            let game = game.clone();
+            tokio::task::spawn_blocking(move || {
+                let annotation = board.annotate(ANNOTATION_DEPTH);
+                let msg = ServerMessage::GameAnnotated {
+                    board: idx,
+                    annotation,
+                };
+                game.lock().unwrap().broadcast(&msg);
+            });
+        }
+        messages
+    }
+
+    /// The id of `game`, if the server started it.
+    fn game_id(&self, game: &SharedGame) -> Option<String> {
+        self.games
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, started)| Arc::ptr_eq(started, game))
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Put the games that just finished on boards of the game with `id` in
+    /// their players' histories, and count them in the tournament they were
+    /// played for, if there is one.
+    fn game_over(&self, game: &SharedGame, id: &str, finished: &[(usize, Board)]) {
+        let now = now_ms();
+        let played: Vec<PlayedGame> = {
+            let game = game.lock().unwrap();
+            finished
+                .iter()
+                .filter_map(|(idx, board)| {
+                    Some(PlayedGame {
+                        game: id.to_string(),
+                        white: game.account_at(*idx, Player::White)?,
+                        black: game.account_at(*idx, Player::Black)?,
+                        variant: board.variant(),
+                        time_control: game.time_control,
+                        outcome: board.outcome()?,
+                        ended: now,
+                    })
+                })
+                .collect()
+        };
+        for played in played {
+            self.accounts.record_game(played);
+        }
+        // tournament games are only ever played on the one board
+        if let Some(outcome) = finished.first().and_then(|(_, board)| board.outcome()) {
+            let result = PairingResult::of(&outcome);
+            if let Some(tournament) = self.tournaments.game_over(id, result) {
+                self.tournament_changed(&tournament);
+            }
+        }
+    }
+
+    /// Send the tournament as it is now to everyone playing in it or running
+    /// it.
+    fn tournament_changed(&self, tournament: &Tournament) {
+        if tournament.finished() {
+            info!("Tournament {} is over", tournament.id);
+        }
+        let msg = ServerMessage::Tournament(tournament.clone());
+        self.send_to_account(&tournament.organizer, &msg);
+        for player in tournament.players.iter() {
+            if !player.username.eq_ignore_ascii_case(&tournament.organizer) {
+                self.send_to_account(&player.username, &msg);
+            }
+        }
+    }
+
+    /// Set up a tournament run by `organizer`.
+    fn create_tournament(
+        &self,
+        organizer: String,
+        name: String,
+        format: TournamentFormat,
+        settings: GameSettings,
+    ) -> ServerMessage {
+        if let Some(msg) = check_time_control(settings.time_control) {
+            return msg;
+        }
+        let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let created = self.tournaments.create(
+            id,
+            name,
+            organizer,
+            format,
+            settings.time_control,
+            settings.rated,
+        );
+        match created {
+            Ok(tournament) => {
+                info!(
+                    "{} created tournament {}",
+                    tournament.organizer, tournament.id
+                );
+                ServerMessage::Tournament(tournament)
+            }
+            Err(e) => ServerMessage::tournament_rejected(e),
+        }
+    }
+
+    /// Enter `account` in a tournament, seeded by their rating in the pool
+    /// its games are rated in.
+    fn join_tournament(&self, account: &str, id: &str) -> Option<ServerMessage> {
+        let tournament = match self.tournaments.get(id) {
+            Ok(tournament) => tournament,
+            Err(e) => return Some(ServerMessage::tournament_rejected(e)),
+        };
+        let category = Category::of(tournament.time_control);
+        let rating = self.accounts.rating(account, category).unwrap_or_default();
+        match self
+            .tournaments
+            .join(id, account, rating.rating.round() as i32)
+        {
+            Ok(tournament) => {
+                self.tournament_changed(&tournament);
+                None
+            }
+            Err(e) => Some(ServerMessage::tournament_rejected(e)),
+        }
+    }
+
+    /// Pair a tournament's next round, for its organiser, and start a game
+    /// for every pairing. Players who aren't connected are sat down anyway,
+    /// and can come back to their game with `OpenGame`.
+    fn pair_round(&self, organizer: &str, id: &str) -> Option<ServerMessage> {
+        let tournament = match self.tournaments.pair_round(id, organizer) {
+            Ok(tournament) => tournament,
+            Err(e) => return Some(ServerMessage::tournament_rejected(e)),
+        };
+        let round = tournament.rounds.len() - 1;
+        info!("Pairing round {} of tournament {}", round + 1, id);
+        let settings = GameSettings {
+            variant: VariantKind::Standard,
+            time_control: tournament.time_control,
+            rated: tournament.rated,
+        };
+        for (board, pairing) in tournament.rounds[round].pairings.iter().enumerate() {
+            let white = self.connection_of(&pairing.white);
+            let black = self.connection_of(&pairing.black);
+            let game = self.start_game(
+                Entrant {
+                    account: &pairing.white,
+                    connection: white.as_ref(),
+                },
+                Entrant {
+                    account: &pairing.black,
+                    connection: black.as_ref(),
+                },
+                settings,
+            );
+            if let Some(game) = game {
+                self.tournaments.set_game(id, round, board, game);
+            }
+        }
+        if let Ok(tournament) = self.tournaments.get(id) {
+            self.tournament_changed(&tournament);
+        }
+        None
+    }
+
+    /// Forget a connection that's gone away, taking it out of the queue and
+    /// calling off the challenges made from it.
+    fn disconnect(&self, connection: &Connection) {
+        self.matchmaker.lock().unwrap().cancel(connection);
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|other| !other.tx.same_receiver(&connection.tx));
+        let withdrawn = self.challenges.lock().unwrap().take_from(connection);
+        for challenge in withdrawn {
+            let id = challenge.info.id.clone();
+            self.challenge_gone(&challenge, ServerMessage::ChallengeCancelled { id });
+        }
+    }
+
+    /// A connection signed in as `account`, if there is one.
+    fn connection_of(&self, account: &str) -> Option<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|connection| {
+                connection
+                    .account
+                    .lock()
+                    .unwrap()
+                    .as_deref()
+                    .is_some_and(|signed_in| signed_in.eq_ignore_ascii_case(account))
+            })
+            .cloned()
+    }
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct GameState {
-    board: Board,
-    turn: Player,
-    ids: HashMap<String, Player>,
-    connections: Vec<UnboundedSender<ServerMessage>>,
+/// Move `connection` from whatever game it's in over to `game`.
+fn move_to(connection: &Connection, game: &SharedGame) {
+    let tx = &connection.tx;
+    let mut current = connection.game.lock().unwrap();
+    current
+        .lock()
+        .unwrap()
+        .connections
+        .retain(|connection| !connection.same_receiver(tx));
+    game.lock().unwrap().connections.push(tx.clone());
+    *current = game.clone();
 }
 
 #[tokio::main]
@@ -52,26 +2523,144 @@ async fn main() -> Result<(), Error> {
         .with_thread_names(true)
         .with_max_level(tracing::Level::DEBUG)
         .init();
-    let addr = env::args()
+    let arg = env::args()
         .nth(1)
         .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    if arg == "replay" {
+        let path = env::args().nth(2).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "usage: chess-server replay <log>")
+        })?;
+        return replay(Path::new(&path));
+    }
+    let addr = arg;
+
+    let log_path = env::var("CHESS_GAME_LOG").unwrap_or_else(|_| "game_log.jsonl".to_string());
+    let new_game = new_game_state()?;
+    let game_state = event_log::restore(Path::new(&log_path), new_game.clone())?;
+    info!("Recording game events to: {}", log_path);
+    let accounts_path = env::var("CHESS_ACCOUNTS").unwrap_or_else(|_| "accounts.json".to_string());
+    let rating_system = match env::var("CHESS_RATING_SYSTEM").as_deref() {
+        Ok("elo") => RatingSystem::Elo,
+        Ok("glicko2") | Err(_) => RatingSystem::Glicko2,
+        Ok(other) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown rating system {}, use glicko2 or elo", other),
+            ))
+        }
+    };
+    let accounts = Accounts::open(Path::new(&accounts_path), rating_system)?;
+    info!(
+        "Keeping accounts in: {}, rated with {:?}",
+        accounts_path, rating_system
+    );
+    let tournaments_path =
+        env::var("CHESS_TOURNAMENTS").unwrap_or_else(|_| "tournaments.json".to_string());
+    let tournaments = Tournaments::open(Path::new(&tournaments_path))?;
+    info!("Keeping tournaments in: {}", tournaments_path);
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
 
-    let game_state = Arc::new(Mutex::new(GameState::default()));
+    let game_state = Arc::new(Mutex::new(game_state));
+    let mut games = HashMap::new();
+    games.insert(DEFAULT_GAME.to_string(), game_state.clone());
+    let server = Arc::new(Server {
+        accounts,
+        matchmaker: Mutex::new(Matchmaker::default()),
+        challenges: Mutex::new(Challenges::default()),
+        tournaments,
+        games: Mutex::new(games),
+        chat: Mutex::new(ChatLimiter::default()),
+        connections: Mutex::new(vec![]),
+        new_game,
+        games_dir: PathBuf::from(env::var_os("CHESS_GAMES_DIR").unwrap_or_else(|| "games".into())),
+    });
+    server.restore_games()?;
+
+    // the rating windows widen as players wait, so pairings can become
+    // possible without anyone new joining the queue, challenges lapse,
+    // clocks run out in games nobody's moving in, and the computer answers
+    // moves made over HTTP and the tablebases end the games they finish
+    let matching = server.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            matching.match_players();
+            matching.expire_challenges();
+            matching.forfeit_overdue_games();
+            matching.computer_turns();
+            matching.adjudications();
+            matching.forget_finished_games();
+        }
+    });
 
     while let Ok((stream, _)) = listener.accept().await {
         let game_state = game_state.clone();
-        tokio::spawn(async move { accept_connection(stream, game_state) }.await);
+        tokio::spawn(accept_connection(stream, game_state, server.clone()));
     }
 
+    event_log::flush();
     Ok(())
 }
 
-async fn accept_connection(stream: TcpStream, game_state: Arc<Mutex<GameState>>) {
+/// A game that hasn't started yet, with the opening book and endgame
+/// tablebases the environment points to. Replays have to start from the same
+/// settings, since tablebase adjudication can end a game.
+fn new_game_state() -> Result<GameState, Error> {
+    let mut game_state = GameState::default();
+    if let Ok(book_path) = env::var("CHESS_BOOK") {
+        game_state.book = Some(Arc::new(Book::open(Path::new(&book_path))?));
+        info!("Playing openings from: {}", book_path);
+    }
+    if let Some(dirs) = env::var_os("CHESS_SYZYGY") {
+        let tablebase = Tablebase::open(env::split_paths(&dirs).collect())?;
+        info!(
+            "Probing endgame tablebases of up to {} pieces",
+            tablebase.max_pieces()
+        );
+        game_state.tablebase = Some(Arc::new(tablebase));
+        game_state.adjudicate = env::var("CHESS_SYZYGY_ADJUDICATE").is_ok();
+    }
+    Ok(game_state)
+}
+
+/// Re-apply a game log to a fresh board and check that it ends up in the same
+/// position the log recorded.
+fn replay(path: &Path) -> Result<(), Error> {
+    let events = event_log::load(path)?;
+    match event_log::replay(&events, new_game_state()?) {
+        Ok(game_state) => {
+            info!(
+                "Replayed {} events from {}, final position verified",
+                events.len(),
+                path.display()
+            );
+            for board in game_state.boards.iter() {
+                println!("{:#?}", board);
+                // Chess960 castling rights are clearer with every rook's file
+                match game_state.mode {
+                    GameMode::Chess960 { .. } => println!("{}", board.to_shredder_fen()),
+                    _ => println!("{}", board.to_fen()),
+                }
+            }
+            Ok(())
+        }
+        Err(e) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Replay of {} diverged: {}", path.display(), e),
+        )),
+    }
+}
+
+async fn accept_connection(stream: TcpStream, game_state: SharedGame, server: Arc<Server>) {
+    if http::is_api_request(&stream).await {
+        http::serve(stream, server).await;
+        return;
+    }
     let addr = stream
         .peer_addr()
         .expect("connected streams should have a peer address");
@@ -84,94 +2673,425 @@ async fn accept_connection(stream: TcpStream, game_state: Arc<Mutex<GameState>>)
     info!("New WebSocket connection: {}", addr);
 
     let (tx, rx) = unbounded();
-    {
-        let mut game_state = game_state.lock().unwrap();
-        game_state.connections.push(tx.clone());
-    }
+    game_state.lock().unwrap().connections.push(tx.clone());
+    let connection = Connection {
+        tx: tx.clone(),
+        game: Arc::new(Mutex::new(game_state)),
+        account: Arc::new(Mutex::new(None)),
+    };
+    server.connections.lock().unwrap().push(connection.clone());
+    // set to stop the analysis this connection has running, if it has one
+    let analysis = Arc::new(Mutex::new(None::<Arc<AtomicBool>>));
     let (write, read) = ws_stream.split();
+    let handler_analysis = analysis.clone();
+    let (handler_connection, handler_server) = (connection.clone(), server.clone());
     let msg_handler = read.try_for_each(move |client_msg| {
-        debug!("Found client message: {:?}", &client_msg);
+        let (connection, server) = (&handler_connection, &handler_server);
+        let game_state = connection.game.lock().unwrap().clone();
+        let accounts = &server.accounts;
+        // the account signed in with `session_token`, which the connection is
+        // then known by
+        let sign_in = |session_token: &str| {
+            let account = accounts.username(session_token, now_ms());
+            if account.is_some() {
+                *connection.account.lock().unwrap() = account.clone();
+            }
+            account
+        };
 
-        let client_msg: ClientMessage = match client_msg {
+        let mut client_msg: ClientMessage = match client_msg {
             Message::Text(text) => match serde_json::from_str(&text) {
                 Ok(v) => v,
                 Err(e) => {
-                    todo!("malformed client message!: {:?}", e);
+                    // e.g. a square that isn't on the board
+                    warn!("Malformed client message: {}", e);
+                    let msg = ServerMessage::UnrecognizedMessage(e.to_string());
+                    tx.unbounded_send(msg).unwrap();
+                    return future::ok(());
                 }
             },
             Message::Binary(_) => {
                 warn!("Binary message ignored!");
-                todo!();
+                let msg = ServerMessage::UnrecognizedMessage(
+                    "Binary messages aren't supported".to_string(),
+                );
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
             }
-            Message::Ping(_) => {
-                warn!("Ping message ignored!");
-                todo!();
-            }
-            Message::Pong(_) => {
-                warn!("Pong message ignored!");
-                todo!();
-            }
-            Message::Close(_) => {
-                warn!("Close message ignored!");
-                todo!();
+            // tungstenite answers pings itself, and a close ends the stream
+            // right after it, which is when the connection is cleaned up
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => {
+                return future::ok(());
             }
         };
-        debug!("Found client message: {:?}", &client_msg);
-
-        let mut messages = vec![];
-        match client_msg {
-            ClientMessage::Connect => {
-                let mut gs = game_state.lock().unwrap();
-                if gs.ids.len() == 0 {
-                    let id = "PLAYER1".to_string(); // TODO replace with random string
-                    gs.ids.insert(id.clone(), Player::White);
+        debug!("Found client message: {}", redacted(&client_msg));
 
-                    messages.push(ServerMessage::Welcome { id_token: id });
-                } else if gs.ids.len() == 1 {
-                    let id = "PLAYER2".to_string(); // TODO replace with random string
-                    gs.ids.insert(id.clone(), Player::Black);
+        if let ClientMessage::Join {
+            session_token,
+            account,
+        } = &mut client_msg
+        {
+            *account = sign_in(session_token);
+            // the token is a secret, and the log only needs to know who it was
+            session_token.clear();
+        }
 
-                    messages.push(ServerMessage::Welcome { id_token: id });
+        match &client_msg {
+            ClientMessage::Analyze {
+                fen,
+                game_id,
+                board,
+                depth,
+                multipv,
+            } => {
+                if let Some(stop) = handler_analysis.lock().unwrap().take() {
+                    stop.store(true, Ordering::Relaxed);
                 }
-
-                messages.push(ServerMessage::BoardState(gs.board.clone()));
+                let stop = Arc::new(AtomicBool::new(false));
+                *handler_analysis.lock().unwrap() = Some(stop.clone());
+                let (server, tx) = (server.clone(), tx.clone());
+                let (fen, game_id, board) = (fen.clone(), game_id.clone(), *board);
+                let (depth, multipv) = (*depth, multipv.unwrap_or(1));
+                // a finished game is read back from its log, and searching
+                // takes a while, so keep both off the threads serving games
+                tokio::task::spawn_blocking(move || {
+                    let position = server.analysis_position(
+                        &game_state,
+                        fen.as_deref(),
+                        game_id.as_deref(),
+                        board,
+                    );
+                    match position {
+                        Ok(position) => position.analyze(depth, multipv, &stop, |line| {
+                            let _ = tx.unbounded_send(ServerMessage::AnalysisInfo(line));
+                        }),
+                        Err(e) => {
+                            let _ = tx.unbounded_send(ServerMessage::Rejected(e));
+                        }
+                    }
+                });
+                return future::ok(());
             }
-            ClientMessage::MovePiece {
-                id_token,
-                prev_location: (Some(prev_l1), Some(prev_l2)),
-                location: (Some(l1), Some(l2)),
-            } => {
-                let mut gs = game_state.lock().unwrap();
-                let logic = || -> ServerMessage {
-                    let player = match gs.ids.get(&id_token) {
-                        Some(player) => player,
-                        None => return ServerMessage::UnrecognizedPlayer(id_token),
+            ClientMessage::StopAnalysis => {
+                if let Some(stop) = handler_analysis.lock().unwrap().take() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+                return future::ok(());
+            }
+            ClientMessage::Register {
+                username, password, ..
+            }
+            | ClientMessage::Login { username, password } => {
+                let (register, bot) = match client_msg {
+                    ClientMessage::Register { bot, .. } => (true, bot),
+                    _ => (false, false),
+                };
+                let (server, tx, connection) = (server.clone(), tx.clone(), connection.clone());
+                let (username, password) = (username.clone(), password.clone());
+                // hashing the password is slow on purpose, so keep it off the
+                // threads serving games
+                tokio::task::spawn_blocking(move || {
+                    let accounts = &server.accounts;
+                    let result = if register {
+                        accounts
+                            .register(&username, &password, bot, now_ms())
+                            .map(|token| (token, username))
+                    } else {
+                        accounts.login(&username, &password, now_ms())
                     };
-                    let turn = gs.turn;
-                    if *player != turn {
-                        return ServerMessage::IllegalMove(format!("It's not your turn"));
-                    }
-                    match gs.board.move_piece(turn, (prev_l1, prev_l2), (l1, l2)) {
-                        Ok(()) => {
-                            gs.turn = !gs.turn;
-                            let msg = ServerMessage::BoardState(gs.board.clone());
-                            for connection in gs.connections.iter() {
-                                connection.unbounded_send(msg.clone());
+                    let msg = match result {
+                        Ok((session_token, username)) => {
+                            *connection.account.lock().unwrap() = Some(username.clone());
+                            ServerMessage::LoggedIn {
+                                username,
+                                session_token,
                             }
-                            msg // TODO: fix this
                         }
-                        Err(e) => ServerMessage::IllegalMove(format!("You can't do that! {:?}", e)),
+                        Err(e) => ServerMessage::account_rejected(e),
+                    };
+                    let _ = tx.unbounded_send(msg);
+                });
+                return future::ok(());
+            }
+            ClientMessage::GetRating { username } => {
+                let msg = match accounts.ratings(username) {
+                    Ok((username, ratings)) => ServerMessage::Ratings { username, ratings },
+                    Err(e) => ServerMessage::account_rejected(e),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::GetLeaderboard { category, limit } => {
+                let msg = ServerMessage::Leaderboard {
+                    category: *category,
+                    entries: accounts.leaderboard(*category, limit.unwrap_or(10)),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::Seek {
+                session_token,
+                time_control,
+            } => {
+                let now = now_ms();
+                let msg = match (sign_in(session_token), check_time_control(*time_control)) {
+                    (Some(_), Some(msg)) => msg,
+                    (Some(account), None) => {
+                        let category = Category::of(*time_control);
+                        let rating = accounts.rating(&account, category).unwrap_or_default();
+                        server.matchmaker.lock().unwrap().seek(Seek {
+                            account,
+                            time_control: *time_control,
+                            rating: rating.rating,
+                            since: now,
+                            connection: connection.clone(),
+                        });
+                        ServerMessage::Seeking { category }
                     }
+                    (None, _) => ServerMessage::account_rejected(AccountError::NotSignedIn),
                 };
-                messages.push(logic());
+                tx.unbounded_send(msg).unwrap();
+                server.match_players();
+                return future::ok(());
             }
-            ClientMessage::Resign { .. } => todo!("resign"),
-            _ => todo!("Unrecognized message"),
-        };
+            ClientMessage::CancelSeek => {
+                let msg = if server.matchmaker.lock().unwrap().cancel(connection) {
+                    ServerMessage::SeekCancelled
+                } else {
+                    ServerMessage::Rejected("You're not waiting for a game".to_string())
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::Challenge {
+                session_token,
+                to,
+                time_control,
+                color,
+                variant,
+                rated,
+            } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => {
+                        let settings = GameSettings {
+                            variant: *variant,
+                            time_control: *time_control,
+                            rated: *rated,
+                        };
+                        server.create_challenge(
+                            account,
+                            Some(connection),
+                            to.as_deref(),
+                            *color,
+                            settings,
+                        )
+                    }
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::AcceptChallenge { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => server.accept_challenge(&account, Some(connection), id),
+                    None => Some(ServerMessage::account_rejected(AccountError::NotSignedIn)),
+                };
+                if let Some(msg) = msg {
+                    tx.unbounded_send(msg).unwrap();
+                }
+                return future::ok(());
+            }
+            ClientMessage::DeclineChallenge { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => server.decline_challenge(&account, id),
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::CancelChallenge { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => server.cancel_challenge(&account, id),
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::GetLobby { session_token } => {
+                let account = session_token.as_deref().and_then(sign_in);
+                let challenges = server.challenges.lock().unwrap().lobby(account.as_deref());
+                tx.unbounded_send(ServerMessage::Lobby { challenges })
+                    .unwrap();
+                return future::ok(());
+            }
+            ClientMessage::CreateTournament {
+                session_token,
+                name,
+                format,
+                time_control,
+                rated,
+            } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => {
+                        let settings = GameSettings {
+                            variant: VariantKind::Standard,
+                            time_control: *time_control,
+                            rated: *rated,
+                        };
+                        server.create_tournament(account, name.clone(), *format, settings)
+                    }
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::JoinTournament { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => server.join_tournament(&account, id),
+                    None => Some(ServerMessage::account_rejected(AccountError::NotSignedIn)),
+                };
+                if let Some(msg) = msg {
+                    tx.unbounded_send(msg).unwrap();
+                }
+                return future::ok(());
+            }
+            ClientMessage::LeaveTournament { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => match server.tournaments.leave(id, &account) {
+                        Ok(tournament) => {
+                            server.tournament_changed(&tournament);
+                            ServerMessage::Tournament(tournament)
+                        }
+                        Err(e) => ServerMessage::tournament_rejected(e),
+                    },
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::PairRound { session_token, id } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => server.pair_round(&account, id),
+                    None => Some(ServerMessage::account_rejected(AccountError::NotSignedIn)),
+                };
+                if let Some(msg) = msg {
+                    tx.unbounded_send(msg).unwrap();
+                }
+                return future::ok(());
+            }
+            ClientMessage::ReportResult {
+                session_token,
+                id,
+                round,
+                board,
+                result,
+            } => {
+                let msg = match sign_in(session_token) {
+                    Some(account) => {
+                        match server
+                            .tournaments
+                            .report(id, &account, *round, *board, *result)
+                        {
+                            Ok(tournament) => {
+                                server.tournament_changed(&tournament);
+                                None
+                            }
+                            Err(e) => Some(ServerMessage::tournament_rejected(e)),
+                        }
+                    }
+                    None => Some(ServerMessage::account_rejected(AccountError::NotSignedIn)),
+                };
+                if let Some(msg) = msg {
+                    tx.unbounded_send(msg).unwrap();
+                }
+                return future::ok(());
+            }
+            ClientMessage::GetTournaments => {
+                let tournaments = server.tournaments.list();
+                tx.unbounded_send(ServerMessage::Tournaments { tournaments })
+                    .unwrap();
+                return future::ok(());
+            }
+            ClientMessage::GetStandings { id } => {
+                let msg = match server.tournaments.get(id) {
+                    Ok(tournament) => ServerMessage::Standings {
+                        id: tournament.id.clone(),
+                        standings: tournament.standings(),
+                    },
+                    Err(e) => ServerMessage::tournament_rejected(e),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::GetCrosstable { id } => {
+                let msg = match server.tournaments.get(id) {
+                    Ok(tournament) => ServerMessage::Crosstable {
+                        id: tournament.id.clone(),
+                        crosstable: tournament.crosstable(),
+                    },
+                    Err(e) => ServerMessage::tournament_rejected(e),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::OpenGame { game } => {
+                let msg = server.open_game(connection, game);
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::AddComputer { depth } => {
+                match server.add_computer(&game_state, *depth) {
+                    Some(msg) => tx.unbounded_send(msg).unwrap(),
+                    None => server.computer_turn(&game_state),
+                }
+                return future::ok(());
+            }
+            ClientMessage::Chat { game_id, text } => {
+                if let Some(msg) = server.chat(connection, game_id, text) {
+                    tx.unbounded_send(msg).unwrap();
+                }
+                return future::ok(());
+            }
+            ClientMessage::Mute {
+                session_token,
+                username,
+            }
+            | ClientMessage::Unmute {
+                session_token,
+                username,
+            } => {
+                let mute = matches!(client_msg, ClientMessage::Mute { .. });
+                let msg = match sign_in(session_token) {
+                    Some(account) => match accounts.set_muted(&account, username, mute) {
+                        Ok(usernames) => ServerMessage::Muted { usernames },
+                        Err(e) => ServerMessage::account_rejected(e),
+                    },
+                    None => ServerMessage::account_rejected(AccountError::NotSignedIn),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            ClientMessage::Logout { session_token } => {
+                let msg = match accounts.logout(session_token) {
+                    Ok(()) => {
+                        *connection.account.lock().unwrap() = None;
+                        ServerMessage::LoggedOut
+                    }
+                    Err(e) => ServerMessage::account_rejected(e),
+                };
+                tx.unbounded_send(msg).unwrap();
+                return future::ok(());
+            }
+            _ => {}
+        }
+
+        let messages = server.play(&game_state, client_msg);
         debug!("Responding with: {:#?}", &messages);
         for message in messages {
             tx.unbounded_send(message).unwrap();
         }
+        server.adjudicate(&game_state);
+        server.computer_turn(&game_state);
 
         future::ok(())
     });
@@ -187,6 +3107,359 @@ async fn accept_connection(stream: TcpStream, game_state: Arc<Mutex<GameState>>)
 
     pin_mut!(msg_handler, receive_from_others);
     future::select(msg_handler, receive_from_others).await;
+    server.disconnect(&connection);
+    if let Some(stop) = analysis.lock().unwrap().take() {
+        stop.store(true, Ordering::Relaxed);
+    }
 
     info!("{} disconnected", addr);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_with_clock(time_control: TimeControl) -> GameState {
+        GameState {
+            clocks: vec![Clock::new(time_control)],
+            time_control: Some(time_control),
+            casual: true,
+            ..Default::default()
+        }
+    }
+
+    /// The id token a `Connect` was welcomed with.
+    fn id_token(replies: Vec<ServerMessage>) -> String {
+        replies
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Welcome { id_token, .. } => Some(id_token),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn sit(game: &mut GameState, now: u64) -> String {
+        id_token(game.apply_message(ClientMessage::Connect, now))
+    }
+
+    /// A server with nothing in it, logging the games it starts to
+    /// `games_dir`.
+    pub(crate) fn server(games_dir: &Path) -> Arc<Server> {
+        Arc::new(Server {
+            accounts: Accounts::in_memory(RatingSystem::Glicko2),
+            matchmaker: Mutex::new(Matchmaker::default()),
+            challenges: Mutex::new(Challenges::default()),
+            tournaments: Tournaments::in_memory(),
+            games: Mutex::new(HashMap::new()),
+            chat: Mutex::new(ChatLimiter::default()),
+            connections: Mutex::new(vec![]),
+            new_game: GameState::default(),
+            games_dir: games_dir.to_path_buf(),
+        })
+    }
+
+    /// A directory of its own for a test to write to.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("chess-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn move_piece(id_token: &str, mv: &str) -> ClientMessage {
+        ClientMessage::MovePiece {
+            id_token: id_token.to_string(),
+            prev_location: mv[..2].parse().unwrap(),
+            location: mv[2..].parse().unwrap(),
+            promotion: None,
+        }
+    }
+
+    #[test]
+    fn analysis_finds_games_going_and_finished() {
+        let dir = scratch_dir("analysis");
+        let server = server(&dir);
+        let current = Arc::new(Mutex::new(GameState::default()));
+
+        // sat down through the log, so it can be read back
+        let mut finished = event_log::restore(&dir.join("0f.jsonl"), GameState::default()).unwrap();
+        let white = id_token(finished.process_message(ClientMessage::Connect));
+        let black = id_token(finished.process_message(ClientMessage::Connect));
+        finished.process_message(move_piece(&white, "e2e4"));
+        finished.process_message(ClientMessage::Resign { id_token: black });
+        let mut going = GameState::default();
+        let white = sit(&mut going, 0);
+        sit(&mut going, 0);
+        going.process_message(move_piece(&white, "d2d4"));
+        let going = Arc::new(Mutex::new(going));
+        server
+            .games
+            .lock()
+            .unwrap()
+            .insert("1e".to_string(), going.clone());
+        event_log::flush();
+
+        let position = |game_id| server.analysis_position(&current, None, game_id, 0);
+        assert_eq!(position(Some("0f")).unwrap(), finished.boards[0]);
+        assert_eq!(
+            position(Some("1e")).unwrap(),
+            going.lock().unwrap().boards[0]
+        );
+        assert_eq!(position(None).unwrap(), Board::default());
+        assert!(position(Some("2d")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logged_messages_leave_out_passwords_and_session_tokens() {
+        let register = ClientMessage::Register {
+            username: "alice".to_string(),
+            password: "hunter22".to_string(),
+            bot: false,
+        };
+        let logout = ClientMessage::Logout {
+            session_token: "0123abcd".to_string(),
+        };
+        for msg in [register, logout] {
+            let logged = redacted(&msg);
+            assert!(!logged.contains("hunter22") && !logged.contains("0123abcd"));
+            assert!(logged.contains("<redacted>"), "{}", logged);
+        }
+        assert!(redacted(&ClientMessage::Connect).contains("Connect"));
+    }
+
+    #[test]
+    fn clients_cannot_send_internal_messages() {
+        for json in [
+            r#""CheckClocks""#,
+            r#"{"Adjudicate":{"board":0,"plies":0}}"#,
+            r#"{"Internal":"CheckClocks"}"#,
+        ] {
+            assert!(serde_json::from_str::<ClientMessage>(json).is_err());
+        }
+        assert!(serde_json::from_str::<ClientMessage>(r#""Connect""#).is_ok());
+    }
+
+    #[test]
+    fn takeback_gives_back_the_time_and_restarts_the_right_clock() {
+        let mut game = game_with_clock(TimeControl {
+            initial_ms: 60_000,
+            increment_ms: 0,
+            days_per_move: None,
+        });
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 5_000);
+        game.apply_message(move_piece(&black, "e7e5"), 8_000);
+        game.apply_message(move_piece(&white, "g1f3"), 10_000);
+
+        // white takes back their own move, one ply
+        game.apply_message(
+            ClientMessage::RequestTakeback {
+                id_token: white.clone(),
+            },
+            11_000,
+        );
+        game.apply_message(ClientMessage::AcceptTakeback { id_token: black }, 12_000);
+        assert_eq!(game.boards[0].history().len(), 2);
+        let clock = &game.clocks[0];
+        // white has what they had when they played Nf3, and it's their clock
+        // that's running again
+        assert_eq!(clock.left(Player::White, 12_000), 53_000);
+        assert_eq!(clock.left(Player::White, 13_000), 52_000);
+        assert_eq!(clock.left(Player::Black, 13_000), 57_000);
+    }
+
+    #[test]
+    fn takebacks_are_refused_once_the_game_is_over_or_if_it_counts() {
+        let mut game = GameState {
+            casual: true,
+            ..Default::default()
+        };
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 0);
+        game.apply_message(ClientMessage::Resign { id_token: black }, 0);
+        let replies = game.apply_message(
+            ClientMessage::RequestTakeback {
+                id_token: white.clone(),
+            },
+            0,
+        );
+        assert!(matches!(replies.as_slice(), [ServerMessage::Rejected(_)]));
+        assert_eq!(game.takeback, None);
+
+        game.casual = false;
+        game.boards = vec![Board::default()];
+        game.apply_message(move_piece(&white, "e2e4"), 0);
+        let replies = game.apply_message(ClientMessage::RequestTakeback { id_token: white }, 0);
+        assert!(matches!(replies.as_slice(), [ServerMessage::Rejected(_)]));
+        assert_eq!(game.takeback, None);
+    }
+
+    fn premove(id_token: &str, mv: &str) -> ClientMessage {
+        match move_piece(id_token, mv) {
+            ClientMessage::MovePiece {
+                id_token,
+                prev_location,
+                location,
+                promotion,
+            } => ClientMessage::Premove {
+                id_token,
+                prev_location,
+                location,
+                promotion,
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn conditional(id_token: &str, lines: &[&[&str]]) -> ClientMessage {
+        ClientMessage::SetConditionalMoves {
+            id_token: id_token.to_string(),
+            lines: lines
+                .iter()
+                .map(|line| line.iter().map(|mv| mv.parse().unwrap()).collect())
+                .collect(),
+        }
+    }
+
+    fn sans(game: &GameState) -> Vec<&str> {
+        game.boards[0]
+            .history()
+            .iter()
+            .map(|mv| mv.san.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn premoves_are_played_as_soon_as_the_opponent_moves_for_free() {
+        let mut game = game_with_clock(TimeControl {
+            initial_ms: 60_000,
+            increment_ms: 0,
+            days_per_move: None,
+        });
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 1_000);
+        let queued = game.apply_message(premove(&white, "d2d4"), 2_000);
+        assert!(matches!(
+            queued.as_slice(),
+            [ServerMessage::PremoveQueued { .. }]
+        ));
+        game.apply_message(move_piece(&black, "e7e5"), 5_000);
+
+        assert_eq!(sans(&game), vec!["e4", "e5", "d4"]);
+        assert!(game.premoves.is_empty());
+        let clock = &game.clocks[0];
+        // white spent only the second before e4
+        assert_eq!(clock.left(Player::White, 9_000), 59_000);
+        // and black's clock started again as the premove was played
+        assert_eq!(clock.left(Player::Black, 9_000), 52_000);
+    }
+
+    #[test]
+    fn premoves_that_turn_out_illegal_are_dropped() {
+        let mut game = GameState::default();
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 0);
+        game.apply_message(premove(&white, "e4e5"), 0);
+        // which the pawn's now blocked from
+        game.apply_message(move_piece(&black, "e7e5"), 0);
+        assert_eq!(sans(&game), vec!["e4", "e5"]);
+        assert!(game.premoves.is_empty());
+        assert_eq!(game.boards[0].turn(), Player::White);
+    }
+
+    #[test]
+    fn conditional_moves_answer_the_line_until_the_opponent_leaves_it() {
+        let mut game = GameState::default();
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 0);
+        let line: &[&str] = &["e7e5", "g1f3", "b8c6", "f1b5"];
+        let set = game.apply_message(conditional(&white, &[line]), 0);
+        assert!(matches!(
+            set.as_slice(),
+            [ServerMessage::ConditionalMoves { .. }]
+        ));
+
+        game.apply_message(move_piece(&black, "e7e5"), 0);
+        assert_eq!(sans(&game), vec!["e4", "e5", "Nf3"]);
+        game.apply_message(move_piece(&black, "b8c6"), 0);
+        assert_eq!(sans(&game), vec!["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+        assert!(game.conditional.is_empty());
+
+        // the same line again, but black answers Nf3 some other way
+        let mut game = GameState::default();
+        let white = sit(&mut game, 0);
+        let black = sit(&mut game, 0);
+        game.apply_message(move_piece(&white, "e2e4"), 0);
+        game.apply_message(conditional(&white, &[line]), 0);
+        game.apply_message(move_piece(&black, "e7e5"), 0);
+        game.apply_message(move_piece(&black, "g8f6"), 0);
+        assert_eq!(sans(&game), vec!["e4", "e5", "Nf3", "Nf6"]);
+        assert!(game.conditional.is_empty());
+        assert_eq!(game.boards[0].turn(), Player::White);
+    }
+
+    #[tokio::test]
+    async fn correspondence_players_lose_when_their_days_run_out() {
+        let dir = scratch_dir("forfeit");
+        let server = server(&dir);
+        let days = 24 * 60 * 60 * 1000;
+        let mut game = game_with_clock(TimeControl {
+            initial_ms: 0,
+            increment_ms: 0,
+            days_per_move: Some(1),
+        });
+        // white moved two days ago, and black never came back
+        let then = now_ms() - 2 * days;
+        let white = sit(&mut game, then);
+        sit(&mut game, then);
+        game.apply_message(move_piece(&white, "e2e4"), then);
+        let game = Arc::new(Mutex::new(game));
+        server
+            .games
+            .lock()
+            .unwrap()
+            .insert("0f".to_string(), game.clone());
+
+        server.forfeit_overdue_games();
+        assert_eq!(
+            game.lock().unwrap().boards[0].outcome(),
+            Some(Outcome::Win {
+                winner: Player::White,
+                reason: WinReason::Timeout,
+            })
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn correspondence_players_keep_the_day_they_have() {
+        let dir = scratch_dir("no-forfeit");
+        let server = server(&dir);
+        let mut game = game_with_clock(TimeControl {
+            initial_ms: 0,
+            increment_ms: 0,
+            days_per_move: Some(1),
+        });
+        let then = now_ms() - 60 * 60 * 1000;
+        let white = sit(&mut game, then);
+        sit(&mut game, then);
+        game.apply_message(move_piece(&white, "e2e4"), then);
+        let game = Arc::new(Mutex::new(game));
+        server
+            .games
+            .lock()
+            .unwrap()
+            .insert("0f".to_string(), game.clone());
+
+        server.forfeit_overdue_games();
+        assert_eq!(game.lock().unwrap().boards[0].outcome(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
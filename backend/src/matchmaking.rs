@@ -0,0 +1,131 @@
+//! Pairing up players who want a game. Each player asks for a time control
+//! and waits in the queue until someone else who asked for the same one comes
+//! along with a rating close enough to theirs. How close is close enough
+//! widens the longer they've waited, so nobody waits for ever.
+
+use crate::chess::Player;
+use crate::clock::TimeControl;
+use crate::Connection;
+
+/// How far apart two ratings can be for a pairing as soon as the players
+/// start waiting
+const INITIAL_WINDOW: f64 = 100.0;
+/// How much further apart they can be for every second waited
+const WINDOW_GROWTH_PER_SECOND: f64 = 10.0;
+/// How many recent games count towards balancing colours
+pub const COLOR_HISTORY: usize = 10;
+
+/// A player waiting for a game
+#[derive(Debug, Clone)]
+pub struct Seek {
+    pub account: String,
+    pub time_control: Option<TimeControl>,
+    /// The player's rating in the pool the time control is rated in
+    pub rating: f64,
+    /// When the player started waiting, in milliseconds since the Unix epoch
+    pub since: u64,
+    pub connection: Connection,
+}
+
+impl Seek {
+    /// How far from the player's own rating an opponent's can be at `now`.
+    fn window(&self, now: u64) -> f64 {
+        let waited = now.saturating_sub(self.since) as f64 / 1000.0;
+        INITIAL_WINDOW + WINDOW_GROWTH_PER_SECOND * waited
+    }
+
+    fn accepts(&self, other: &Seek, now: u64) -> bool {
+        self.time_control == other.time_control
+            && !self.account.eq_ignore_ascii_case(&other.account)
+            && (self.rating - other.rating).abs() <= self.window(now)
+    }
+}
+
+/// Everyone waiting for a game, in the order they started waiting
+#[derive(Debug, Default)]
+pub struct Matchmaker {
+    queue: Vec<Seek>,
+}
+
+impl Matchmaker {
+    /// Put a player in the queue, in place of anything they were already
+    /// waiting for.
+    pub fn seek(&mut self, seek: Seek) {
+        self.cancel(&seek.connection);
+        self.queue.push(seek);
+    }
+
+    /// Take whatever `connection` was waiting for out of the queue, returning
+    /// whether it was waiting at all.
+    pub fn cancel(&mut self, connection: &Connection) -> bool {
+        let before = self.queue.len();
+        self.queue
+            .retain(|seek| !seek.connection.tx.same_receiver(&connection.tx));
+        self.queue.len() < before
+    }
+
+    /// Pair off everyone who can be paired at `now`, longest waiting first,
+    /// each with the closest rated of the opponents they'd both accept.
+    pub fn pair(&mut self, now: u64) -> Vec<(Seek, Seek)> {
+        let mut pairs = vec![];
+        let mut idx = 0;
+        while idx < self.queue.len() {
+            let seek = &self.queue[idx];
+            let opponent = self
+                .queue
+                .iter()
+                .enumerate()
+                .filter(|(other, opponent)| {
+                    *other != idx && seek.accepts(opponent, now) && opponent.accepts(seek, now)
+                })
+                .min_by(|(_, a), (_, b)| {
+                    let distance = |opponent: &Seek| (opponent.rating - seek.rating).abs();
+                    distance(a).total_cmp(&distance(b))
+                })
+                .map(|(other, _)| other);
+            match opponent {
+                Some(other) => {
+                    // take the later one out first so the earlier one's index
+                    // still holds
+                    let (first, second) = (idx.min(other), idx.max(other));
+                    let second = self.queue.remove(second);
+                    let first = self.queue.remove(first);
+                    pairs.push(if first.since <= second.since {
+                        (first, second)
+                    } else {
+                        (second, first)
+                    });
+                }
+                None => idx += 1,
+            }
+        }
+        pairs
+    }
+}
+
+/// Whether the first player should have White, going by the colours each has
+/// had in their recent games, oldest first: whoever has had White more often
+/// gets Black, and failing that whoever had White last. `None` when there's
+/// nothing to tell them apart.
+pub fn first_plays_white(first: &[Player], second: &[Player]) -> Option<bool> {
+    let recent = |colors: &[Player]| {
+        let start = colors.len().saturating_sub(COLOR_HISTORY);
+        colors[start..]
+            .iter()
+            .map(|color| match color {
+                Player::White => 1,
+                Player::Black => -1,
+            })
+            .sum::<i32>()
+    };
+    match recent(first).cmp(&recent(second)) {
+        std::cmp::Ordering::Less => Some(true),
+        std::cmp::Ordering::Greater => Some(false),
+        std::cmp::Ordering::Equal => match (first.last(), second.last()) {
+            (Some(a), Some(b)) if a != b => Some(*a == Player::Black),
+            (Some(a), None) => Some(*a == Player::Black),
+            (None, Some(b)) => Some(*b == Player::White),
+            _ => None,
+        },
+    }
+}
@@ -0,0 +1,283 @@
+//! Ratings, worked out from the results of games between accounts. Glicko-2
+//! is the default, treating every game as a rating period of its own the way
+//! most online servers do, and plain Elo can be used instead. Games are rated
+//! in separate pools by how long they take.
+
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::clock::TimeControl;
+
+const DEFAULT_RATING: f64 = 1500.0;
+const DEFAULT_DEVIATION: f64 = 350.0;
+const DEFAULT_VOLATILITY: f64 = 0.06;
+// how much the volatility can change, which Glickman suggests keeping
+// between 0.3 and 1.2
+const TAU: f64 = 0.5;
+// between Glicko and Glicko-2's scale
+const SCALE: f64 = 173.7178;
+const CONVERGENCE: f64 = 0.000_001;
+/// Glicko ratings less certain than this are provisional
+const PROVISIONAL_DEVIATION: f64 = 110.0;
+/// Elo ratings from fewer games than this are provisional
+const PROVISIONAL_GAMES: u32 = 20;
+// how far one Elo game can move a rating, further while it's provisional
+const ELO_K: f64 = 20.0;
+const ELO_PROVISIONAL_K: f64 = 40.0;
+
+/// How ratings are worked out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RatingSystem {
+    Glicko2,
+    Elo,
+}
+
+/// Which pool a game is rated in, by how long it's expected to last: the
+/// initial time plus forty moves' worth of increment, or days for
+/// correspondence games
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Category {
+    /// Under three minutes
+    Bullet,
+    /// Under eight minutes
+    Blitz,
+    /// Under twenty-five minutes
+    Rapid,
+    Classical,
+    /// Games played over days, with a limit on every move
+    Correspondence,
+    /// Games played without a clock
+    Unlimited,
+}
+
+impl Category {
+    pub fn of(time_control: Option<TimeControl>) -> Self {
+        let time_control = match time_control {
+            Some(time_control) => time_control,
+            None => return Category::Unlimited,
+        };
+        if time_control.days_per_move.is_some() {
+            return Category::Correspondence;
+        }
+        let expected_ms = time_control.initial_ms + 40 * time_control.increment_ms;
+        match expected_ms / 1000 {
+            0..=179 => Category::Bullet,
+            180..=479 => Category::Blitz,
+            480..=1499 => Category::Rapid,
+            _ => Category::Classical,
+        }
+    }
+}
+
+/// A player's rating in one pool
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rating {
+    pub rating: f64,
+    /// How uncertain the rating is, as one standard deviation
+    pub deviation: f64,
+    /// How erratic the player's results are
+    pub volatility: f64,
+    pub games: u32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating {
+            rating: DEFAULT_RATING,
+            deviation: DEFAULT_DEVIATION,
+            volatility: DEFAULT_VOLATILITY,
+            games: 0,
+        }
+    }
+}
+
+/// A player's rating in one pool as it's shown to players
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerRating {
+    pub category: Category,
+    pub rating: i32,
+    pub deviation: i32,
+    pub games: u32,
+    pub provisional: bool,
+}
+
+/// How a game moved a player's rating
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatingChange {
+    pub username: String,
+    pub before: i32,
+    pub after: i32,
+    pub provisional: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    /// Starting from 1 for the highest rated
+    pub rank: usize,
+    pub username: String,
+    pub rating: i32,
+    pub games: u32,
+}
+
+/// Glicko-2's g function, which weights a result by how sure the opponent's
+/// rating is
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+}
+
+/// The score a player rated `rating` is expected to make against one rated
+/// `opponent`, out of 1.
+fn elo_expected(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+impl Rating {
+    /// Whether there aren't enough games behind the rating to trust it yet.
+    pub fn provisional(&self, system: RatingSystem) -> bool {
+        match system {
+            RatingSystem::Glicko2 => self.deviation > PROVISIONAL_DEVIATION,
+            RatingSystem::Elo => self.games < PROVISIONAL_GAMES,
+        }
+    }
+
+    /// The rating rounded off for showing to players.
+    pub fn shown(&self, category: Category, system: RatingSystem) -> PlayerRating {
+        PlayerRating {
+            category,
+            rating: self.rating.round() as i32,
+            deviation: self.deviation.round() as i32,
+            games: self.games,
+            provisional: self.provisional(system),
+        }
+    }
+
+    /// The rating after scoring `score` (1 for a win, ½ for a draw and 0 for
+    /// a loss) against `opponent`.
+    pub fn after(&self, opponent: &Rating, score: f64, system: RatingSystem) -> Rating {
+        let mut rating = match system {
+            RatingSystem::Glicko2 => self.glicko2(&[(*opponent, score)]),
+            RatingSystem::Elo => {
+                let k = if self.provisional(system) {
+                    ELO_PROVISIONAL_K
+                } else {
+                    ELO_K
+                };
+                let expected = elo_expected(self.rating, opponent.rating);
+                Rating {
+                    rating: self.rating + k * (score - expected),
+                    ..*self
+                }
+            }
+        };
+        rating.games += 1;
+        rating
+    }
+
+    /// Glickman's Glicko-2 update for a rating period of the games in
+    /// `results`, each an opponent and the score made against them. Games
+    /// are rated one to a period, but the update works for any number.
+    fn glicko2(&self, results: &[(Rating, f64)]) -> Rating {
+        let mu = (self.rating - DEFAULT_RATING) / SCALE;
+        let phi = self.deviation / SCALE;
+        let sigma = self.volatility;
+        let mut variance = 0.0;
+        let mut improvement = 0.0;
+        for (opponent, score) in results {
+            let mu_j = (opponent.rating - DEFAULT_RATING) / SCALE;
+            let g_j = g(opponent.deviation / SCALE);
+            let expected = 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+            variance += g_j * g_j * expected * (1.0 - expected);
+            improvement += g_j * (score - expected);
+        }
+
+        // the estimated variance of the rating from these games alone, and
+        // how much they suggest the rating should move
+        let v = 1.0 / variance;
+        let delta = v * improvement;
+
+        // the new volatility, found with the Illinois algorithm
+        let a = (sigma * sigma).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            ex * (delta * delta - phi * phi - v - ex) / (2.0 * (phi * phi + v + ex).powi(2))
+                - (x - a) / (TAU * TAU)
+        };
+        let mut bound_a = a;
+        let mut bound_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * TAU) < 0.0 {
+                k += 1.0;
+            }
+            a - k * TAU
+        };
+        let (mut f_a, mut f_b) = (f(bound_a), f(bound_b));
+        while (bound_b - bound_a).abs() > CONVERGENCE {
+            let c = bound_a + (bound_a - bound_b) * f_a / (f_b - f_a);
+            let f_c = f(c);
+            if f_c * f_b <= 0.0 {
+                bound_a = bound_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            bound_b = c;
+            f_b = f_c;
+        }
+        let volatility = (bound_a / 2.0).exp();
+
+        let phi_star = (phi * phi + volatility * volatility).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * improvement;
+        Rating {
+            rating: new_mu * SCALE + DEFAULT_RATING,
+            deviation: (new_phi * SCALE).min(DEFAULT_DEVIATION),
+            volatility,
+            games: self.games,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating,
+            deviation,
+            ..Rating::default()
+        }
+    }
+
+    #[test]
+    fn glicko2_matches_glickmans_example() {
+        let player = rating(1500.0, 200.0);
+        let results = [
+            (rating(1400.0, 30.0), 1.0),
+            (rating(1550.0, 100.0), 0.0),
+            (rating(1700.0, 300.0), 0.0),
+        ];
+        let after = player.glicko2(&results);
+        assert!((after.rating - 1464.06).abs() < 0.01, "{:?}", after);
+        assert!((after.deviation - 151.52).abs() < 0.01, "{:?}", after);
+        assert!((after.volatility - 0.05999).abs() < 0.00001, "{:?}", after);
+    }
+
+    #[test]
+    fn one_game_is_a_rating_period() {
+        let player = rating(1500.0, 200.0);
+        let opponent = rating(1400.0, 30.0);
+        let after = player.after(&opponent, 1.0, RatingSystem::Glicko2);
+        assert_eq!(after.games, 1);
+        assert_eq!(
+            Rating {
+                games: 1,
+                ..player.glicko2(&[(opponent, 1.0)])
+            },
+            after
+        );
+        assert!(after.rating > player.rating && after.deviation < player.deviation);
+    }
+}
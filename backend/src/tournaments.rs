@@ -0,0 +1,1047 @@
+//! Tournaments between accounts, run round by round by whoever organised
+//! them. A Swiss tournament pairs players with the same score, or as close to
+//! it as it can, without anyone meeting twice, the way the Dutch system does.
+//! A round robin has everyone play everyone, to Berger tables. Results come in
+//! from the games as they finish, or from the organiser, and standings are
+//! broken with Buchholz and then Sonneborn-Berger. Everything is kept in one
+//! JSON file, like the accounts, and games still going when the server stops
+//! are picked back up from their logs.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::chess::{Outcome, Player};
+use crate::clock::TimeControl;
+use crate::matchmaking::first_plays_white;
+
+/// What a bye is worth, in half points
+const BYE_HALF_POINTS: u32 = 2;
+/// How many pairings a Swiss round tries before giving up on a rule
+const PAIRING_ATTEMPTS: usize = 100_000;
+
+/// Why something couldn't be done to a tournament
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TournamentError {
+    NoSuchTournament,
+    /// Only the organiser can pair rounds and set results
+    NotOrganizer,
+    /// Players can only join or leave before the first round
+    AlreadyStarted,
+    AlreadyJoined,
+    NotJoined,
+    NotEnoughPlayers,
+    /// A Swiss tournament needs at least one round
+    NoRounds,
+    /// The next round can't be paired until every game of this one is over
+    RoundNotFinished,
+    Finished,
+    NoSuchPairing,
+}
+
+impl fmt::Display for TournamentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            TournamentError::NoSuchTournament => "There's no tournament by that id",
+            TournamentError::NotOrganizer => "Only the organiser can do that",
+            TournamentError::AlreadyStarted => "The tournament has already started",
+            TournamentError::AlreadyJoined => "You're already playing in the tournament",
+            TournamentError::NotJoined => "You're not playing in the tournament",
+            TournamentError::NotEnoughPlayers => "A tournament needs at least two players",
+            TournamentError::NoRounds => "A Swiss tournament needs at least one round",
+            TournamentError::RoundNotFinished => "The round isn't over yet",
+            TournamentError::Finished => "The tournament is over",
+            TournamentError::NoSuchPairing => "There's no such game in the tournament",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TournamentFormat {
+    Swiss { rounds: usize },
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PairingResult {
+    WhiteWins,
+    Draw,
+    BlackWins,
+}
+
+impl PairingResult {
+    pub fn of(outcome: &Outcome) -> Self {
+        match outcome {
+            Outcome::Win {
+                winner: Player::White,
+                ..
+            } => PairingResult::WhiteWins,
+            Outcome::Win { .. } => PairingResult::BlackWins,
+            Outcome::Draw { .. } => PairingResult::Draw,
+        }
+    }
+
+    /// What the game was worth to the player with `color`, in half points.
+    fn half_points(self, color: Player) -> u32 {
+        match (self, color) {
+            (PairingResult::Draw, _) => 1,
+            (PairingResult::WhiteWins, Player::White)
+            | (PairingResult::BlackWins, Player::Black) => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TournamentPlayer {
+    pub username: String,
+    /// The rating the player is seeded by, as it was when they joined
+    pub rating: i32,
+}
+
+/// Two players meeting in a round, on the board the pairing's position in the
+/// round gives
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pairing {
+    pub white: String,
+    pub black: String,
+    /// The game it's being played in, once the server has started one
+    #[serde(default)]
+    pub game: Option<String>,
+    #[serde(default)]
+    pub result: Option<PairingResult>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Round {
+    pub pairings: Vec<Pairing>,
+    /// The player left over when there's an odd number, who scores a point
+    #[serde(default)]
+    pub bye: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tournament {
+    pub id: String,
+    pub name: String,
+    pub organizer: String,
+    pub format: TournamentFormat,
+    pub time_control: Option<TimeControl>,
+    pub rated: bool,
+    /// In the order they joined, and then by seed once the tournament starts
+    pub players: Vec<TournamentPlayer>,
+    pub rounds: Vec<Round>,
+}
+
+/// A player's place in the tournament so far
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Standing {
+    /// Starting from 1 for the leader
+    pub rank: usize,
+    pub username: String,
+    pub rating: i32,
+    pub score: f64,
+    /// The opponents' scores added up
+    pub buchholz: f64,
+    /// The scores of the opponents beaten, and half those drawn with
+    pub sonneborn_berger: f64,
+}
+
+/// One round of a tournament as one player saw it, by index into the seeded
+/// players
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Entry {
+    Game {
+        opponent: usize,
+        color: Player,
+        // in half points, once the game is over
+        result: Option<u32>,
+    },
+    Bye,
+}
+
+impl Tournament {
+    /// How many rounds the tournament lasts with the players it has.
+    pub fn total_rounds(&self) -> usize {
+        match self.format {
+            TournamentFormat::Swiss { rounds } => rounds,
+            // with an odd number everyone sits one round out
+            TournamentFormat::RoundRobin => {
+                let players = self.players.len();
+                players.saturating_sub(1) + players % 2
+            }
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        !self.rounds.is_empty()
+    }
+
+    /// Whether every game of the latest round is over.
+    fn round_finished(&self) -> bool {
+        self.rounds.last().is_none_or(|round| {
+            round
+                .pairings
+                .iter()
+                .all(|pairing| pairing.result.is_some())
+        })
+    }
+
+    pub fn finished(&self) -> bool {
+        self.started() && self.rounds.len() >= self.total_rounds() && self.round_finished()
+    }
+
+    fn player(&self, username: &str) -> Option<usize> {
+        self.players
+            .iter()
+            .position(|player| player.username.eq_ignore_ascii_case(username))
+    }
+
+    fn join(&mut self, username: &str, rating: i32) -> Result<(), TournamentError> {
+        if self.started() {
+            return Err(TournamentError::AlreadyStarted);
+        }
+        if self.player(username).is_some() {
+            return Err(TournamentError::AlreadyJoined);
+        }
+        self.players.push(TournamentPlayer {
+            username: username.to_string(),
+            rating,
+        });
+        Ok(())
+    }
+
+    fn leave(&mut self, username: &str) -> Result<(), TournamentError> {
+        if self.started() {
+            return Err(TournamentError::AlreadyStarted);
+        }
+        let idx = self.player(username).ok_or(TournamentError::NotJoined)?;
+        self.players.remove(idx);
+        Ok(())
+    }
+
+    /// Pair the next round, seeding the players by rating first if it's the
+    /// first one.
+    fn pair_round(&mut self) -> Result<(), TournamentError> {
+        if self.players.len() < 2 {
+            return Err(TournamentError::NotEnoughPlayers);
+        }
+        if !self.round_finished() {
+            return Err(TournamentError::RoundNotFinished);
+        }
+        if self.rounds.len() >= self.total_rounds() {
+            return Err(TournamentError::Finished);
+        }
+        if !self.started() {
+            // stable, so equal ratings stay in the order they joined
+            self.players.sort_by_key(|player| Reverse(player.rating));
+        }
+        let pairs = match self.format {
+            TournamentFormat::Swiss { .. } => self.swiss_pairs(),
+            TournamentFormat::RoundRobin => self.round_robin_pairs(),
+        };
+        let username = |idx: usize| self.players[idx].username.clone();
+        let mut round = Round::default();
+        for (white, black) in pairs {
+            match (white, black) {
+                (Some(white), Some(black)) => round.pairings.push(Pairing {
+                    white: username(white),
+                    black: username(black),
+                    game: None,
+                    result: None,
+                }),
+                (Some(player), None) | (None, Some(player)) => round.bye = Some(username(player)),
+                (None, None) => {}
+            }
+        }
+        self.rounds.push(round);
+        Ok(())
+    }
+
+    /// Every player's rounds so far, by seed.
+    fn entries(&self) -> Vec<Vec<Entry>> {
+        let mut entries = vec![vec![]; self.players.len()];
+        for round in self.rounds.iter() {
+            for pairing in round.pairings.iter() {
+                let (white, black) =
+                    match (self.player(&pairing.white), self.player(&pairing.black)) {
+                        (Some(white), Some(black)) => (white, black),
+                        _ => continue,
+                    };
+                for (player, opponent, color) in
+                    [(white, black, Player::White), (black, white, Player::Black)]
+                {
+                    entries[player].push(Entry::Game {
+                        opponent,
+                        color,
+                        result: pairing.result.map(|result| result.half_points(color)),
+                    });
+                }
+            }
+            if let Some(player) = round.bye.as_deref().and_then(|bye| self.player(bye)) {
+                entries[player].push(Entry::Bye);
+            }
+        }
+        entries
+    }
+
+    /// Everyone's score so far, in half points.
+    fn half_points(entries: &[Vec<Entry>]) -> Vec<u32> {
+        entries
+            .iter()
+            .map(|rounds| {
+                rounds
+                    .iter()
+                    .map(|entry| match entry {
+                        Entry::Game { result, .. } => result.unwrap_or(0),
+                        Entry::Bye => BYE_HALF_POINTS,
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// The next Swiss round as (White, Black) pairs, with the bye paired with
+    /// nobody. Players are ranked by score and then seed, and each score group
+    /// is split in half with the top half playing the bottom half in order,
+    /// trying other opponents when that would be a rematch or give someone
+    /// the same colour a third time running. Those who can't be paired in
+    /// their own group float down to the next.
+    fn swiss_pairs(&self) -> Vec<(Option<usize>, Option<usize>)> {
+        let entries = self.entries();
+        let points = Tournament::half_points(&entries);
+        let colors: Vec<Vec<Player>> = entries
+            .iter()
+            .map(|rounds| {
+                rounds
+                    .iter()
+                    .filter_map(|entry| match entry {
+                        Entry::Game { color, .. } => Some(*color),
+                        Entry::Bye => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        let met = |a: usize, b: usize| {
+            entries[a]
+                .iter()
+                .any(|entry| matches!(entry, Entry::Game { opponent, .. } if *opponent == b))
+        };
+
+        let mut ranked: Vec<usize> = (0..self.players.len()).collect();
+        ranked.sort_by_key(|&player| Reverse(points[player]));
+        let mut bye = None;
+        if ranked.len() % 2 == 1 {
+            // the lowest ranked player who hasn't had one yet
+            let had_bye = |player: usize| entries[player].contains(&Entry::Bye);
+            let idx = ranked
+                .iter()
+                .rposition(|&player| !had_bye(player))
+                .unwrap_or(ranked.len() - 1);
+            bye = Some(ranked.remove(idx));
+        }
+
+        // loosen the rules one at a time until there's a pairing
+        let rules: [&dyn Fn(usize, usize) -> bool; 3] = [
+            &|a, b| !met(a, b) && compatible_colors(&colors[a], &colors[b]),
+            &|a, b| !met(a, b),
+            &|_, _| true,
+        ];
+        let paired = rules
+            .iter()
+            .find_map(|can_meet| {
+                let mut attempts = PAIRING_ATTEMPTS;
+                pair_groups(&ranked, &points, can_meet, &mut attempts)
+            })
+            .unwrap_or_default();
+        let mut pairs = vec![];
+        for (board, (higher, lower)) in paired.into_iter().enumerate() {
+            let higher_white =
+                match (due_color(&colors[higher]), due_color(&colors[lower])) {
+                    (Some(color), _) => color == Player::White,
+                    (None, Some(color)) => color == Player::Black,
+                    (None, None) => first_plays_white(&colors[higher], &colors[lower])
+                        .unwrap_or_else(|| match colors[higher].last() {
+                            Some(color) => *color == Player::Black,
+                            // alternating down the boards in the first round
+                            None => board.is_multiple_of(2),
+                        }),
+                };
+            pairs.push(if higher_white {
+                (Some(higher), Some(lower))
+            } else {
+                (Some(lower), Some(higher))
+            });
+        }
+        if bye.is_some() {
+            pairs.push((bye, None));
+        }
+        pairs
+    }
+
+    /// The next round robin round as (White, Black) pairs, by the circle
+    /// method Berger tables are made with: the last seed stays put while the
+    /// others go round, and a missing player stands for the bye.
+    fn round_robin_pairs(&self) -> Vec<(Option<usize>, Option<usize>)> {
+        let players = self.players.len();
+        let seats = players + players % 2;
+        let player = |seat: usize| Some(seat).filter(|&seat| seat < players);
+        let fixed = seats - 1;
+        let round = self.rounds.len();
+        let mut pairs = vec![];
+        for board in 0..seats / 2 {
+            let (a, b) = if board == 0 {
+                (round % fixed, fixed)
+            } else {
+                ((round + board) % fixed, (round + fixed - board) % fixed)
+            };
+            // the fixed seat and the rest each change colour every round
+            let a_white = if board == 0 {
+                round.is_multiple_of(2)
+            } else {
+                board % 2 == 1
+            };
+            let (white, black) = if a_white { (a, b) } else { (b, a) };
+            pairs.push((player(white), player(black)));
+        }
+        // byes go last, after the boards
+        pairs.sort_by_key(|(white, black)| white.is_none() || black.is_none());
+        pairs
+    }
+
+    /// Give the game on `board` of `round` its result, whatever it was.
+    fn report(
+        &mut self,
+        round: usize,
+        board: usize,
+        result: PairingResult,
+    ) -> Result<(), TournamentError> {
+        let pairing = self
+            .rounds
+            .get_mut(round)
+            .and_then(|round| round.pairings.get_mut(board))
+            .ok_or(TournamentError::NoSuchPairing)?;
+        pairing.result = Some(result);
+        Ok(())
+    }
+
+    /// Everyone's place in the tournament so far, by score, then Buchholz,
+    /// then Sonneborn-Berger, then seed.
+    pub fn standings(&self) -> Vec<Standing> {
+        let entries = self.entries();
+        let points = Tournament::half_points(&entries);
+        let mut standings: Vec<(usize, Standing)> = entries
+            .iter()
+            .enumerate()
+            .map(|(player, rounds)| {
+                let mut buchholz = 0;
+                let mut sonneborn_berger = 0;
+                for entry in rounds.iter() {
+                    if let Entry::Game {
+                        opponent,
+                        result: Some(result),
+                        ..
+                    } = entry
+                    {
+                        buchholz += points[*opponent];
+                        sonneborn_berger += points[*opponent] * result;
+                    }
+                }
+                let standing = Standing {
+                    rank: 0,
+                    username: self.players[player].username.clone(),
+                    rating: self.players[player].rating,
+                    score: f64::from(points[player]) / 2.0,
+                    buchholz: f64::from(buchholz) / 2.0,
+                    // half points times half points
+                    sonneborn_berger: f64::from(sonneborn_berger) / 4.0,
+                };
+                (player, standing)
+            })
+            .collect();
+        standings.sort_by(|(a, a_standing), (b, b_standing)| {
+            let key = |standing: &Standing| {
+                (standing.score, standing.buchholz, standing.sonneborn_berger)
+            };
+            let (a_key, b_key) = (key(a_standing), key(b_standing));
+            b_key
+                .0
+                .total_cmp(&a_key.0)
+                .then(b_key.1.total_cmp(&a_key.1))
+                .then(b_key.2.total_cmp(&a_key.2))
+                .then(a.cmp(b))
+        });
+        standings
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (_, standing))| Standing {
+                rank: idx + 1,
+                ..standing
+            })
+            .collect()
+    }
+
+    /// The crosstable as plain text, one player a line in standings order,
+    /// with each round's result, colour and opponent's rank: `+W3` for a win
+    /// with White against whoever's third, `=B1` a draw with Black against
+    /// the leader, `-` a loss, `*` a game still going, and `+BYE` a bye.
+    pub fn crosstable(&self) -> String {
+        let standings = self.standings();
+        let entries = self.entries();
+        let rank = |player: usize| {
+            let username = &self.players[player].username;
+            standings
+                .iter()
+                .find(|standing| &standing.username == username)
+                .map_or(0, |standing| standing.rank)
+        };
+        let name_width = self
+            .players
+            .iter()
+            .map(|player| player.username.len())
+            .max()
+            .unwrap_or(0)
+            .max("Player".len());
+
+        let mut text = String::new();
+        let format = match self.format {
+            TournamentFormat::Swiss { rounds } => format!("Swiss, {} rounds", rounds),
+            TournamentFormat::RoundRobin => "Round robin".to_string(),
+        };
+        let _ = writeln!(text, "{} ({})", self.name, format);
+        let _ = writeln!(text);
+        let _ = write!(text, "{:>3}  {:<name_width$}  Rating", "#", "Player");
+        for round in 1..=self.rounds.len() {
+            let _ = write!(text, " {:>5}", round);
+        }
+        let _ = writeln!(text, "  Score   Buch     SB");
+        for standing in standings.iter() {
+            let _ = write!(
+                text,
+                "{:>3}  {:<name_width$}  {:>6}",
+                standing.rank, standing.username, standing.rating
+            );
+            let player = self
+                .player(&standing.username)
+                .expect("standings are made from the players");
+            for entry in entries[player].iter() {
+                let cell = match *entry {
+                    Entry::Game {
+                        opponent,
+                        color,
+                        result,
+                    } => {
+                        let result = match result {
+                            Some(2) => '+',
+                            Some(1) => '=',
+                            Some(_) => '-',
+                            None => '*',
+                        };
+                        let color = match color {
+                            Player::White => 'W',
+                            Player::Black => 'B',
+                        };
+                        format!("{}{}{}", result, color, rank(opponent))
+                    }
+                    Entry::Bye => "+BYE".to_string(),
+                };
+                let _ = write!(text, " {:>5}", cell);
+            }
+            let _ = writeln!(
+                text,
+                "  {:>5.1} {:>6.1} {:>6.2}",
+                standing.score, standing.buchholz, standing.sonneborn_berger
+            );
+        }
+        text
+    }
+}
+
+/// The colour a player must have next, having had the other one two more
+/// times or twice running.
+fn due_color(colors: &[Player]) -> Option<Player> {
+    let whites = colors
+        .iter()
+        .filter(|&&color| color == Player::White)
+        .count();
+    let blacks = colors.len() - whites;
+    let last_two = colors.len().checked_sub(2).map(|start| &colors[start..]);
+    if whites >= blacks + 2 || last_two == Some(&[Player::White, Player::White]) {
+        Some(Player::Black)
+    } else if blacks >= whites + 2 || last_two == Some(&[Player::Black, Player::Black]) {
+        Some(Player::White)
+    } else {
+        None
+    }
+}
+
+/// Whether two players can meet without either getting a colour they mustn't.
+fn compatible_colors(a: &[Player], b: &[Player]) -> bool {
+    match (due_color(a), due_color(b)) {
+        (Some(a), Some(b)) => a != b,
+        _ => true,
+    }
+}
+
+/// Pair off `ranked` players, best first, trying the Dutch system's opponent
+/// for the top player and then the others in turn, and backtracking when the
+/// rest can't be paired. Gives up after `attempts` tries.
+fn pair_groups(
+    ranked: &[usize],
+    points: &[u32],
+    can_meet: &dyn Fn(usize, usize) -> bool,
+    attempts: &mut usize,
+) -> Option<Vec<(usize, usize)>> {
+    let (&top, rest) = match ranked.split_first() {
+        Some(split) => split,
+        None => return Some(vec![]),
+    };
+    *attempts = attempts.checked_sub(1)?;
+    // the rest of the top player's score group, whose top half they head
+    let group = rest
+        .iter()
+        .take_while(|&&player| points[player] == points[top])
+        .count();
+    let natural = group.div_ceil(2);
+    // the bottom half in order, then the top half upwards, then anyone lower
+    let candidates = (natural.saturating_sub(1)..group)
+        .chain((0..natural.saturating_sub(1)).rev())
+        .chain(group..rest.len());
+    for idx in candidates {
+        let opponent = rest[idx];
+        if !can_meet(top, opponent) {
+            continue;
+        }
+        let remaining: Vec<usize> = rest
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != idx)
+            .map(|(_, &player)| player)
+            .collect();
+        if let Some(mut pairs) = pair_groups(&remaining, points, can_meet, attempts) {
+            pairs.insert(0, (top, opponent));
+            return Some(pairs);
+        }
+    }
+    None
+}
+
+/// Every tournament on the server, past and present
+#[derive(Debug)]
+pub struct Tournaments {
+    // `None` keeps the tournaments in memory only
+    path: Option<PathBuf>,
+    tournaments: Mutex<Vec<Tournament>>,
+}
+
+impl Tournaments {
+    /// Read the tournaments kept at `path`, or start with none if there's
+    /// nothing there yet. Changes are written back to the same file.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let tournaments = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(Tournaments {
+            path: Some(path.to_path_buf()),
+            tournaments: Mutex::new(tournaments),
+        })
+    }
+
+    /// Start with no tournaments, kept in memory only.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Tournaments {
+            path: None,
+            tournaments: Mutex::new(vec![]),
+        }
+    }
+
+    /// Set up a tournament for players to join.
+    pub fn create(
+        &self,
+        id: String,
+        name: String,
+        organizer: String,
+        format: TournamentFormat,
+        time_control: Option<TimeControl>,
+        rated: bool,
+    ) -> Result<Tournament, TournamentError> {
+        if format == (TournamentFormat::Swiss { rounds: 0 }) {
+            return Err(TournamentError::NoRounds);
+        }
+        let tournament = Tournament {
+            id,
+            name,
+            organizer,
+            format,
+            time_control,
+            rated,
+            players: vec![],
+            rounds: vec![],
+        };
+        let mut tournaments = self.tournaments.lock().unwrap();
+        tournaments.push(tournament.clone());
+        self.save(&tournaments);
+        Ok(tournament)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Tournament, TournamentError> {
+        self.tournaments
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|tournament| tournament.id == id)
+            .cloned()
+            .ok_or(TournamentError::NoSuchTournament)
+    }
+
+    pub fn list(&self) -> Vec<Tournament> {
+        self.tournaments.lock().unwrap().clone()
+    }
+
+    pub fn join(
+        &self,
+        id: &str,
+        username: &str,
+        rating: i32,
+    ) -> Result<Tournament, TournamentError> {
+        self.update(id, |tournament| tournament.join(username, rating))
+    }
+
+    pub fn leave(&self, id: &str, username: &str) -> Result<Tournament, TournamentError> {
+        self.update(id, |tournament| tournament.leave(username))
+    }
+
+    /// Pair the next round, for the organiser.
+    pub fn pair_round(&self, id: &str, organizer: &str) -> Result<Tournament, TournamentError> {
+        self.update(id, |tournament| {
+            check_organizer(tournament, organizer)?;
+            tournament.pair_round()
+        })
+    }
+
+    /// Note which game the pairing on `board` of `round` is being played in.
+    pub fn set_game(&self, id: &str, round: usize, board: usize, game: String) {
+        let _ = self.update(id, |tournament| {
+            let pairing = tournament
+                .rounds
+                .get_mut(round)
+                .and_then(|round| round.pairings.get_mut(board))
+                .ok_or(TournamentError::NoSuchPairing)?;
+            pairing.game = Some(game);
+            Ok(())
+        });
+    }
+
+    /// Set the result of a game, for the organiser, to settle games that
+    /// were never played or finished.
+    pub fn report(
+        &self,
+        id: &str,
+        organizer: &str,
+        round: usize,
+        board: usize,
+        result: PairingResult,
+    ) -> Result<Tournament, TournamentError> {
+        self.update(id, |tournament| {
+            check_organizer(tournament, organizer)?;
+            tournament.report(round, board, result)
+        })
+    }
+
+    /// Record how a game the server started ended, returning the tournament
+    /// it was played in if there is one.
+    pub fn game_over(&self, game: &str, result: PairingResult) -> Option<Tournament> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = tournaments.iter_mut().find_map(|tournament| {
+            let pairing = tournament
+                .rounds
+                .iter_mut()
+                .flat_map(|round| round.pairings.iter_mut())
+                .find(|pairing| pairing.game.as_deref() == Some(game))?;
+            // the organiser may have settled it already
+            pairing.result.get_or_insert(result);
+            Some(tournament.clone())
+        })?;
+        self.save(&tournaments);
+        Some(tournament)
+    }
+
+    /// Whether a tournament is still waiting on the result of the game the
+    /// server started with id `game`.
+    pub fn waiting_on(&self, game: &str) -> bool {
+        self.tournaments
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|tournament| tournament.rounds.iter())
+            .flat_map(|round| round.pairings.iter())
+            .any(|pairing| pairing.game.as_deref() == Some(game) && pairing.result.is_none())
+    }
+
+    /// Change the tournament with `id` and save it if that worked, returning
+    /// it as it is now.
+    fn update(
+        &self,
+        id: &str,
+        change: impl FnOnce(&mut Tournament) -> Result<(), TournamentError>,
+    ) -> Result<Tournament, TournamentError> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = tournaments
+            .iter_mut()
+            .find(|tournament| tournament.id == id)
+            .ok_or(TournamentError::NoSuchTournament)?;
+        change(tournament)?;
+        let tournament = tournament.clone();
+        self.save(&tournaments);
+        Ok(tournament)
+    }
+
+    fn save(&self, tournaments: &[Tournament]) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let write = || -> io::Result<()> {
+            let partial = path.with_extension("json.partial");
+            fs::write(&partial, serde_json::to_string_pretty(tournaments)?)?;
+            fs::rename(&partial, path)
+        };
+        if let Err(e) = write() {
+            error!("Failed to write tournaments to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn check_organizer(tournament: &Tournament, account: &str) -> Result<(), TournamentError> {
+    if tournament.organizer.eq_ignore_ascii_case(account) {
+        Ok(())
+    } else {
+        Err(TournamentError::NotOrganizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn tournament(format: TournamentFormat, players: &[&str]) -> Tournament {
+        let mut tournament = Tournament {
+            id: "test".to_string(),
+            name: "Test".to_string(),
+            organizer: players[0].to_string(),
+            format,
+            time_control: None,
+            rated: false,
+            players: vec![],
+            rounds: vec![],
+        };
+        // seeded in the order they're given
+        for (seed, username) in players.iter().enumerate() {
+            tournament.join(username, 2000 - 100 * seed as i32).unwrap();
+        }
+        tournament
+    }
+
+    /// Pair every round and finish it, the better seed winning each game.
+    fn play_out(tournament: &mut Tournament) {
+        while tournament.pair_round().is_ok() {
+            let round = tournament.rounds.len() - 1;
+            for board in 0..tournament.rounds[round].pairings.len() {
+                let pairing = &tournament.rounds[round].pairings[board];
+                let white = tournament.player(&pairing.white).unwrap();
+                let black = tournament.player(&pairing.black).unwrap();
+                let result = if white < black {
+                    PairingResult::WhiteWins
+                } else {
+                    PairingResult::BlackWins
+                };
+                tournament.report(round, board, result).unwrap();
+            }
+        }
+        assert!(tournament.finished());
+    }
+
+    /// Every game played, as the two players' names in alphabetical order so
+    /// a rematch with the colours reversed looks the same
+    fn games(tournament: &Tournament) -> Vec<(String, String)> {
+        tournament
+            .rounds
+            .iter()
+            .flat_map(|round| round.pairings.iter())
+            .map(|pairing| {
+                let mut players = [pairing.white.clone(), pairing.black.clone()];
+                players.sort();
+                let [a, b] = players;
+                (a, b)
+            })
+            .collect()
+    }
+
+    fn byes(tournament: &Tournament) -> Vec<String> {
+        tournament
+            .rounds
+            .iter()
+            .filter_map(|round| round.bye.clone())
+            .collect()
+    }
+
+    #[test]
+    fn swiss_never_pairs_the_same_players_twice() {
+        let players = ["ann", "bob", "cat", "dan", "eve"];
+        let mut tournament = tournament(TournamentFormat::Swiss { rounds: 4 }, &players);
+        play_out(&mut tournament);
+
+        assert_eq!(tournament.rounds.len(), 4);
+        let games = games(&tournament);
+        assert_eq!(games.len(), 8);
+        assert_eq!(games.iter().collect::<HashSet<_>>().len(), games.len());
+        // with an odd number someone sits out every round, never twice
+        let byes = byes(&tournament);
+        assert_eq!(byes.len(), 4);
+        assert_eq!(byes.iter().collect::<HashSet<_>>().len(), byes.len());
+        // the lowest ranked player has the first
+        assert_eq!(byes[0], "eve");
+        assert_eq!(tournament.pair_round(), Err(TournamentError::Finished));
+    }
+
+    #[test]
+    fn swiss_waits_for_the_round_to_finish() {
+        let players = ["ann", "bob", "cat", "dan"];
+        let mut tournament = tournament(TournamentFormat::Swiss { rounds: 3 }, &players);
+        tournament.pair_round().unwrap();
+        assert_eq!(
+            tournament.pair_round(),
+            Err(TournamentError::RoundNotFinished)
+        );
+        assert_eq!(
+            tournament.join("fay", 1500),
+            Err(TournamentError::AlreadyStarted)
+        );
+    }
+
+    #[test]
+    fn round_robin_has_everyone_play_everyone_once() {
+        for players in [
+            &["ann", "bob", "cat", "dan"][..],
+            &["ann", "bob", "cat", "dan", "eve"],
+        ] {
+            let mut tournament = tournament(TournamentFormat::RoundRobin, players);
+            assert_eq!(
+                tournament.total_rounds(),
+                players.len() - 1 + players.len() % 2
+            );
+            play_out(&mut tournament);
+
+            let games = games(&tournament);
+            let n = players.len();
+            assert_eq!(games.len(), n * (n - 1) / 2);
+            assert_eq!(games.iter().collect::<HashSet<_>>().len(), games.len());
+            let byes = byes(&tournament);
+            if n % 2 == 1 {
+                assert_eq!(byes.iter().collect::<HashSet<_>>().len(), n);
+            } else {
+                assert!(byes.is_empty());
+            }
+            // nobody has more than one White more than Black, or the other
+            // way round
+            for player in players {
+                let whites = tournament
+                    .rounds
+                    .iter()
+                    .flat_map(|round| round.pairings.iter())
+                    .filter(|pairing| pairing.white == *player)
+                    .count();
+                let played = games
+                    .iter()
+                    .filter(|(a, b)| a == player || b == player)
+                    .count();
+                assert!(
+                    (2 * whites).abs_diff(played) <= 1,
+                    "{} {:?}",
+                    player,
+                    tournament
+                );
+            }
+        }
+    }
+
+    /// Four players who've played everyone, where ann and cat tie on points
+    /// and Buchholz but cat beat the stronger opponent
+    fn tied() -> Tournament {
+        let mut tournament =
+            tournament(TournamentFormat::RoundRobin, &["ann", "bob", "cat", "dan"]);
+        let pairing = |white: &str, black: &str, result| Pairing {
+            white: white.to_string(),
+            black: black.to_string(),
+            game: None,
+            result: Some(result),
+        };
+        for pairings in [
+            [
+                pairing("ann", "bob", PairingResult::WhiteWins),
+                pairing("cat", "dan", PairingResult::WhiteWins),
+            ],
+            [
+                pairing("ann", "cat", PairingResult::BlackWins),
+                pairing("bob", "dan", PairingResult::Draw),
+            ],
+            [
+                pairing("dan", "ann", PairingResult::BlackWins),
+                pairing("cat", "bob", PairingResult::BlackWins),
+            ],
+        ] {
+            tournament.rounds.push(Round {
+                pairings: pairings.to_vec(),
+                bye: None,
+            });
+        }
+        tournament
+    }
+
+    #[test]
+    fn standings_are_broken_by_buchholz_then_sonneborn_berger() {
+        let standings: Vec<(usize, String, f64, f64, f64)> = tied()
+            .standings()
+            .into_iter()
+            .map(|s| (s.rank, s.username, s.score, s.buchholz, s.sonneborn_berger))
+            .collect();
+        assert_eq!(
+            standings,
+            vec![
+                (1, "cat".to_string(), 2.0, 4.0, 2.5),
+                (2, "ann".to_string(), 2.0, 4.0, 2.0),
+                (3, "bob".to_string(), 1.5, 4.5, 2.25),
+                (4, "dan".to_string(), 0.5, 5.5, 0.75),
+            ]
+        );
+    }
+
+    #[test]
+    fn crosstable_shows_each_round_against_the_opponents_rank() {
+        // cat and bob are still playing, so their game doesn't count towards
+        // the tie-breaks yet
+        let mut tournament = tied();
+        tournament.rounds[2].pairings[1].result = None;
+        let expected = "\
+Test (Round robin)
+
+  #  Player  Rating     1     2     3  Score   Buch     SB
+  1  ann       2000   +W4   -W2   +B3    2.0    3.0   1.00
+  2  cat       1800   +W3   +B1   *W4    2.0    2.5   2.50
+  3  dan       1700   -B2   =B4   -W1    0.5    4.5   0.25
+  4  bob       1900   -B1   =W3   *B2    0.5    2.5   0.25
+";
+        assert_eq!(tournament.crosstable(), expected);
+    }
+}